@@ -0,0 +1,67 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use once_cell::sync::Lazy;
+
+/// The pairs of the syntaxes not in the table
+pub const DEFAULT_PAIRS_KEY: &str = "*";
+
+// the opening and closing chars by syntax name
+type Pairs = HashMap<String, Vec<(char, char)>>;
+
+static AUTO_PAIRS: Lazy<Mutex<Pairs>> = Lazy::new(|| Mutex::new(parse_pairs(&default_auto_pairs()).0));
+
+/// The pairs closed automatically, by syntax name.
+/// A pair is a string of two chars, ex: `"()"`. The pairs of [`DEFAULT_PAIRS_KEY`] are used for the other syntaxes
+pub fn default_auto_pairs() -> HashMap<String, Vec<String>> {
+    let pairs = ["()", "[]", "{}", "\"\"", "''", "``"];
+    let html_pairs = pairs.iter().chain(&["<>"]).map(|p| p.to_string()).collect();
+    HashMap::from([
+        (
+            DEFAULT_PAIRS_KEY.to_string(),
+            pairs.iter().map(|p| p.to_string()).collect(),
+        ),
+        ("HTML".to_string(), html_pairs),
+    ])
+}
+
+fn parse_pairs(pairs: &HashMap<String, Vec<String>>) -> (Pairs, Vec<String>) {
+    let mut errors = Vec::new();
+    let pairs = pairs
+        .iter()
+        .map(|(syntax_name, pairs)| {
+            let pairs = pairs
+                .iter()
+                .filter_map(|p| {
+                    let mut chars = p.chars();
+                    match (chars.next(), chars.next(), chars.next()) {
+                        (Some(open), Some(close), None) => Some((open, close)),
+                        _ => {
+                            errors.push(p.clone());
+                            None
+                        }
+                    }
+                })
+                .collect();
+            (syntax_name.clone(), pairs)
+        })
+        .collect();
+    (pairs, errors)
+}
+
+/// Set the pairs closed automatically, by syntax name, see [`default_auto_pairs`].
+/// Return the pairs that are not made of two chars, they are ignored
+pub fn set_auto_pairs(pairs: &HashMap<String, Vec<String>>) -> Vec<String> {
+    let (pairs, errors) = parse_pairs(pairs);
+    *AUTO_PAIRS.lock().unwrap() = pairs;
+    errors
+}
+
+/// The pairs closed automatically for the syntax
+pub(crate) fn auto_pairs(syntax_name: &str) -> Vec<(char, char)> {
+    let pairs = AUTO_PAIRS.lock().unwrap();
+    pairs
+        .get(syntax_name)
+        .or_else(|| pairs.get(DEFAULT_PAIRS_KEY))
+        .cloned()
+        .unwrap_or_default()
+}
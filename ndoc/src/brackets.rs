@@ -0,0 +1,196 @@
+use std::{
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+use ropey::Rope;
+use syntect::parsing::SyntaxReference;
+
+use crate::syntax::CodeBrackets;
+
+/// Lines searched around a bracket for its match
+const MAX_BRACKET_LINES: usize = 1000;
+/// Lines parsed before a bracket to know if it's in a string or a comment
+const SCOPE_CONTEXT_LINES: usize = 50;
+/// Cursors whose brackets are matched, each match parses up to [MAX_BRACKET_LINES] lines
+pub(crate) const MAX_MATCHED_CURSORS: usize = 16;
+
+/// The brackets matched for the last cursors, reused until they move.
+/// Clones share it, a new cache must be used once the text or the syntax is modified
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BracketCache {
+    last: Arc<Mutex<Option<MatchedCursors>>>,
+}
+
+#[derive(Debug)]
+struct MatchedCursors {
+    cursors: Vec<usize>,
+    pairs: Vec<(usize, usize)>,
+}
+
+impl BracketCache {
+    /// The bracket pairs of the cursors at the char indexes, matched by `f` when the cursors moved
+    pub fn pairs(
+        &self,
+        cursors: Vec<usize>,
+        f: impl FnOnce(&[usize]) -> Vec<(usize, usize)>,
+    ) -> Vec<(usize, usize)> {
+        let mut last = self.last.lock().unwrap();
+        match &*last {
+            Some(matched) if matched.cursors == cursors => matched.pairs.clone(),
+            _ => {
+                let pairs = f(&cursors);
+                *last = Some(MatchedCursors {
+                    cursors,
+                    pairs: pairs.clone(),
+                });
+                pairs
+            }
+        }
+    }
+}
+
+pub(crate) fn closing_bracket(c: char) -> Option<char> {
+    match c {
+        '(' => Some(')'),
+        '[' => Some(']'),
+        '{' => Some('}'),
+        _ => None,
+    }
+}
+
+fn is_bracket(c: char) -> bool {
+    matches!(c, '(' | ')' | '[' | ']' | '{' | '}')
+}
+
+/// The brackets of the code from `first_line` to `last_line`, with their char index
+fn code_brackets<'a>(
+    rope: &'a Rope,
+    syntax: &SyntaxReference,
+    first_line: usize,
+    last_line: usize,
+) -> impl Iterator<Item = (usize, char)> + 'a {
+    CodeBrackets::new(rope, Some(syntax), first_line)
+        .take_while(move |(line, _)| *line <= last_line)
+        .flat_map(|(_, brackets)| brackets)
+}
+
+/// The char index of the bracket matching the one at `char_idx`.
+/// None if there is no bracket at `char_idx`, if it's in a string or a comment, or if it's not matched
+pub(crate) fn matching_bracket(
+    rope: &Rope,
+    syntax: &SyntaxReference,
+    char_idx: usize,
+) -> Option<usize> {
+    if char_idx >= rope.len_chars() {
+        return None;
+    }
+    let c = rope.char(char_idx);
+    if !is_bracket(c) {
+        return None;
+    }
+    let line = rope.char_to_line(char_idx);
+
+    if let Some(closing) = closing_bracket(c) {
+        let mut brackets = code_brackets(
+            rope,
+            syntax,
+            line.saturating_sub(SCOPE_CONTEXT_LINES),
+            line + MAX_BRACKET_LINES,
+        )
+        .skip_while(|(idx, _)| *idx < char_idx);
+        if brackets.next()? != (char_idx, c) {
+            // not code
+            return None;
+        }
+        let mut stack = vec![closing];
+        for (idx, c) in brackets {
+            if let Some(closing) = closing_bracket(c) {
+                stack.push(closing);
+            } else if stack.last() == Some(&c) {
+                stack.pop();
+                if stack.is_empty() {
+                    return Some(idx);
+                }
+            }
+        }
+        None
+    } else {
+        let mut stack: Vec<(usize, char)> = Vec::new();
+        for (idx, b) in code_brackets(rope, syntax, line.saturating_sub(MAX_BRACKET_LINES), line) {
+            if idx == char_idx {
+                return stack
+                    .last()
+                    .filter(|(_, open)| closing_bracket(*open) == Some(c))
+                    .map(|(open_idx, _)| *open_idx);
+            }
+            if closing_bracket(b).is_some() {
+                stack.push((idx, b));
+            } else if stack.last().and_then(|(_, o)| closing_bracket(*o)) == Some(b) {
+                stack.pop();
+            }
+        }
+        None
+    }
+}
+
+/// The char indexes of the innermost brackets around `range`
+pub(crate) fn enclosing_brackets(
+    rope: &Rope,
+    syntax: &SyntaxReference,
+    range: Range<usize>,
+) -> Option<(usize, usize)> {
+    let first_line = rope.char_to_line(range.start).saturating_sub(MAX_BRACKET_LINES);
+    let last_line = rope.char_to_line(range.end) + MAX_BRACKET_LINES;
+    let mut stack: Vec<(usize, char)> = Vec::new();
+    for (idx, c) in code_brackets(rope, syntax, first_line, last_line) {
+        if closing_bracket(c).is_some() {
+            stack.push((idx, c));
+        } else if stack.last().and_then(|(_, o)| closing_bracket(*o)) == Some(c) {
+            let (open_idx, _) = stack.pop().unwrap();
+            if open_idx < range.start && idx >= range.end {
+                return Some((open_idx, idx));
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use ropey::Rope;
+
+    use super::{enclosing_brackets, matching_bracket};
+    use crate::syntax::SYNTAXSET;
+
+    #[test]
+    fn skip_strings_and_comments() {
+        let rust = SYNTAXSET.find_syntax_by_name("Rust").unwrap();
+        let text = "fn f(a: [u8; 2]) {\n    let s = \"(\"; // )\n    /* } */ g(')');\n}\n";
+        let rope = Rope::from_str(text);
+        let idx = |pat: &str| text.find(pat).unwrap();
+
+        assert_eq!(matching_bracket(&rope, rust, idx("(a")), Some(idx(") {")));
+        assert_eq!(matching_bracket(&rope, rust, idx(") {")), Some(idx("(a")));
+        assert_eq!(matching_bracket(&rope, rust, idx("[u8")), Some(idx("])")));
+        assert_eq!(matching_bracket(&rope, rust, idx("{\n")), Some(text.len() - 2));
+        assert_eq!(matching_bracket(&rope, rust, text.len() - 2), Some(idx("{\n")));
+        assert_eq!(matching_bracket(&rope, rust, idx("(\"")), None);
+        assert_eq!(matching_bracket(&rope, rust, idx("fn")), None);
+
+        let inside_g = idx("')'");
+        assert_eq!(
+            enclosing_brackets(&rope, rust, inside_g..inside_g),
+            Some((idx("('"), idx(");")))
+        );
+        let in_string = idx("\"(\"") + 1;
+        assert_eq!(
+            enclosing_brackets(&rope, rust, in_string..in_string),
+            Some((idx("{\n"), text.len() - 2))
+        );
+
+        // plain text has no string nor comment
+        let text_syntax = SYNTAXSET.find_syntax_plain_text();
+        assert_eq!(matching_bracket(&rope, text_syntax, idx("(\"")), Some(idx(")\n")));
+    }
+}
@@ -1,109 +1,59 @@
 use std::{
     borrow::Cow,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs,
-    io::{Read, Result, Write},
+    io::{self, Read, Result, Seek},
+    ops::Range,
     path::{Path, PathBuf},
     sync::{
         atomic::{AtomicUsize, Ordering},
-        mpsc::{self, Sender},
+        mpsc::{self, Sender, TryRecvError},
         Arc, Mutex,
     },
     thread,
     time::Duration,
 };
 
-use chardetng::EncodingDetector;
 use encoding_rs::Encoding;
 use itertools::Itertools;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
 use once_cell::sync::Lazy;
 use ropey::{Rope, RopeSlice};
+use similar::{DiffTag, TextDiff};
 use syntect::parsing::SyntaxReference;
 
 use crate::{
-    file_info::{detect_indentation, detect_linefeed, FileInfo, Indentation, LineFeed},
+    file_info::{
+        bom_for, decode, decode_by_chunks, decode_errors, decode_with, detect_indentation, detect_linefeed,
+        encode_with_invalid_bytes, is_binary, is_binary_text, unencodable_chars, write_atomically, DecodeError, FileInfo,
+        FileStamp, Indentation, LineFeed, LineFeedCount,
+    },
+    history::{Action, Change, Edit, History},
+    indent::IndentRules,
+    auto_pairs::auto_pairs,
+    brackets::{enclosing_brackets, matching_bracket, BracketCache, MAX_MATCHED_CURSORS},
+    folding::{fold_range, fold_ranges, is_foldable, Folds},
+    language::detect_syntax,
+    replace_in_files::FileReplace,
+    search::{SearchCache, SearchOptions, Searcher},
+    wrap::Wraps,
     rope_utils::{
         self, char_to_grapheme, get_line_start_boundary, grapheme_to_char, next_grapheme_boundary,
         next_word_boundary, prev_grapheme_boundary, prev_word_boundary, word_end, word_start,
     },
-    syntax::{StateCache, StyledLine, StyledLinesCache, SYNTAXSET},
+    syntax::{
+        new_highlighter, syntax_metadata, Highlighter, LinesChange, StyledLine, StyledLinesCache,
+        SYNTAXSET,
+    },
 };
 
+/// Size above which [Document::from_file] opens files in large-file mode
+pub const DEFAULT_LARGE_FILE_THRESHOLD: u64 = 64 * 1024 * 1024;
+
 static DOCID: AtomicUsize = AtomicUsize::new(0);
 static MESSAGE_SENDER: Lazy<Arc<Mutex<Option<Sender<BackgroundWorkerMessage>>>>> =
     Lazy::new(|| Arc::new(Mutex::new(None)));
 
-#[derive(Debug, Clone, Default)]
-struct History {
-    edit_stack: Vec<(Rope, Vec<Selection>)>,
-    edit_stack_top: usize,
-    last_action: Action,
-}
-
-impl History {
-    fn is_empty(&self) -> bool {
-        self.edit_stack_top == 0
-    }
-
-    fn push(&mut self, rope: Rope, selections: Vec<Selection>, action: &Action) {
-        if self.should_push(action, &self.last_action) {
-            self.edit_stack.drain(self.edit_stack_top..);
-            self.edit_stack.push((rope, selections));
-            self.edit_stack_top += 1;
-        }
-        self.last_action = action.clone();
-    }
-
-    fn undo(&mut self, rope: Rope, selections: Vec<Selection>) -> Option<(Rope, Vec<Selection>)> {
-        if self.edit_stack_top == self.edit_stack.len() {
-            self.edit_stack.push((rope, selections));
-        }
-
-        if self.edit_stack_top > 0 {
-            self.edit_stack_top -= 1;
-            Some(self.edit_stack[self.edit_stack_top].clone())
-        } else {
-            None
-        }
-    }
-
-    fn redo(&mut self) -> Option<(Rope, Vec<Selection>)> {
-        if self.edit_stack_top + 1 < self.edit_stack.len() {
-            self.edit_stack_top += 1;
-            Some(self.edit_stack[self.edit_stack_top].clone())
-        } else {
-            None
-        }
-    }
-
-    fn should_push(&self, action: &Action, last_action: &Action) -> bool {
-        if self.is_empty() {
-            return true;
-        }
-        match (action, last_action) {
-            (Action::Delete, Action::Delete) => false,
-            (Action::Backspace, Action::Backspace) => false,
-            (Action::Delete, _) => true,
-            (Action::Backspace, _) => true,
-            (Action::Tab, _) => true,
-            (_, Action::Tab) => true,
-            (Action::Text(t), _) if t.chars().count() > 1 => true,
-            (Action::Text(t), _) if t.chars().nth(0).is_some_and(|c| !c.is_alphanumeric()) => true,
-            (_, _) => false,
-        }
-    }
-}
-
-#[derive(Debug, Default, Clone)]
-enum Action {
-    #[default]
-    None,
-    Backspace,
-    Delete,
-    Text(String),
-    Tab,
-}
-
 #[test]
 fn new_doc_id() {
     DOCID.store(0, Ordering::Relaxed);
@@ -119,19 +69,23 @@ enum BackgroundWorkerMessage {
         usize,
         SyntaxReference,
         Rope,
-        usize,
+        LinesChange,
         StyledLinesCache,
         Sender<()>,
         usize,
     ),
     UpdateTheme(String),
-    // WatchFile(PathBuf),
-    // UnwatchFile(PathBuf),
+    VisibleLines(usize, Range<usize>),
+    UnregisterDocument(usize),
+    OnFileChanged(usize, Box<dyn Send + Fn()>),
+    WatchFile(usize, PathBuf),
+    UnwatchFile(usize),
+    FilesChanged(Vec<PathBuf>),
 }
 
-struct HighlighterState<'a> {
-    syntax: &'a SyntaxReference,
-    state_cache: StateCache,
+struct HighlighterState {
+    syntax_name: String,
+    highlighter: Box<dyn Highlighter>,
     current_index: usize,
     chunk_len: usize,
     rope: Rope,
@@ -139,11 +93,11 @@ struct HighlighterState<'a> {
     tab_len: usize,
 }
 
-impl<'a> HighlighterState<'a> {
-    fn new() -> Self {
+impl HighlighterState {
+    fn new(syntax: &SyntaxReference, theme: &str) -> Self {
         Self {
-            syntax: SYNTAXSET.find_syntax_plain_text(),
-            state_cache: StateCache::new(),
+            syntax_name: syntax.name.clone(),
+            highlighter: new_highlighter(syntax, theme),
             current_index: 0,
             chunk_len: 100,
             rope: Rope::new(),
@@ -153,31 +107,245 @@ impl<'a> HighlighterState<'a> {
     }
 
     fn update_chunk(&mut self) {
-        self.state_cache.update_range(
+        if self.current_index >= self.rope.len_lines() {
+            return;
+        }
+        self.current_index = self.highlighter.update_range(
             &self.lines_cache,
-            self.syntax,
             &self.rope,
             self.current_index,
             self.current_index + self.chunk_len,
             self.tab_len,
         );
-        self.current_index += self.chunk_len;
         // subsequent chunck are bigger, for better performance
         self.chunk_len = 1000;
     }
 
     fn update_theme(&mut self, theme: &str) {
-        self.state_cache.change_theme(theme);
+        self.highlighter.change_theme(theme);
+    }
+}
+
+/// Watch the folders of the opened files and call the document callback when its file is modified.
+/// Folders are watched instead of files, so files replaced by a rename are still tracked.
+struct FileWatcher {
+    watcher: Option<RecommendedWatcher>,
+    files: HashMap<usize, PathBuf>,
+    folders: HashSet<PathBuf>,
+    callbacks: HashMap<usize, Box<dyn Send + Fn()>>,
+}
+
+impl FileWatcher {
+    /// The events are sent to the worker as [BackgroundWorkerMessage::FilesChanged]
+    fn new(tx: Sender<BackgroundWorkerMessage>) -> Self {
+        let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            if let Ok(event) = event {
+                let _ = tx.send(BackgroundWorkerMessage::FilesChanged(event.paths));
+            }
+        })
+        .ok();
+        Self {
+            watcher,
+            files: HashMap::new(),
+            folders: HashSet::new(),
+            callbacks: HashMap::new(),
+        }
+    }
+
+    fn watch(&mut self, id: usize, path: PathBuf) {
+        let path = fs::canonicalize(&path).unwrap_or(path);
+        self.files.insert(id, path);
+        self.update_folders();
+    }
+
+    fn unwatch(&mut self, id: usize) {
+        self.files.remove(&id);
+        self.callbacks.remove(&id);
+        self.update_folders();
+    }
+
+    fn update_folders(&mut self) {
+        let Some(watcher) = self.watcher.as_mut() else {
+            return;
+        };
+        let folders = self
+            .files
+            .values()
+            .filter_map(|f| f.parent().map(Path::to_path_buf))
+            .collect::<HashSet<_>>();
+        for folder in self.folders.difference(&folders) {
+            let _ = watcher.unwatch(folder);
+        }
+        for folder in folders.difference(&self.folders) {
+            let _ = watcher.watch(folder, RecursiveMode::NonRecursive);
+        }
+        self.folders = folders;
+    }
+
+    fn dispatch_event(&self, paths: &[PathBuf]) {
+        for (id, file) in self.files.iter() {
+            if paths.contains(file) {
+                if let Some(f) = self.callbacks.get(id) {
+                    f();
+                }
+            }
+        }
+    }
+}
+
+/// State of the background thread: highlight the documents and watch their files
+struct BackgroundWorker {
+    highlight_state: HashMap<usize, HighlighterState>,
+    callbacks: HashMap<usize, Box<dyn Send + Fn()>>,
+    // lines shown by the editors of the documents
+    visible_lines: HashMap<usize, Range<usize>>,
+    theme: String,
+    file_watcher: FileWatcher,
+}
+
+impl BackgroundWorker {
+    fn new(tx: Sender<BackgroundWorkerMessage>) -> Self {
+        Self {
+            highlight_state: HashMap::new(),
+            callbacks: HashMap::new(),
+            visible_lines: HashMap::new(),
+            theme: "base16-ocean.dark".to_string(),
+            file_watcher: FileWatcher::new(tx),
+        }
+    }
+
+    fn handle_message(&mut self, message: BackgroundWorkerMessage) {
+        match message {
+            BackgroundWorkerMessage::UpdateBuffer(id, s, r, change, cache, tx, tab_len) => {
+                let state = self
+                    .highlight_state
+                    .entry(id)
+                    .or_insert_with(|| HighlighterState::new(&s, &self.theme));
+                if state.syntax_name != s.name {
+                    *state = HighlighterState::new(&s, &self.theme);
+                }
+
+                cache.apply_change(change);
+                state.current_index = state.highlighter.update_text(&r, change);
+                state.rope = r;
+                state.tab_len = tab_len;
+                state.lines_cache = cache;
+                // smaller chunk for the first synchronous update
+                state.chunk_len = 100;
+                state.update_chunk();
+                let _ = tx.send(());
+            }
+            BackgroundWorkerMessage::RegisterDocument(id, f) => {
+                self.callbacks.insert(id, f);
+            }
+            BackgroundWorkerMessage::UpdateTheme(new_theme) => {
+                for state in self.highlight_state.values_mut() {
+                    state.update_theme(&new_theme);
+                }
+                self.theme = new_theme;
+            }
+            BackgroundWorkerMessage::VisibleLines(id, lines) => {
+                self.visible_lines.insert(id, lines);
+            }
+            BackgroundWorkerMessage::UnregisterDocument(id) => {
+                self.highlight_state.remove(&id);
+                self.callbacks.remove(&id);
+                self.visible_lines.remove(&id);
+                self.file_watcher.unwatch(id);
+            }
+            BackgroundWorkerMessage::OnFileChanged(id, f) => {
+                self.file_watcher.callbacks.insert(id, f);
+            }
+            BackgroundWorkerMessage::WatchFile(id, path) => {
+                self.file_watcher.watch(id, path);
+            }
+            BackgroundWorkerMessage::UnwatchFile(id) => {
+                self.file_watcher.unwatch(id);
+            }
+            BackgroundWorkerMessage::FilesChanged(paths) => {
+                self.file_watcher.dispatch_event(&paths);
+            }
+        }
+    }
+
+    fn has_pending_work(&self) -> bool {
+        self.highlight_state
+            .values()
+            .any(|s| s.current_index < s.rope.len_lines())
+    }
+
+    /// The document to highlight next: the ones with lines to highlight on screen first, then from the top
+    fn next_document(&self) -> Option<usize> {
+        self.highlight_state
+            .iter()
+            .filter(|(_, s)| s.current_index < s.rope.len_lines())
+            .min_by_key(|(id, s)| {
+                let visible_end = self.visible_lines.get(id).map_or(0, |l| l.end);
+                (s.current_index >= visible_end, s.current_index)
+            })
+            .map(|(id, _)| *id)
+    }
+
+    fn highlight_next_chunk(&mut self) {
+        let Some(id) = self.next_document() else {
+            return;
+        };
+        if let Some(state) = self.highlight_state.get_mut(&id) {
+            state.update_chunk();
+        }
+        if let Some(f) = self.callbacks.get(&id) {
+            f();
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 struct BatchEdit {
-    rope: Rope,
+    edits: Vec<Edit>,
     selections: Vec<Selection>,
     action: Action,
-    from_char_idx: usize,
-    have_change: bool,
+    // number of lines before the batch
+    len_lines: usize,
+    // number of nested begin_batch_edit calls
+    depth: usize,
+}
+
+/// A rectangular selection, between the visible columns of its anchor and of its head.
+/// It makes one selection per line, the lines too short to reach its columns are in virtual space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct BlockSelection {
+    anchor_line: usize,
+    anchor_vcol: usize,
+    head_line: usize,
+    head_vcol: usize,
+}
+
+/// The lines of `rope` modified by the changes, applied in order to a text of `old_len_lines` lines
+fn lines_change(rope: &Rope, old_len_lines: usize, changes: &[Change]) -> LinesChange {
+    // chars range of the new text covering all the changes
+    let mut region: Option<Range<usize>> = None;
+    for c in changes {
+        let end = c.char_idx + c.inserted;
+        region = Some(match region {
+            None => c.char_idx..end,
+            Some(r) => {
+                let r_end = if r.end >= c.char_idx + c.removed {
+                    r.end - c.removed + c.inserted
+                } else {
+                    0
+                };
+                r.start.min(c.char_idx)..r_end.max(end)
+            }
+        });
+    }
+    let region = region.unwrap_or_default();
+    let start = rope.char_to_line(region.start.min(rope.len_chars()));
+    let end = rope.char_to_line(region.end.min(rope.len_chars())) + 1;
+    LinesChange {
+        start,
+        end,
+        delta: rope.len_lines() as isize - old_len_lines as isize,
+    }
 }
 
 /// A Document represent a editable text, it can be attached to a file or not
@@ -197,6 +365,20 @@ pub struct Document {
     message_sender: Option<Sender<BackgroundWorkerMessage>>,
     line_style_cache: StyledLinesCache,
     batch_edit: Option<BatchEdit>,
+    disk_stamp: Option<FileStamp>,
+    // current char index of the replacement char of each decode error, None once it was edited
+    decode_error_chars: Vec<Option<usize>>,
+    large_file: bool,
+    folds: Folds,
+    wraps: Wraps,
+    search_cache: SearchCache,
+    bracket_cache: BracketCache,
+    // the last rectangular selection, it's still active while the selections are the ones made from it
+    block: Option<BlockSelection>,
+    // char indexes of the closing chars of the pairs inserted automatically, they are typed over
+    auto_closers: Vec<usize>,
+    // the content of a binary file, the rope is left empty
+    binary: Option<Arc<Vec<u8>>>,
 }
 
 impl PartialEq for Document {
@@ -227,6 +409,16 @@ impl Default for Document {
             message_sender,
             line_style_cache: StyledLinesCache::new(),
             batch_edit: None,
+            disk_stamp: None,
+            decode_error_chars: Vec::new(),
+            large_file: false,
+            binary: None,
+            folds: Folds::default(),
+            wraps: Wraps::default(),
+            search_cache: SearchCache::default(),
+            bracket_cache: BracketCache::default(),
+            block: None,
+            auto_closers: Vec::new(),
         }
     }
 }
@@ -265,63 +457,29 @@ impl Document {
 
         let (tx, rx) = mpsc::channel();
 
-        (*MESSAGE_SENDER.lock().unwrap()) = Some(tx);
+        (*MESSAGE_SENDER.lock().unwrap()) = Some(tx.clone());
 
         let _ = thread::Builder::new()
             .name("Document Highlighter".to_string())
             .spawn(move || {
-                let mut highlight_state = HashMap::new();
-                let mut callback = HashMap::new();
-
+                let mut worker = BackgroundWorker::new(tx);
                 loop {
-                    match rx.try_recv() {
-                        Ok(BackgroundWorkerMessage::UpdateBuffer(
-                            id,
-                            s,
-                            r,
-                            start,
-                            cache,
-                            tx,
-                            tab_len,
-                        )) => {
-                            let state = highlight_state
-                                .entry(id)
-                                .or_insert(HighlighterState::new());
-
-                            state.rope = r;
-                            state.tab_len = tab_len;
-                            state.current_index = start;
-                            state.syntax = SYNTAXSET.find_syntax_by_name(&s.name).unwrap();
-                            state.lines_cache = cache;
-                            // smaller chunk for the first synchronous update
-                            state.chunk_len = 100;
-                            state.update_chunk();
-                            let _ = tx.send(());
-                        }
-                        Ok(BackgroundWorkerMessage::RegisterDocument(id, f)) => {
-                            callback.insert(id, f);
-                        }
-                        Ok(BackgroundWorkerMessage::UpdateTheme(theme)) => {
-                            for (_, state) in highlight_state.iter_mut() {
-                                state.update_theme(&theme);
-                            }
-                        }
-                        _ => (),
-                    }
-                    if highlight_state
-                        .values()
-                        .any(|s| s.current_index < s.rope.len_lines())
-                    {
-                        for h in highlight_state.values_mut() {
-                            h.update_chunk();
-                        }
-                        for id in highlight_state.keys() {
-                            if let Some(f) = callback.get(id) {
-                                f();
-                            }
+                    // handle every pending message before highlighting, so the work made stale by them is skipped
+                    let message = if worker.has_pending_work() {
+                        match rx.try_recv() {
+                            Ok(message) => Some(message),
+                            Err(TryRecvError::Empty) => None,
+                            Err(TryRecvError::Disconnected) => break,
                         }
                     } else {
-                        thread::sleep(Duration::from_millis(1));
+                        match rx.recv() {
+                            Ok(message) => Some(message),
+                            Err(_) => break,
+                        }
+                    };
+                    match message {
+                        Some(message) => worker.handle_message(message),
+                        None => worker.highlight_next_chunk(),
                     }
                 }
             });
@@ -337,19 +495,66 @@ impl Document {
         }
     }
 
+    /// Tell the highlighter which lines are shown, they are highlighted before the rest of the documents
+    pub fn set_visible_lines(&self, lines: Range<usize>) {
+        if let Some(mg) = &self.message_sender {
+            let _ = mg.send(BackgroundWorkerMessage::VisibleLines(self.id, lines));
+        }
+    }
+
+    /// Drop everything the background thread keeps for the document: highlighting, callbacks and file watching.
+    /// To be called when the document is closed
+    pub fn unregister(&self) {
+        if let Some(mg) = &self.message_sender {
+            let _ = mg.send(BackgroundWorkerMessage::UnregisterDocument(self.id));
+        }
+    }
+
+    /// The document was open in large-file mode, see [from_file_with_progress](Self::from_file_with_progress)
+    pub fn is_large_file(&self) -> bool {
+        self.large_file
+    }
+
+    /// The document was detected as binary, its content is only available as bytes
+    pub fn is_binary(&self) -> bool {
+        self.binary.is_some()
+    }
+
+    /// The raw content of a binary document, None for text documents
+    pub fn binary_content(&self) -> Option<&[u8]> {
+        self.binary.as_deref().map(|b| b.as_slice())
+    }
+
     /// Return style information for the given line, None if the line is not yet highlighted, out of range, or the document is plain text
     pub fn get_style_line_info(&self, line_idx: usize) -> Option<StyledLine> {
         self.line_style_cache.get(line_idx)
     }
 
-    fn update_highlight_from(&self, line_idx: usize) {
+    fn text_changed(&mut self, change: LinesChange) {
+        self.search_cache = SearchCache::default();
+        self.bracket_cache = BracketCache::default();
+        self.folds.apply_change(change);
+        let tab_len = self.tab_len();
+        self.wraps.apply_change(&self.rope, &self.folds, tab_len, change);
+        self.update_highlight_lines(change);
+    }
+
+    /// Highlight the whole document again
+    fn update_highlight(&self) {
+        self.update_highlight_lines(LinesChange::from_line(0, &self.rope));
+    }
+
+    fn update_highlight_lines(&self, change: LinesChange) {
+        if self.large_file {
+            return;
+        }
         let (sender, receiver) = mpsc::channel();
         if let Some(tx) = self.message_sender.as_ref() {
             let _ = tx.send(BackgroundWorkerMessage::UpdateBuffer(
                 self.id,
                 self.file_info.syntax.clone(),
                 self.rope.clone(),
-                line_idx,
+                change,
                 self.line_style_cache.clone(),
                 sender,
                 self.file_info.indentation.size(),
@@ -363,7 +568,8 @@ impl Document {
     pub fn update_language(&mut self, language: &str) {
         if let Some(s) = SYNTAXSET.find_syntax_by_name(language) {
             self.file_info.syntax = s;
-            self.update_highlight_from(0);
+            self.bracket_cache = BracketCache::default();
+            self.update_highlight();
         }
     }
 
@@ -372,127 +578,366 @@ impl Document {
         if let Some(tx) = self.message_sender.as_ref() {
             let _ = tx.send(BackgroundWorkerMessage::UpdateTheme(theme.to_string()));
         }
-        self.update_highlight_from(0);
+        self.update_highlight();
     }
 
     /// Create a new Document from a file
     pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let mut file = fs::File::open(&path)?;
-
-        let mut detector = EncodingDetector::new();
-        let mut vec = Vec::new();
-        file.read_to_end(&mut vec)?;
+        Self::from_file_with_progress(path, DEFAULT_LARGE_FILE_THRESHOLD, |_| ())
+    }
 
-        detector.feed(&vec, true);
-        let encoding = Encoding::for_bom(&vec);
+    /// Create a new Document from a file. Files bigger than `large_file_threshold` bytes are open in large-file mode:
+    /// they are decoded by chunks, calling `on_progress` with the fraction of the file read,
+    /// and the syntax highlighting and the indentation detection are turned off
+    pub fn from_file_with_progress<P: AsRef<Path>>(
+        path: P,
+        large_file_threshold: u64,
+        on_progress: impl FnMut(f32),
+    ) -> Result<Self> {
+        let mut file = fs::File::open(&path)?;
+        let metadata = file.metadata()?;
+        let large_file = metadata.len() > large_file_threshold;
+
+        let mut head = Vec::new();
+        (&file).take(8000).read_to_end(&mut head)?;
+        file.rewind()?;
+        if is_binary(&head) {
+            let mut content = Vec::new();
+            file.read_to_end(&mut content)?;
+            return Ok(Self::from_binary_content(path.as_ref(), content, &metadata, large_file));
+        }
 
-        let syntax = if let Ok(s) = SYNTAXSET.find_syntax_for_file(&path) {
-            s.unwrap_or_else(|| SYNTAXSET.find_syntax_plain_text())
+        let (rope, encoding, bom, decode_errors, disk_stamp) = if large_file {
+            let decoded = decode_by_chunks(io::BufReader::new(&file), metadata.len(), on_progress)?;
+            (
+                decoded.rope,
+                decoded.encoding,
+                decoded.bom,
+                decoded.decode_errors,
+                FileStamp::with_hash(&metadata, decoded.hash),
+            )
         } else {
-            SYNTAXSET.find_syntax_plain_text()
+            let mut vec = Vec::new();
+            file.read_to_end(&mut vec)?;
+            let (text, encoding, bom) = decode(&vec);
+            let decode_errors = decode_errors(&vec, &text, encoding, bom.as_deref());
+            if is_binary_text(&text) {
+                return Ok(Self::from_binary_content(path.as_ref(), vec, &metadata, large_file));
+            }
+            let disk_stamp = FileStamp::new(&metadata, &vec);
+            (Rope::from_str(&text), encoding, bom, decode_errors, disk_stamp)
         };
 
+        let syntax = detect_syntax(path.as_ref(), &rope.slice(..));
+
         let message_sender = if let Ok(mg) = MESSAGE_SENDER.lock() {
             mg.clone()
         } else {
             None
         };
 
-        let doc = match encoding {
-            None => {
-                let encoding = detector.guess(None, true);
-
-                let rope = Rope::from_str(&encoding.decode_with_bom_removal(&vec).0);
-                let linefeed = detect_linefeed(&rope.slice(..));
-                let indentation = detect_indentation(&rope.slice(..));
-
-                Self {
-                    rope: rope.clone(),
-                    file_info: FileInfo {
-                        encoding,
-                        bom: None,
-                        linefeed,
-                        indentation,
-                        syntax,
-                    },
-                    selections: vec![Selection::default()],
-                    file_name: Some(path.as_ref().to_path_buf()),
-                    history: Default::default(),
-                    id: Document::new_id(),
-                    message_sender,
-                    line_style_cache: StyledLinesCache::new(),
-                    batch_edit: None,
-                }
-            }
-            Some((encoding, bom_size)) => {
-                let bom = {
-                    let mut v = Vec::new();
-                    v.extend_from_slice(&vec[0..bom_size]);
-                    v
-                };
-                let rope = Rope::from_str(&encoding.decode_with_bom_removal(&vec).0);
-                let linefeed = detect_linefeed(&rope.slice(..));
-                let indentation = detect_indentation(&rope.slice(..));
-
-                Self {
-                    rope: rope.clone(),
-                    file_info: FileInfo {
-                        encoding,
-                        bom: Some(bom),
-                        linefeed,
-                        indentation,
-                        syntax,
-                    },
-                    selections: vec![Selection::default()],
-                    file_name: Some(path.as_ref().to_path_buf()),
-                    history: Default::default(),
-                    id: Document::new_id(),
-                    message_sender,
-                    line_style_cache: StyledLinesCache::new(),
-                    batch_edit: None,
-                }
-            }
+        let linefeed = detect_linefeed(&rope.slice(..));
+        let mixed_linefeeds = LineFeedCount::new(&rope.slice(..)).is_mixed();
+        let indentation = if large_file {
+            Indentation::default()
+        } else {
+            detect_indentation(&rope.slice(..))
         };
-        doc.update_highlight_from(0);
+
+        let doc = Self {
+            decode_error_chars: decode_errors.iter().map(|e| Some(e.char_idx)).collect(),
+            rope,
+            file_info: FileInfo {
+                encoding,
+                bom,
+                linefeed,
+                mixed_linefeeds,
+                indentation,
+                syntax,
+                decode_errors,
+            },
+            selections: vec![Selection::default()],
+            file_name: Some(path.as_ref().to_path_buf()),
+            history: Default::default(),
+            id: Document::new_id(),
+            message_sender,
+            line_style_cache: StyledLinesCache::new(),
+            batch_edit: None,
+            disk_stamp: Some(disk_stamp),
+            large_file,
+            binary: None,
+            folds: Folds::default(),
+            wraps: Wraps::default(),
+            search_cache: SearchCache::default(),
+            bracket_cache: BracketCache::default(),
+            block: None,
+            auto_closers: Vec::new(),
+        };
+        doc.watch_file();
+        doc.update_highlight();
         Ok(doc)
     }
 
-    fn reset_edit_stack(&mut self) {
-        self.history = Default::default();
+    fn from_binary_content(path: &Path, content: Vec<u8>, metadata: &fs::Metadata, large_file: bool) -> Self {
+        let doc = Self {
+            file_name: Some(path.to_path_buf()),
+            disk_stamp: Some(FileStamp::new(metadata, &content)),
+            large_file,
+            binary: Some(Arc::new(content)),
+            ..Default::default()
+        };
+        doc.watch_file();
+        doc
     }
 
     /// Save the document to the given path
     pub fn save_as(&mut self, path: &Path) -> Result<()> {
-        let mut file = fs::File::create(path)?;
-        let input = self.rope.to_string();
-        let encoded_output = match self.file_info.encoding.name() {
-            "UTF-16LE" => {
-                let mut v = Vec::new();
-                input
-                    .encode_utf16()
-                    .for_each(|i| v.extend_from_slice(&i.to_le_bytes()));
-                Cow::from(v)
-            }
-            "UTF-16BE" => {
-                let mut v = Vec::new();
-                input
-                    .encode_utf16()
-                    .for_each(|i| v.extend_from_slice(&i.to_be_bytes()));
-                Cow::from(v)
+        self.save_with_options(path, SaveOptions::default())
+    }
+
+    /// Save the document to the given path.
+    /// The content is written to a temporary file in the same folder, synced, then renamed over the target,
+    /// so the file is never left truncated. Symlinks are followed and the permissions of the file are kept
+    pub fn save_with_options(&mut self, path: &Path, options: SaveOptions) -> Result<()> {
+        let (output, decode_errors) = match &self.binary {
+            Some(content) => (content.to_vec(), Vec::new()),
+            None => {
+                let errors = if options.keep_invalid_bytes {
+                    self.unchanged_decode_errors()
+                } else {
+                    Vec::new()
+                };
+                encode_with_invalid_bytes(&self.rope, self.file_info.encoding, self.file_info.bom.as_deref(), &errors)
             }
-            _ => self.file_info.encoding.encode(&input).0,
         };
+        let file = write_atomically(path, &output, options.backup)?;
+
+        self.history.mark_saved();
+        self.decode_error_chars = decode_errors.iter().map(|e| Some(e.char_idx)).collect();
+        self.file_info.decode_errors = decode_errors;
+        self.disk_stamp = Some(FileStamp::new(&file.metadata()?, &output));
+        if self.file_name.as_deref() != Some(path) {
+            self.file_name = Some(path.to_owned());
+            self.watch_file();
+        }
+        Ok(())
+    }
+
+    fn watch_file(&self) {
+        if let (Some(tx), Some(path)) = (self.message_sender.as_ref(), self.file_name.as_ref()) {
+            let _ = tx.send(BackgroundWorkerMessage::WatchFile(self.id, path.clone()));
+        }
+    }
+
+    /// Register a callback called from a background thread when the file of the document is modified on disk.
+    /// Our own saves also trigger it, use [has_changed_on_disk](Self::has_changed_on_disk) to filter them out
+    pub fn on_file_changed(&self, f: impl Fn() + Send + 'static) {
+        if let Some(tx) = &self.message_sender {
+            let _ = tx.send(BackgroundWorkerMessage::OnFileChanged(self.id, Box::new(f)));
+        }
+    }
+
+    /// Stop watching the file of the document, to be called when the document is closed
+    pub fn unwatch_file(&self) {
+        if let Some(tx) = &self.message_sender {
+            let _ = tx.send(BackgroundWorkerMessage::UnwatchFile(self.id));
+        }
+    }
+
+    /// The file has been modified by another program since it was loaded or saved
+    pub fn has_changed_on_disk(&self) -> bool {
+        let (Some(path), Some(stamp)) = (&self.file_name, &self.disk_stamp) else {
+            return false;
+        };
+        match fs::metadata(path) {
+            Ok(metadata) if !stamp.same_metadata(&metadata) => {
+                fs::read(path).is_ok_and(|content| !stamp.same_content(&content))
+            }
+            _ => false,
+        }
+    }
+
+    /// Forget about the modifications made on disk, the document will overwrite them when saved
+    pub fn ignore_disk_changes(&mut self) {
+        if let Some(path) = &self.file_name {
+            if let (Ok(metadata), Ok(content)) = (fs::metadata(path), fs::read(path)) {
+                self.disk_stamp = Some(FileStamp::new(&metadata, &content));
+            }
+        }
+    }
 
-        if let Some(bom) = &self.file_info.bom {
-            file.write_all(bom)?;
+    /// Reload the document from disk. Only the modified lines are replaced, as a single undoable action,
+    /// so the cursors outside of them are kept
+    pub fn reload(&mut self) -> Result<()> {
+        let Some(path) = self.file_name.clone() else {
+            return Ok(());
+        };
+        let content = fs::read(&path)?;
+        let metadata = fs::metadata(&path)?;
+        if self.binary.is_some() {
+            self.disk_stamp = Some(FileStamp::new(&metadata, &content));
+            self.binary = Some(Arc::new(content));
+            return Ok(());
         }
-        file.write_all(&encoded_output)?;
+        let (text, encoding, bom) = decode(&content);
+        let errors = decode_errors(&content, &text, encoding, bom.as_deref());
+        self.replace_with_disk_content(&text, encoding, bom, errors);
+        self.disk_stamp = Some(FileStamp::new(&metadata, &content));
+        Ok(())
+    }
 
-        self.reset_edit_stack();
-        self.file_name = Some(path.to_owned());
+    /// Decode the file again with the given encoding, to fix a wrong detection.
+    /// Like [reload](Self::reload), this is a single undoable action
+    pub fn reopen_with_encoding(&mut self, encoding: &'static Encoding) -> Result<()> {
+        let Some(path) = self.file_name.clone() else {
+            return Ok(());
+        };
+        let content = fs::read(&path)?;
+        let metadata = fs::metadata(&path)?;
+        let (text, bom) = decode_with(&content, encoding);
+        let errors = decode_errors(&content, &text, encoding, bom.as_deref());
+        self.replace_with_disk_content(&text, encoding, bom, errors);
+        self.disk_stamp = Some(FileStamp::new(&metadata, &content));
         Ok(())
     }
 
+    /// Change the encoding used to save the document, with or without a BOM
+    /// The original bytes of the invalid sequences are meaningless in another encoding, they are forgotten
+    pub fn set_encoding(&mut self, encoding: &'static Encoding, bom: bool) {
+        if encoding != self.file_info.encoding {
+            self.file_info.decode_errors.clear();
+            self.decode_error_chars.clear();
+        }
+        self.file_info.encoding = encoding;
+        self.file_info.bom = if bom { bom_for(encoding) } else { None };
+    }
+
+    /// The chars that can't be represented in the encoding, they would be replaced on save
+    pub fn unencodable_chars(&self, encoding: &'static Encoding) -> Vec<(Position, char)> {
+        let slice = self.rope.slice(..);
+        unencodable_chars(&self.rope.to_string(), encoding)
+            .into_iter()
+            .map(|(byte_idx, c)| (char_to_position(&slice, self.rope.byte_to_char(byte_idx)), c))
+            .collect()
+    }
+
+    /// Replace the content of the document by the content of its file, as a single undoable action
+    fn replace_with_disk_content(
+        &mut self,
+        text: &str,
+        encoding: &'static Encoding,
+        bom: Option<Vec<u8>>,
+        decode_errors: Vec<DecodeError>,
+    ) {
+        let old_text = self.rope.to_string();
+        let diff = TextDiff::from_lines(old_text.as_str(), text);
+        let old_offsets = line_char_offsets(diff.old_slices());
+        let new_offsets = line_char_offsets(diff.new_slices());
+
+        // char index in the new text of a char index of the old text
+        let map_char_idx = |char_idx: usize| {
+            for op in diff.ops() {
+                let (old, new) = (op.old_range(), op.new_range());
+                if char_idx < old_offsets[old.end] || old.end == old_offsets.len() - 1 {
+                    return match op.tag() {
+                        DiffTag::Equal => {
+                            (new_offsets[new.start] + char_idx - old_offsets[old.start])
+                                .min(new_offsets[new.end])
+                        }
+                        _ => new_offsets[new.start],
+                    };
+                }
+            }
+            *new_offsets.last().unwrap()
+        };
+        let selections = self
+            .selections
+            .iter()
+            .map(|s| {
+                (
+                    map_char_idx(self.position_to_char(s.head)),
+                    map_char_idx(self.position_to_char(s.tail)),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        self.begin_batch_edit(Action::Replace);
+        // cursors are restored afterward, keep them out of the way of the edits
+        let previous_selections = std::mem::replace(&mut self.selections, vec![Selection::default()]);
+        for op in diff.ops().iter().rev() {
+            if op.tag() == DiffTag::Equal {
+                continue;
+            }
+            let (old, new) = (op.old_range(), op.new_range());
+            let inserted = diff.new_slices()[new].concat();
+            self.insert_at(&inserted, old_offsets[old.start], old_offsets[old.end]);
+        }
+        self.selections = previous_selections
+            .into_iter()
+            .zip(selections)
+            .map(|(mut s, (head, tail))| {
+                s.head = self.char_to_position(head);
+                s.tail = self.char_to_position(tail);
+                s
+            })
+            .collect();
+        self.end_batch_edit();
+
+        self.file_info.encoding = encoding;
+        self.file_info.bom = bom;
+        self.decode_error_chars = decode_errors.iter().map(|e| Some(e.char_idx)).collect();
+        self.file_info.decode_errors = decode_errors;
+        self.file_info.mixed_linefeeds = self.linefeed_count().is_mixed();
+        self.history.mark_saved();
+    }
+
+    /// The invalid byte sequences of the file whose replacement char was not modified since the file was loaded,
+    /// with their current position
+    pub fn decode_errors(&self) -> Vec<(Position, &DecodeError)> {
+        let slice = self.rope.slice(..);
+        self.unchanged_decode_errors()
+            .into_iter()
+            .map(|(char_idx, e)| (char_to_position(&slice, char_idx), e))
+            .collect()
+    }
+
+    /// Same as [decode_errors](Self::decode_errors) with the current char index of the errors
+    fn unchanged_decode_errors(&self) -> Vec<(usize, &DecodeError)> {
+        self.decode_error_chars
+            .iter()
+            .zip(self.file_info.decode_errors.iter())
+            .filter_map(|(idx, e)| idx.map(|idx| (idx, e)))
+            .collect()
+    }
+
+    /// Follow the replacement chars of the decode errors through a change of the text
+    fn map_decode_errors(&mut self, change: Change) {
+        let removed = change.char_idx..change.char_idx + change.removed;
+        for idx in self.decode_error_chars.iter_mut() {
+            *idx = idx.filter(|i| !removed.contains(i)).map(|i| {
+                if i >= removed.end {
+                    i - change.removed + change.inserted
+                } else {
+                    i
+                }
+            });
+        }
+    }
+
+    /// Return a unified diff between the document and its file on disk
+    pub fn diff_with_disk(&self) -> Result<String> {
+        let Some(path) = self.file_name.as_ref() else {
+            return Ok(String::new());
+        };
+        let content = fs::read(path)?;
+        let text = decode(&content).0;
+        let old_text = self.rope.to_string();
+        Ok(TextDiff::from_lines(old_text.as_str(), text.as_ref())
+            .unified_diff()
+            .header("editor", "disk")
+            .to_string())
+    }
+
     /// Find the input string inside the Document starting at the given position and return the start and end position of the first match
     /// If cycling is true, the search will continue from the beginning of the document if the end is reached
     /// If no match is found, return None
@@ -506,68 +951,185 @@ impl Document {
         if input.is_empty() {
             return None;
         }
+        let options = SearchOptions {
+            case_sensitive: !case_insensitive,
+            ..Default::default()
+        };
+        let searcher = Searcher::new(input, options).ok()?;
+        self.find(&searcher, position, cycling)
+    }
 
+    /// Return the start and end position of the first match of the [searcher](Searcher) starting at the given position
+    /// If cycling is true, the search will continue from the beginning of the document if the end is reached
+    pub fn find(
+        &self,
+        searcher: &Searcher,
+        position: Position,
+        cycling: bool,
+    ) -> Option<(Position, Position)> {
+        searcher
+            .find_in(&self.rope, &self.search_cache, self.position_to_char(position), cycling)
+            .map(|r| (self.char_to_position(r.start), self.char_to_position(r.end)))
+    }
+
+    /// Iterate over all the matches of the [searcher](Searcher) in the document
+    pub fn find_all<'a>(
+        &'a self,
+        searcher: &'a Searcher,
+    ) -> impl Iterator<Item = (Position, Position)> + 'a {
+        searcher
+            .find_iter_in(&self.rope, &self.search_cache, 0)
+            .map(|r| (self.char_to_position(r.start), self.char_to_position(r.end)))
+    }
+
+    /// Replace the first match of the [searcher](Searcher) found from the given position, cycling to the start of the document if needed.
+    /// See [Searcher::replace_iter] for the replacement syntax.
+    /// Return the start and end position of the inserted text, or None if there was no match
+    pub fn replace(
+        &mut self,
+        searcher: &Searcher,
+        position: Position,
+        replacement: &str,
+        preserve_case: bool,
+    ) -> Option<(Position, Position)> {
         let char_idx = self.position_to_char(position);
+        let (range, replacement) = searcher
+            .replace_iter_in(&self.rope, &self.search_cache, char_idx, replacement, preserve_case)
+            .next()
+            .or_else(|| {
+                searcher
+                    .replace_iter_in(&self.rope, &self.search_cache, 0, replacement, preserve_case)
+                    .next()
+            })?;
+        self.begin_batch_edit(Action::Replace);
+        self.insert_at(&replacement, range.start, range.end);
+        self.end_batch_edit();
+        Some((
+            self.char_to_position(range.start),
+            self.char_to_position(range.start + replacement.chars().count()),
+        ))
+    }
+
+    /// Replace all the matches of the [searcher](Searcher) in the document, as a single undoable action.
+    /// Return the number of replaced matches
+    pub fn replace_all(&mut self, searcher: &Searcher, replacement: &str, preserve_case: bool) -> usize {
+        let replacements = searcher
+            .replace_iter_in(&self.rope, &self.search_cache, 0, replacement, preserve_case)
+            .collect::<Vec<_>>();
+        self.apply_replacements(replacements)
+    }
+
+    /// Replace the matches of the [searcher](Searcher) that are inside the selections, as a single undoable action.
+    /// Return the number of replaced matches
+    pub fn replace_in_selections(
+        &mut self,
+        searcher: &Searcher,
+        replacement: &str,
+        preserve_case: bool,
+    ) -> usize {
+        let selections = self
+            .selections
+            .iter()
+            .filter(|s| !s.is_empty())
+            .map(|s| self.position_to_char(s.start())..self.position_to_char(s.end()))
+            .collect::<Vec<_>>();
+        let replacements = searcher
+            .replace_iter_in(&self.rope, &self.search_cache, 0, replacement, preserve_case)
+            .filter(|(r, _)| {
+                selections
+                    .iter()
+                    .any(|s| s.start <= r.start && r.end <= s.end)
+            })
+            .collect::<Vec<_>>();
+        self.apply_replacements(replacements)
+    }
 
-        for i in char_idx..self.rope.len_chars() {
-            let doc = self.rope.slice(i..).chars();
-            let search = input.chars();
-            if search.zip(doc).all(|(ic, c)| if case_insensitive{  ic.eq_ignore_ascii_case(&c) } else {ic == c }) {
-                return Some((
-                    self.char_to_position(i),
-                    self.char_to_position(i + input.len()),
-                ));
-            }
-        }
-        if cycling {
-            for i in 0..char_idx {
-                let doc = self.rope.slice(i..).chars();
-                let search = input.chars();
+    /// Apply the accepted hunks of a project wide replace, as a single undoable action.
+    /// Hunks that do not match the document anymore are skipped.
+    /// Return the number of replaced matches
+    pub fn apply_file_replace(&mut self, file_replace: &FileReplace) -> usize {
+        let replacements = file_replace.accepted_replacements(&self.rope, self.file_info.linefeed);
+        self.apply_replacements(replacements)
+    }
 
-                if search.zip(doc).all(|(ic, c)| ic == c) {
-                    return Some((
-                        self.char_to_position(i),
-                        self.char_to_position(i + input.len()),
-                    ));
+    /// Count the line feeds of the whole document
+    pub fn linefeed_count(&self) -> LineFeedCount {
+        LineFeedCount::new(&self.rope.slice(..))
+    }
+
+    /// Convert every line feed of the document, as a single undoable action.
+    /// The line feed is also used for the new lines. Return the number of converted line feeds
+    pub fn convert_linefeeds(&mut self, linefeed: LineFeed) -> usize {
+        let mut replacements = Vec::new();
+        let mut bytes = self.rope.bytes().enumerate().peekable();
+        while let Some((byte_idx, b)) = bytes.next() {
+            let found = match b {
+                b'\r' if bytes.peek().is_some_and(|(_, b)| *b == b'\n') => {
+                    bytes.next();
+                    LineFeed::CRLF
                 }
+                b'\r' => LineFeed::CR,
+                b'\n' => LineFeed::LF,
+                _ => continue,
+            };
+            if found != linefeed {
+                let start = self.rope.byte_to_char(byte_idx);
+                let len = if found == LineFeed::CRLF { 2 } else { 1 };
+                replacements.push((start..start + len, linefeed.to_string()));
             }
         }
+        self.file_info.linefeed = linefeed;
+        self.file_info.mixed_linefeeds = false;
+        self.apply_replacements(replacements)
+    }
 
-        None
+    fn apply_replacements(&mut self, replacements: Vec<(Range<usize>, String)>) -> usize {
+        self.begin_batch_edit(Action::Replace);
+        // replace from the end so the char indexes of the remaining matches stay valid
+        for (range, replacement) in replacements.iter().rev() {
+            self.insert_at(replacement, range.start, range.end);
+        }
+        self.end_batch_edit();
+        replacements.len()
     }
 
     /// Start a batch of edit Action
     /// The batch will be committed when end_batch_edit is called
     /// Batching permit undo and redo to treat the whole batch as a single action
+    /// Nested batches are merged into the outermost one
     fn begin_batch_edit(&mut self, action: Action) {
-        let rope = self.rope.clone();
-        let selections = self.selections.clone();
+        if let Some(batch) = &mut self.batch_edit {
+            batch.depth += 1;
+            return;
+        }
         self.batch_edit = Some(BatchEdit {
-            rope,
-            selections: selections.clone(),
+            edits: Vec::new(),
+            selections: self.selections.clone(),
             action,
-            from_char_idx: self.position_to_char(
-                self.selections
-                    .iter()
-                    .min()
-                    .map(|s| s.start())
-                    .unwrap_or_default(),
-            ),
-            have_change: false,
+            len_lines: self.rope.len_lines(),
+            depth: 0,
         });
     }
 
     /// End the current batch
     fn end_batch_edit(&mut self) {
+        if let Some(batch) = &mut self.batch_edit {
+            if batch.depth > 0 {
+                batch.depth -= 1;
+                return;
+            }
+        }
         if let Some(batch) = self.batch_edit.take() {
-            if batch.have_change {
-                self.history
-                    .push(batch.rope, batch.selections, &batch.action);
-                self.update_highlight_from(
-                    self.rope
-                        .char_to_line(batch.from_char_idx.min(self.rope.len_chars())),
+            if !batch.edits.is_empty() {
+                let changes: Vec<Change> = batch.edits.iter().map(Edit::change).collect();
+                let lines_change = lines_change(&self.rope, batch.len_lines, &changes);
+                self.history.push(
+                    batch.edits,
+                    batch.selections,
+                    self.selections.clone(),
+                    &batch.action,
                 );
-                self.batch_edit = None;
+                self.text_changed(lines_change);
             }
         }
     }
@@ -584,11 +1146,26 @@ impl Document {
 
     /// Modification of the document content are not saved to disk
     pub fn is_dirty(&self) -> bool {
-        !self.history.is_empty()
+        self.history.is_dirty()
     }
 
     fn insert_at(&mut self, input: &str, start: usize, end: usize) {
-        let mut changed = false;
+        if start == end && input.is_empty() {
+            return;
+        }
+        if self.batch_edit.is_none() {
+            // a single edit is its own batch, so the selections before it are kept for undo
+            self.begin_batch_edit(Action::None);
+            self.insert_at(input, start, end);
+            self.end_batch_edit();
+            return;
+        }
+        let edit = Edit {
+            char_idx: start,
+            removed: self.rope.slice(start..end).to_string(),
+            inserted: input.to_string(),
+        };
+        self.map_decode_errors(edit.change());
 
         if start != end {
             let sel_idx = self
@@ -604,6 +1181,11 @@ impl Document {
 
             self.rope.remove(start..end);
             let to_sub = end - start;
+            self.auto_closers.retain(|idx| !(start..end).contains(idx));
+            self.auto_closers
+                .iter_mut()
+                .filter(|idx| **idx >= end)
+                .for_each(|idx| *idx -= to_sub);
             (0..self.selections.len()).for_each(|i| {
                 if sel_idx[i].0 >= end {
                     self.selections[i].head =
@@ -614,7 +1196,6 @@ impl Document {
                         char_to_position(&self.rope.slice(..), sel_idx[i].1 - to_sub);
                 }
             });
-            changed = true;
         }
 
         if !input.is_empty() {
@@ -632,6 +1213,10 @@ impl Document {
 
             // update selections after the insertion point
             let to_add = input.chars().count();
+            self.auto_closers
+                .iter_mut()
+                .filter(|idx| **idx >= start)
+                .for_each(|idx| *idx += to_add);
             (0..self.selections.len()).for_each(|i| {
                 if sel_idx[i].0 >= start {
                     self.selections[i].head =
@@ -642,46 +1227,90 @@ impl Document {
                         char_to_position(&self.rope.slice(..), sel_idx[i].1 + to_add);
                 }
             });
-            changed = true;
         }
 
         if let Some(batch) = &mut self.batch_edit {
-            batch.have_change = changed;
+            batch.edits.push(edit);
         }
-
-        // TODO: cause performance issue
-        // if changed {
-        //     if let Some(batch) = &mut self.batch_edit {
-        //         batch.have_change = true
-        //     } else {
-        //         self.history
-        //             .push(histo_rope, histo_selections, &saved_action);
-        //         self.update_highlight_from(self.rope.char_to_line(start));
-        //     }
-        // }
     }
 
-    /// Undo the last action
-    pub fn undo(&mut self) {
-        if let Some((rope, selections)) = self
-            .history
-            .undo(self.rope.clone(), self.selections.clone())
-        {
-            self.rope = rope;
-            self.selections = selections;
-            // TODO: potential perf issue
-            self.update_highlight_from(0);
+    fn restore_history_state(&mut self, selections: Option<Vec<Selection>>, len_lines: usize) {
+        let changes = self.history.take_changes();
+        for change in changes.iter() {
+            self.map_decode_errors(*change);
+        }
+        if !changes.is_empty() {
+            // the restored edits may convert the line feeds
+            let count = self.linefeed_count();
+            self.file_info.mixed_linefeeds = count.is_mixed();
+            if let Some(linefeed) = count.most_used() {
+                self.file_info.linefeed = linefeed;
+            }
+        }
+        self.auto_closers.clear();
+        if let Some(selections) = selections {
+            self.selections = if selections.is_empty() {
+                vec![Selection::default()]
+            } else {
+                selections
+            };
+            if !changes.is_empty() {
+                self.text_changed(lines_change(&self.rope, len_lines, &changes));
+            }
         }
     }
 
+    /// Undo the last action
+    pub fn undo(&mut self) {
+        let len_lines = self.rope.len_lines();
+        let selections = self.history.undo(&mut self.rope);
+        self.restore_history_state(selections, len_lines);
+    }
+
     /// Redo the last undone action
+    /// When several branches exist, the most recently visited one is followed
     pub fn redo(&mut self) {
-        if let Some((rope, selections)) = self.history.redo() {
-            self.rope = rope;
-            self.selections = selections;
-            // TODO: potential perf issue
-            self.update_highlight_from(0);
-        }
+        let len_lines = self.rope.len_lines();
+        let selections = self.history.redo(&mut self.rope);
+        self.restore_history_state(selections, len_lines);
+    }
+
+    /// Go back `steps` edits in chronological order, switching undo branch if needed
+    pub fn earlier(&mut self, steps: usize) {
+        let len_lines = self.rope.len_lines();
+        let selections = self.history.earlier(&mut self.rope, steps);
+        self.restore_history_state(selections, len_lines);
+    }
+
+    /// Go forward `steps` edits in chronological order, switching undo branch if needed
+    pub fn later(&mut self, steps: usize) {
+        let len_lines = self.rope.len_lines();
+        let selections = self.history.later(&mut self.rope, steps);
+        self.restore_history_state(selections, len_lines);
+    }
+
+    /// Restore the document as it was `duration` before the current edit (ex: undo to 5 minutes ago)
+    pub fn earlier_by(&mut self, duration: Duration) {
+        let len_lines = self.rope.len_lines();
+        let selections = self.history.earlier_by(&mut self.rope, duration);
+        self.restore_history_state(selections, len_lines);
+    }
+
+    /// Restore the document as it was `duration` after the current edit
+    pub fn later_by(&mut self, duration: Duration) {
+        let len_lines = self.rope.len_lines();
+        let selections = self.history.later_by(&mut self.rope, duration);
+        self.restore_history_state(selections, len_lines);
+    }
+
+    /// Return the number of undo branches that can be followed by [redo](Self::redo)
+    pub fn redo_branch_count(&self) -> usize {
+        self.history.redo_branch_count()
+    }
+
+    /// Select the next undo branch that will be followed by [redo](Self::redo)
+    pub fn next_redo_branch(&mut self) {
+        self.history.next_redo_branch();
     }
 
     /// Convert a [position](Position) to a char index
@@ -714,6 +1343,7 @@ impl Document {
     /// This is mostly useful for copy/pasting in multi-cursor situation.
     pub fn insert_many(&mut self, input: &str) {
         self.begin_batch_edit(Action::Text(input.to_string()));
+        self.fill_block_virtual_space();
         if self.selections.len() > 1 && input.lines().count() == self.selections.len() {
             for (i, l) in input.lines().enumerate() {
                 self.insert_at_selection(l, self.selections[i]);
@@ -727,92 +1357,238 @@ impl Document {
     /// Insert the given string at the current [selection](Selection).
     pub fn insert(&mut self, input: &str) {
         self.begin_batch_edit(Action::Text(input.to_string()));
+        self.fill_block_virtual_space();
+        let pairs = auto_pairs(&self.file_info.syntax.name);
+        let rules = IndentRules::new(self.file_info.syntax);
+        // the lines closing a block once the input is typed are dedented
+        let mut closing_lines = Vec::new();
         for i in 0..self.selections.len() {
-            let selection = self.selections[i];
-            let char_idx_start = self.position_to_char(selection.start());
-            let char_idx_end = self.position_to_char(selection.start());
-            let (input, selection) = match (
-                input,
-                selection.is_empty(),
-                self.rope.chars_at(char_idx_start).prev(),
-                self.rope.chars_at(char_idx_end).next(),
-            ) {
-                // insert { and do nothing because } is allready there
-                ("{", true, _, Some('}')) => (input.to_string(), None),
-                // } is allready there, insert nothing, just move the cursor to the right
-                ("}", true, _, Some('}')) => {
-                    let mut selection = selection;
-                    selection.head = Position::new(selection.head.line, selection.head.column + 1);
-                    selection.tail = selection.head;
-                    ("".to_string(), Some(selection))
-                }
-                // insert {} and move the cursor to the right
-                ("{", true, _, _) => {
-                    let mut selection = selection;
-                    selection.head = Position::new(selection.head.line, selection.head.column + 1);
-                    selection.tail = selection.head;
-                    ("{}".to_string(), Some(selection))
-                }
-                // insert new line between { and } insert two line with the correct indentation and place the cursor in between
-                ("\r" | "\n" | "\r\n", _, Some('{'), Some('}')) => {
-                    let indent1 = self.compute_indentation(selection.head, 1);
-                    let indent2 = self.compute_indentation(selection.head, 0);
-                    let first_part = format!("{}{}", self.file_info.linefeed, indent1);
-
-                    let mut selection = selection;
-                    selection.head =
-                        Position::new(selection.head.line + 1, indent1.chars().count());
-                    selection.tail = selection.head;
-                    let second_part = format!("{}{}", self.file_info.linefeed, indent2);
-                    let input = format!("{}{}", first_part, second_part);
-                    (input, Some(selection))
+            let closing = rules.decrease(&self.line_content(self.selections[i].head.line));
+            if !self.insert_pair(input, i, &pairs) {
+                self.insert_with_indentation(input, i, &rules);
+            }
+            let line = self.selections[i].head.line;
+            if !closing && rules.decrease(&self.line_content(line)) {
+                closing_lines.push(line);
+            }
+        }
+        if input.chars().count() == 1 && !input.chars().all(char::is_whitespace) {
+            for line in closing_lines {
+                let width = self.expected_indent_width(line, &rules);
+                if width < self.line_indent_width(line, self.tab_len()) {
+                    self.set_indent_width(line, width);
                 }
-                // insert new line after a { should increment the indentation
-                ("\r" | "\n" | "\r\n", _, Some('{'), _) => (
-                    format!(
-                        "{}{}",
-                        self.file_info.linefeed,
-                        self.compute_indentation(selection.head, 1),
+            }
+        }
+        self.merge_selections();
+        self.end_batch_edit();
+    }
+
+    /// Insert at the selection `i`, a new line is indented following the rules of the syntax
+    fn insert_with_indentation(&mut self, input: &str, i: usize, rules: &IndentRules) {
+        let selection = self.selections[i];
+        let (input, selection) = match input {
+            "\r" | "\n" | "\r\n" => {
+                let (start, end) = (selection.start(), selection.end());
+                let line = self.rope.line(start.line);
+                let before = line.slice(..grapheme_to_char(&line, start.column)).to_string();
+                let line = self.rope.line(end.line);
+                let after = line.slice(grapheme_to_char(&line, end.column)..).to_string();
+                let after = after.trim_end_matches(['\r', '\n']);
+                let increase = rules.increase(&before) || rules.indent_next_line(&before);
+                match (increase, rules.decrease(after)) {
+                    // new line between the start and the end of a block, insert two lines with the correct indentation and place the cursor in between
+                    (true, true) => {
+                        let indent1 = self.compute_indentation(start, 1);
+                        let indent2 = self.compute_indentation(start, 0);
+                        let first_part = format!("{}{}", self.file_info.linefeed, indent1);
+
+                        let mut selection = selection;
+                        selection.head = Position::new(start.line + 1, indent1.chars().count());
+                        selection.tail = selection.head;
+                        let second_part = format!("{}{}", self.file_info.linefeed, indent2);
+                        let input = format!("{}{}", first_part, second_part);
+                        (input, Some(selection))
+                    }
+                    // insert new line after the start of a block should increment the indentation
+                    (true, false) => (
+                        format!(
+                            "{}{}",
+                            self.file_info.linefeed,
+                            self.compute_indentation(start, 1),
+                        ),
+                        None,
                     ),
-                    None,
-                ),
-                // insert new line before a } should decrement the indentation
-                ("\r" | "\n" | "\r\n", _, _, Some('}')) => (
-                    format!(
-                        "{}{}",
-                        self.file_info.linefeed,
-                        self.compute_indentation(selection.head, -1),
+                    // insert new line before the end of a block should decrement the indentation
+                    (false, true) => (
+                        format!(
+                            "{}{}",
+                            self.file_info.linefeed,
+                            self.compute_indentation(start, -1),
+                        ),
+                        None,
                     ),
-                    None,
-                ),
-                // Insert new line should preserve the indentation
-                ("\r" | "\n" | "\r\n", _, _, _) => (
-                    format!(
-                        "{}{}",
-                        self.file_info.linefeed,
-                        self.compute_indentation(selection.head, 0),
+                    // Insert new line should preserve the indentation
+                    (false, false) => (
+                        format!(
+                            "{}{}",
+                            self.file_info.linefeed,
+                            self.compute_indentation(start, 0),
+                        ),
+                        None,
                     ),
-                    None,
-                ),
-                // TODO: find a way to not reallocate the string
-                _ => (input.to_string(), None),
-            };
+                }
+            }
+            // TODO: find a way to not reallocate the string
+            _ => (input.to_string(), None),
+        };
+
+        self.insert_at_selection(&input, self.selections[i]);
+        if let Some(selection) = selection {
+            self.selections[i] = selection;
+        }
+    }
 
-            self.insert_at_selection(&input, self.selections[i]);
-            if let Some(selection) = selection {
-                self.selections[i] = selection;
+    /// The text of the line, without the line feed
+    fn line_content(&self, line: usize) -> String {
+        let mut content = self.rope.line(line).to_string();
+        content.truncate(content.trim_end_matches(['\r', '\n']).len());
+        content
+    }
+
+    /// The last line before this one that is not blank
+    fn previous_non_blank_line(&self, line: usize) -> Option<usize> {
+        (0..line)
+            .rev()
+            .find(|l| !self.rope.line(*l).chars().all(char::is_whitespace))
+    }
+
+    /// The indentation width the line should have, from the previous line that is not blank.
+    /// After the single line body of a statement like an `if` without braces, it goes back to the statement indentation
+    fn expected_indent_width(&self, line: usize, rules: &IndentRules) -> usize {
+        let tab_len = self.tab_len();
+        let Some(previous) = self.previous_non_blank_line(line) else {
+            return 0;
+        };
+        let content = self.line_content(previous);
+        let mut width = if rules.increase(&content) || rules.indent_next_line(&content) {
+            self.line_indent_width(previous, tab_len) + tab_len
+        } else {
+            let mut statement = previous;
+            while let Some(l) = self.previous_non_blank_line(statement).filter(|l| {
+                let content = self.line_content(*l);
+                rules.indent_next_line(&content) && !rules.increase(&content)
+            }) {
+                statement = l;
+            }
+            self.line_indent_width(statement, tab_len)
+        };
+        if rules.decrease(&self.line_content(line)) {
+            width = width.saturating_sub(tab_len);
+        }
+        width
+    }
+
+    /// Replace the indentation of the line
+    fn set_indent_width(&mut self, line: usize, width: usize) {
+        let indent = match self.file_info.indentation {
+            Indentation::Tab(x) => format!("{}{}", "\t".repeat(width / x), " ".repeat(width % x)),
+            Indentation::Space(_) => " ".repeat(width),
+        };
+        let line_start = self.rope.line_to_char(line);
+        let indent_len = self.line_indent_len(line);
+        if self.rope.slice(line_start..line_start + indent_len) != indent.as_str() {
+            self.insert_at(&indent, line_start, line_start + indent_len);
+        }
+    }
+
+    /// Indent the selected lines again, following the rules of the syntax. Blank lines are left as is
+    pub fn reindent(&mut self) {
+        let rules = IndentRules::new(self.file_info.syntax);
+        self.begin_batch_edit(Action::Replace);
+        for line in self.selected_lines() {
+            if !self.rope.line(line).chars().all(char::is_whitespace) {
+                let width = self.expected_indent_width(line, &rules);
+                self.set_indent_width(line, width);
             }
         }
-        self.merge_selections();
         self.end_batch_edit();
     }
 
-    /// Delete the character juste before [selections](Self::selections)
+    /// Type the char of a pair at the selection `i`: close it automatically, wrap the selected text in it
+    /// or type over the closing char inserted automatically.
+    /// Return false if nothing is done, the input should then be inserted as usual
+    fn insert_pair(&mut self, input: &str, i: usize, pairs: &[(char, char)]) -> bool {
+        let mut chars = input.chars();
+        let (Some(c), None) = (chars.next(), chars.next()) else {
+            return false;
+        };
+        let selection = self.selections[i];
+        let start = self.position_to_char(selection.start());
+        let end = self.position_to_char(selection.end());
+        let prev = self.rope.chars_at(start).prev();
+        let next = self.rope.chars_at(end).next();
+
+        if selection.is_empty() && next == Some(c) && self.auto_closers.contains(&start) {
+            self.auto_closers.retain(|idx| *idx != start);
+            let head = self.char_to_position(start + 1);
+            self.selections[i] = Selection::new(head, head, selection.is_clone, selection.generation);
+            return true;
+        }
+
+        let Some(&(open, close)) = pairs.iter().find(|(open, _)| *open == c) else {
+            return false;
+        };
+        if !selection.is_empty() {
+            let text = self.rope.slice(start..end).to_string();
+            self.insert_at(&format!("{open}{text}{close}"), start, end);
+            let (mut head, mut tail) = (end + 1, start + 1);
+            if selection.head < selection.tail {
+                (head, tail) = (tail, head);
+            }
+            self.selections[i] = Selection::new(
+                self.char_to_position(head),
+                self.char_to_position(tail),
+                selection.is_clone,
+                selection.generation,
+            );
+            return true;
+        }
+
+        // close only before a blank or a closing char, and not a quote ending a word
+        let before_blank =
+            next.is_none_or(|n| n.is_whitespace() || pairs.iter().any(|(_, cl)| *cl == n));
+        let after_word = open == close && prev.is_some_and(|p| p.is_alphanumeric() || p == open);
+        if !before_blank || after_word {
+            return false;
+        }
+        self.insert_at(&format!("{open}{close}"), start, start);
+        self.auto_closers.push(start + 1);
+        let head = self.char_to_position(start + 1);
+        self.selections[i] = Selection::new(head, head, selection.is_clone, selection.generation);
+        true
+    }
+
+    /// Delete the character juste before [selections](Self::selections).
+    /// Both chars of an empty pair are deleted
     pub fn backspace(&mut self) {
         self.begin_batch_edit(Action::Backspace);
-        for i in 0..self.selections.len() {
+        let pairs = auto_pairs(&self.file_info.syntax.name);
+        let in_virtual_space = self.selections_in_virtual_space();
+        for (i, in_virtual_space) in in_virtual_space.into_iter().enumerate() {
+            if in_virtual_space {
+                continue;
+            }
             if self.selections[i].head == self.selections[i].tail {
                 let start = self.selections[i].start();
+                let char_idx = self.position_to_char(start);
+                let open = char_idx.checked_sub(1).and_then(|i| self.rope.get_char(i));
+                if let (Some(open), Some(close)) = (open, self.rope.get_char(char_idx)) {
+                    if pairs.contains(&(open, close)) {
+                        self.insert_at("", char_idx - 1, char_idx + 1);
+                        continue;
+                    }
+                }
                 self.insert_at_position("", self.prev_position(start), start);
             } else {
                 self.insert_at_selection("", self.selections[i]);
@@ -825,7 +1601,11 @@ impl Document {
     /// Delete the character under [selections](Self::selections)
     pub fn delete(&mut self) {
         self.begin_batch_edit(Action::Delete);
-        for i in 0..self.selections.len() {
+        let in_virtual_space = self.selections_in_virtual_space();
+        for (i, in_virtual_space) in in_virtual_space.into_iter().enumerate() {
+            if in_virtual_space {
+                continue;
+            }
             if self.selections[i].head == self.selections[i].tail {
                 let start = self.selections[i].start();
                 self.insert_at_position("", start, self.next_position(start));
@@ -912,6 +1692,167 @@ impl Document {
         self.end_batch_edit();
     }
 
+    /// The lines of the selections, a selection ending at the start of a line doesn't include it
+    fn selected_lines(&self) -> Vec<usize> {
+        self.selections
+            .iter()
+            .flat_map(|s| {
+                let end = if s.end().line > s.start().line && s.end().column == 0 {
+                    s.end().line - 1
+                } else {
+                    s.end().line
+                };
+                s.start().line..=end
+            })
+            .sorted()
+            .dedup()
+            .collect()
+    }
+
+    /// Comment the selected lines with the line comment of the syntax, or uncomment them if they are all commented.
+    /// The comment is inserted at the indentation of the least indented line, so the lines stay aligned.
+    /// The syntaxes without line comment use their block comment on each line
+    pub fn toggle_line_comment(&mut self) {
+        let metadata = syntax_metadata(self.file_info.syntax);
+        let (start, end) = match (metadata.line_comment(), metadata.block_comment()) {
+            (Some(start), _) => (start.to_string(), String::new()),
+            (None, Some((start, end))) => (start.to_string(), end.to_string()),
+            (None, None) => return,
+        };
+        let tab_len = self.file_info.indentation.size();
+
+        // blank lines are left as is
+        let lines = self
+            .selected_lines()
+            .into_iter()
+            .filter(|l| !self.rope.line(*l).chars().all(char::is_whitespace))
+            .collect::<Vec<usize>>();
+        if lines.is_empty() {
+            return;
+        }
+        let commented = lines.iter().all(|l| {
+            let line = self.rope.line(*l).to_string();
+            let line = line.trim();
+            line.starts_with(start.trim_end()) && line.ends_with(end.trim_start())
+        });
+
+        self.begin_batch_edit(Action::Replace);
+        if commented {
+            for l in lines.into_iter().rev() {
+                let line = self.rope.line(l).to_string();
+                // `commented` ignored the trailing whitespace too
+                let content = line.trim_end();
+                let indent = content.len() - content.trim_start().len();
+                let line_start = self.rope.line_to_char(l);
+                let content_end = line_start + content.chars().count();
+                if !end.is_empty() {
+                    let token = if content.ends_with(&end) { end.as_str() } else { end.trim_start() };
+                    let token_start = content_end - token.chars().count();
+                    self.insert_at("", token_start, content_end);
+                }
+                let token = if content[indent..].starts_with(&start) { start.as_str() } else { start.trim_end() };
+                let token_start = line_start + content[..indent].chars().count();
+                self.insert_at("", token_start, token_start + token.chars().count());
+            }
+        } else {
+            let indent = lines
+                .iter()
+                .map(|l| self.line_indent_width(*l, tab_len))
+                .min()
+                .unwrap_or_default();
+            for l in lines.into_iter().rev() {
+                let line_start = self.rope.line_to_char(l);
+                if !end.is_empty() {
+                    let content_end = line_start + line_len_char(&self.rope.slice(..), l);
+                    self.insert_at(&end, content_end, content_end);
+                }
+                let mut width = 0;
+                let offset = self
+                    .rope
+                    .line(l)
+                    .chars()
+                    .take_while(|c| {
+                        let fits = width < indent;
+                        width += if *c == '\t' { tab_len - width % tab_len } else { 1 };
+                        fits
+                    })
+                    .count();
+                self.insert_at(&start, line_start + offset, line_start + offset);
+            }
+        }
+        self.end_batch_edit();
+    }
+
+    /// Wrap each selection in the block comment of the syntax, or remove it if the selection is already commented.
+    /// An empty selection comments its line
+    pub fn toggle_block_comment(&mut self) {
+        let metadata = syntax_metadata(self.file_info.syntax);
+        let Some((start, end)) = metadata.block_comment() else {
+            return self.toggle_line_comment();
+        };
+        let (start, end) = (start.to_string(), end.to_string());
+
+        self.begin_batch_edit(Action::Replace);
+        for i in (0..self.selections.len()).rev() {
+            let s = self.selections[i];
+            let (range_start, range_end) = if s.is_empty() {
+                let line_start = self.rope.line_to_char(s.head.line);
+                let indent = self.line_indent_len(s.head.line);
+                let content_end = line_start + line_len_char(&self.rope.slice(..), s.head.line);
+                (line_start + indent, content_end)
+            } else {
+                (self.position_to_char(s.start()), self.position_to_char(s.end()))
+            };
+            let text = self.rope.slice(range_start..range_end).to_string();
+            let lead = text.chars().count() - text.trim_start().chars().count();
+            let trail = text.chars().count() - text.trim_end().chars().count();
+            let trimmed = text.trim();
+
+            // a single comment, not the text between two of them
+            let commented = trimmed.len() >= start.trim().len() + end.trim().len()
+                && trimmed.starts_with(start.trim())
+                && trimmed.ends_with(end.trim())
+                && !trimmed[start.trim().len()..trimmed.len() - end.trim().len()].contains(end.trim());
+            let (new_start, new_end) = if commented {
+                let content = &trimmed[start.trim().len()..trimmed.len() - end.trim().len()];
+                let content = content.strip_prefix(' ').unwrap_or(content);
+                let content = content.strip_suffix(' ').unwrap_or(content);
+                let (start_idx, end_idx) = (range_start + lead, range_end - trail);
+                self.insert_at(content, start_idx, end_idx);
+                (start_idx, start_idx + content.chars().count())
+            } else {
+                self.insert_at(&end, range_end, range_end);
+                self.insert_at(&start, range_start, range_start);
+                (range_start, range_end + start.chars().count() + end.chars().count())
+            };
+            if !s.is_empty() {
+                let (mut head, mut tail) = (new_end, new_start);
+                if s.head < s.tail {
+                    (head, tail) = (tail, head);
+                }
+                self.selections[i] = Selection::new(
+                    self.char_to_position(head),
+                    self.char_to_position(tail),
+                    s.is_clone,
+                    s.generation,
+                );
+            }
+        }
+        self.merge_selections();
+        self.end_batch_edit();
+    }
+
+    /// Width of the indentation of the line, tabs included
+    fn line_indent_width(&self, line: usize, tab_len: usize) -> usize {
+        self.rope
+            .line(line)
+            .chars()
+            .take_while(|c| *c == ' ' || *c == '\t')
+            .fold(0, |width, c| {
+                width + if c == '\t' { tab_len - width % tab_len } else { 1 }
+            })
+    }
+
     /// Move all the [selections](Self::selections) in the given direction.
     /// If expand is true, only the head of the selection is moved (the cursor part)
     pub fn move_selections(&mut self, dir: MoveDirection, expand: bool) {
@@ -919,32 +1860,12 @@ impl Document {
             .selections
             .iter()
             .map(|s| {
-                let vcol = s.head.vcol;
-                let mut head = match dir {
-                    MoveDirection::Up => {
-                        let line = s.head.line.saturating_sub(1);
-                        Position::new(
-                            line,
-                            s.head
-                                .vcol
-                                .min(line_len_grapheme(&self.rope.slice(..), line)),
-                        )
-                    }
-                    MoveDirection::Down => {
-                        let line = usize::min(s.head.line + 1, self.rope.len_lines() - 1);
-                        Position::new(
-                            line,
-                            s.head
-                                .vcol
-                                .min(line_len_grapheme(&self.rope.slice(..), line)),
-                        )
-                    }
-                    MoveDirection::Left => self.prev_position(s.head),
-                    MoveDirection::Right => self.next_position(s.head),
+                let head = match dir {
+                    MoveDirection::Up => self.move_rows(s.head, -1),
+                    MoveDirection::Down => self.move_rows(s.head, 1),
+                    MoveDirection::Left => self.skip_hidden_lines(self.prev_position(s.head), false),
+                    MoveDirection::Right => self.skip_hidden_lines(self.next_position(s.head), true),
                 };
-                if matches!(dir, MoveDirection::Down | MoveDirection::Up) {
-                    head.vcol = vcol;
-                }
                 let tail = if !expand { head } else { s.tail };
 
                 Selection::new(head, tail, s.is_clone, s.generation)
@@ -1063,179 +1984,670 @@ impl Document {
         }
     }
 
-    /// return the start [position](Position) of the line pointed by the given [position](Position)
-    /// (stupid? it could be just Position.line)
-    pub fn line_start(&mut self, line: usize) -> Position {
-        char_to_position(&self.rope.slice(..), self.rope.line_to_char(line))
+    /// return the start [position](Position) of the line pointed by the given [position](Position)
+    /// (stupid? it could be just Position.line)
+    pub fn line_start(&mut self, line: usize) -> Position {
+        char_to_position(&self.rope.slice(..), self.rope.line_to_char(line))
+    }
+
+    /// return the line indentation length in char
+    pub fn line_indent_len(&self, line: usize) -> usize {
+        get_line_start_boundary(&self.rope.slice(..), line)
+    }
+
+    /// return the end position of the line
+    pub fn line_end(&mut self, line: usize) -> Position {
+        char_to_position(
+            &self.rope.slice(..),
+            self.rope.line_to_char(line) + line_len_char(&self.rope.slice(..), line),
+        )
+    }
+
+    /// return the end position of the line including end of line characters
+    pub fn line_end_full(&mut self, line: usize) -> Position {
+        self.line_start(line + 1)
+    }
+
+    pub fn select_line(&mut self, line: usize) {
+        let tail = self.line_start(line);
+        let head = self.line_end_full(line);
+        self.selections = vec![Selection {
+            head,
+            tail,
+            is_clone: false,
+            generation: 0,
+        }]
+    }
+
+    pub fn select_all(&mut self) {
+        let tail = char_to_position(&self.rope.slice(..), 0);
+        let head = char_to_position(&self.rope.slice(..), self.rope.len_chars());
+        self.selections = vec![Selection {
+            head,
+            tail,
+            is_clone: false,
+            generation: 0,
+        }]
+    }
+
+    /// Specify the main selection. Also cancel multi-cursor
+    /// Replace the selections by a single one, the folds hiding its head are opened
+    pub fn set_main_selection(&mut self, head: Position, tail: Position) {
+        self.reveal_line(head.line);
+        self.selections = vec![Selection {
+            head,
+            tail,
+            is_clone: false,
+            generation: 0,
+        }]
+    }
+
+    /// Select a rectangle of text: one selection per line from `anchor_line` to `head_line`,
+    /// between the visible columns `anchor_vcol` and `head_vcol`. A tab is as wide as the indentation
+    pub fn select_block(&mut self, anchor_line: usize, anchor_vcol: usize, head_line: usize, head_vcol: usize) {
+        let last_line = self.rope.len_lines() - 1;
+        let block = BlockSelection {
+            anchor_line: anchor_line.min(last_line),
+            anchor_vcol,
+            head_line: head_line.min(last_line),
+            head_vcol,
+        };
+        self.block = Some(block);
+        self.selections = self.block_selections(block);
+    }
+
+    /// Move the head of the rectangular selection, one line up or down, one visible column left or right.
+    /// A new one starts at the head of the main selection when there is no rectangular selection
+    pub fn expand_block_selection(&mut self, dir: MoveDirection) {
+        let mut block = self.current_block().unwrap_or_else(|| {
+            let head = self.selections[0].head;
+            let vcol = self.col_to_vcol(head.line, head.column);
+            BlockSelection {
+                anchor_line: head.line,
+                anchor_vcol: vcol,
+                head_line: head.line,
+                head_vcol: vcol,
+            }
+        });
+        match dir {
+            MoveDirection::Up => block.head_line = block.head_line.saturating_sub(1),
+            MoveDirection::Down => block.head_line += 1,
+            MoveDirection::Left => block.head_vcol = block.head_vcol.saturating_sub(1),
+            MoveDirection::Right => block.head_vcol += 1,
+        }
+        self.select_block(block.anchor_line, block.anchor_vcol, block.head_line, block.head_vcol);
+    }
+
+    /// The selections are still the ones of the last rectangular selection
+    pub fn is_block_selection(&self) -> bool {
+        self.current_block().is_some()
+    }
+
+    fn current_block(&self) -> Option<BlockSelection> {
+        self.block
+            .filter(|b| self.selections == self.block_selections(*b))
+    }
+
+    fn block_selections(&self, block: BlockSelection) -> Vec<Selection> {
+        let lines = block.anchor_line.min(block.head_line)..=block.anchor_line.max(block.head_line);
+        lines
+            .map(|line| {
+                let len = line_len_grapheme(&self.rope.slice(..), line);
+                // an edge inside a tab goes after it, the text typed in the block stays right of its left edge
+                let col = |vcol| {
+                    let col = self.vcol_to_col(line, vcol).min(len);
+                    if col < len && self.col_to_vcol(line, col) < vcol {
+                        col + 1
+                    } else {
+                        col
+                    }
+                };
+                let head = Position::new(line, col(block.head_vcol));
+                let tail = Position::new(line, col(block.anchor_vcol));
+                let distance = line.abs_diff(block.anchor_line);
+                Selection::new(head, tail, distance > 0, distance)
+            })
+            .collect()
+    }
+
+    /// The lines of the rectangular selection too short to reach its left column, with the missing width
+    fn block_virtual_space(&self) -> Vec<(usize, usize)> {
+        let Some(block) = self.current_block() else {
+            return Vec::new();
+        };
+        let left = block.anchor_vcol.min(block.head_vcol);
+        self.selections
+            .iter()
+            .filter_map(|s| {
+                let line = s.head.line;
+                let width = self.col_to_vcol(line, line_len_grapheme(&self.rope.slice(..), line));
+                (width < left).then(|| (line, left - width))
+            })
+            .collect()
+    }
+
+    /// The cursors in virtual space have no text around them to delete
+    fn selections_in_virtual_space(&self) -> Vec<bool> {
+        let virtual_space = self.block_virtual_space();
+        self.selections
+            .iter()
+            .map(|s| virtual_space.iter().any(|(line, _)| *line == s.head.line))
+            .collect()
+    }
+
+    /// Pad the lines in virtual space with spaces, the text typed in a rectangular selection stays aligned
+    fn fill_block_virtual_space(&mut self) {
+        for (line, missing) in self.block_virtual_space() {
+            let len = line_len_grapheme(&self.rope.slice(..), line);
+            let end = self.position_to_char(Position::new(line, len));
+            self.insert_at(&" ".repeat(missing), end, end);
+        }
+    }
+
+    pub fn cancel_multi_cursor(&mut self) {
+        self.selections = self
+            .selections
+            .iter()
+            .filter(|s| !s.is_clone)
+            .copied()
+            .collect();
+    }
+
+    /// duplicate the selection in the given direction
+    pub fn duplicate_selection(&mut self, direction: MoveDirection) {
+        let generation = self.last_generation() + 1;
+        match direction {
+            MoveDirection::Down => {
+                let s = *self.selections.iter().max().unwrap();
+                let mut news = s;
+                news.head.line = usize::min(s.head.line + 1, self.rope.len_lines() - 1);
+                news.head.column = s
+                    .head
+                    .vcol
+                    .min(line_len_grapheme(&self.rope.slice(..), news.head.line));
+                news.tail = news.head;
+                news.is_clone = true;
+                news.generation = generation;
+                if news.head.line > s.head.line {
+                    self.selections.push(news);
+                }
+            }
+            MoveDirection::Up => {
+                let s = *self.selections.iter().min().unwrap();
+                let mut news = s;
+                news.head.line = s.head.line.saturating_sub(1);
+                news.head.column = s
+                    .head
+                    .vcol
+                    .min(line_len_grapheme(&self.rope.slice(..), news.head.line));
+                news.tail = news.head;
+                news.is_clone = true;
+                news.generation = generation;
+                if news.head.line < s.head.line {
+                    self.selections.push(news);
+                }
+            }
+            _ => (),
+        }
+
+        self.merge_selections();
+    }
+
+    /// ducplicate the selection based on the currently selected text
+    pub fn duplicate_selection_for_selected_text(&mut self) {
+        let start = self.position_to_char(self.selections[0].start());
+        let end = self.position_to_char(self.selections[0].end());
+        let content = self.rope.slice(start..end).to_string();
+        let mut s = self
+            .selections
+            .iter()
+            .max_by_key(|s| s.generation)
+            .unwrap()
+            .duplicate();
+        let next = self.find_from(&content, s.end(), true, true);
+        if let Some((start, end)) = next {
+            s.tail = start;
+            s.head = end;
+            self.selections.push(s);
+            self.merge_selections();
+        }
+    }
+
+    fn last_generation(&self) -> usize {
+        self.selections
+            .iter()
+            .map(|s| s.generation)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// The searcher of the occurrences: the text of the main selection, or the word under the cursor when it's empty.
+    /// The word matches whole words with the same case, the selected text matches anywhere ignoring the case
+    fn occurrence_searcher(&mut self) -> Option<Searcher> {
+        let word = self.selections.len() == 1 && self.selections[0].is_empty();
+        if word {
+            self.select_word(self.selections[0].head);
+        }
+        let s = self.selections[0];
+        let start = self.position_to_char(s.start());
+        let end = self.position_to_char(s.end());
+        let options = SearchOptions {
+            case_sensitive: word,
+            whole_word: word,
+            ..Default::default()
+        };
+        Searcher::new(&self.rope.slice(start..end).to_string(), options).ok()
+    }
+
+    /// Select every occurrence of the selected text, or of the word under the cursor when nothing is selected
+    pub fn select_all_occurrences(&mut self) {
+        let Some(searcher) = self.occurrence_searcher() else {
+            return;
+        };
+        let main = self.selections[0].start();
+        let selections = self
+            .find_all(&searcher)
+            .map(|(start, end)| Selection::new(end, start, start != main, usize::from(start != main)))
+            .collect::<Vec<_>>();
+        if !selections.is_empty() {
+            self.selections = selections;
+        }
+    }
+
+    /// Replace the last selection added by [duplicate_selection_for_selected_text](Self::duplicate_selection_for_selected_text)
+    /// by the next occurrence of its text
+    pub fn skip_occurrence(&mut self) {
+        let Some((i, s)) = self
+            .selections
+            .iter()
+            .copied()
+            .enumerate()
+            .max_by_key(|(_, s)| s.generation)
+        else {
+            return;
+        };
+        let start = self.position_to_char(s.start());
+        let end = self.position_to_char(s.end());
+        let content = self.rope.slice(start..end).to_string();
+        let Some((start, end)) = self.find_from(&content, s.end(), true, true) else {
+            return;
+        };
+        // the search wrapped around to a selected occurrence, moving there would merge two cursors
+        let selected = self
+            .selections
+            .iter()
+            .enumerate()
+            .any(|(j, o)| j != i && o.start() < end && start < o.end());
+        if !selected {
+            self.selections[i] = Selection::new(end, start, s.is_clone, s.generation);
+            self.merge_selections();
+        }
+    }
+
+    /// Remove the last added cursor, the main one is kept
+    pub fn undo_last_cursor(&mut self) {
+        let last = self
+            .selections
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.is_clone)
+            .max_by_key(|(_, s)| s.generation)
+            .map(|(i, _)| i);
+        if let Some(i) = last {
+            self.selections.remove(i);
+        }
+    }
+
+    /// Replace the selections over several lines by a cursor at the end of each of their lines
+    pub fn add_cursors_at_line_ends(&mut self) {
+        let mut generation = self.last_generation();
+        let mut selections = Vec::with_capacity(self.selections.len());
+        for s in &self.selections {
+            if s.is_single_line() {
+                selections.push(*s);
+                continue;
+            }
+            let (start, end) = (s.start(), s.end());
+            // a selection ending at the start of a line doesn't select it
+            let last_line = if end.column == 0 { end.line - 1 } else { end.line };
+            for line in (start.line..=last_line).filter(|l| !self.is_line_hidden(*l)) {
+                let column = if line == end.line {
+                    end.column
+                } else {
+                    line_len_grapheme(&self.rope.slice(..), line)
+                };
+                let p = Position::new(line, column);
+                if line == start.line {
+                    selections.push(Selection::new(p, p, s.is_clone, s.generation));
+                } else {
+                    generation += 1;
+                    selections.push(Selection::new(p, p, true, generation));
+                }
+            }
+        }
+        self.selections = selections;
+        self.merge_selections();
+    }
+
+    pub fn page_up(&mut self, amount: usize, expand: bool) {
+        self.move_heads(expand, |d, head| d.move_rows(head, -(amount as isize)));
+    }
+
+    pub fn page_down(&mut self, amount: usize, expand: bool) {
+        self.move_heads(expand, |d, head| d.move_rows(head, amount as isize));
+    }
+
+    /// Move the heads to the start of their wrapped row, then to the start of the indentation and to the start of the line
+    pub fn home(&mut self, expand: bool) {
+        self.move_heads(expand, |d, head| {
+            let row_start = d.row_columns(d.position_to_row(head)).start;
+            let indent_start = get_line_start_boundary(&d.rope.slice(..), head.line);
+            let column = match head.column {
+                c if c > row_start && row_start > 0 => row_start,
+                c if c == indent_start => 0,
+                _ => indent_start,
+            };
+            Position::new(head.line, column)
+        });
+    }
+
+    /// Move the heads to the end of their wrapped row, then to the end of the line
+    pub fn end(&mut self, expand: bool) {
+        self.move_heads(expand, |d, head| {
+            let row_end = d.row_end(d.position_to_row(head));
+            let column = if head.column != row_end {
+                row_end
+            } else {
+                line_len_grapheme(&d.rope.slice(..), head.line)
+            };
+            Position::new(head.line, column)
+        });
+    }
+
+    fn move_heads(&mut self, expand: bool, f: impl Fn(&Self, Position) -> Position) {
+        let mut selections = std::mem::take(&mut self.selections);
+        for s in &mut selections {
+            s.head = f(self, s.head);
+            if !expand {
+                s.tail = s.head;
+            }
+        }
+        self.selections = selections;
+        self.merge_selections();
+    }
+
+    /// The position `rows` screen rows below the head, or above it when negative.
+    /// The column in the row is kept in `vcol`, for the rows shorter than it
+    fn move_rows(&self, head: Position, rows: isize) -> Position {
+        let row = self.position_to_row(head);
+        let offset = head.vcol.saturating_sub(self.row_columns(row).start);
+        let row = (row as isize + rows).clamp(0, self.row_count() as isize - 1) as usize;
+        let start = self.row_columns(row).start;
+        let mut position = Position::new(self.row_to_line(row), (start + offset).min(self.row_end(row)));
+        position.vcol = start + offset;
+        position
+    }
+
+    /// A position in folded lines is moved after them when going forward, to the end of their header otherwise
+    fn skip_hidden_lines(&self, position: Position, forward: bool) -> Position {
+        match self.folds.hidden_range(position.line) {
+            Some(hidden) if forward && hidden.end < self.rope.len_lines() => {
+                Position::new(hidden.end, 0)
+            }
+            Some(hidden) => {
+                let line = hidden.start - 1;
+                Position::new(line, line_len_grapheme(&self.rope.slice(..), line))
+            }
+            None => position,
+        }
+    }
+
+    fn tab_len(&self) -> usize {
+        self.file_info.indentation.size()
+    }
+
+    /// A fold can start at the line
+    pub fn is_foldable(&self, line: usize) -> bool {
+        is_foldable(&self.rope, self.file_info.syntax, self.tab_len(), line)
+    }
+
+    /// The lines after this one are folded
+    pub fn is_folded(&self, line: usize) -> bool {
+        self.folds.is_folded(line)
+    }
+
+    /// The line is inside a fold and is not shown
+    pub fn is_line_hidden(&self, line: usize) -> bool {
+        self.folds.hidden_range(line).is_some()
+    }
+
+    /// Hide the block starting at the line: the lines inside the brackets opened by it for code,
+    /// the following lines that are more indented otherwise.
+    /// The selections inside the block are moved to the end of the line
+    pub fn fold(&mut self, line: usize) -> bool {
+        let Some(range) = fold_range(&self.rope, self.file_info.syntax, self.tab_len(), line)
+        else {
+            return false;
+        };
+        self.folds.add(range);
+        self.folds_changed();
+        self.selections_out_of_folds();
+        true
+    }
+
+    /// Show the lines hidden by the fold starting at the line
+    pub fn unfold(&mut self, line: usize) -> bool {
+        let unfolded = self.folds.remove(line);
+        if unfolded {
+            self.folds_changed();
+        }
+        unfolded
+    }
+
+    pub fn toggle_fold(&mut self, line: usize) -> bool {
+        self.unfold(line) || self.fold(line)
+    }
+
+    /// Fold every block of the document, nested ones included
+    pub fn fold_all(&mut self) {
+        for range in fold_ranges(&self.rope, self.file_info.syntax, self.tab_len()) {
+            self.folds.add(range);
+        }
+        self.folds_changed();
+        self.selections_out_of_folds();
+    }
+
+    pub fn unfold_all(&mut self) {
+        self.folds.clear();
+        self.folds_changed();
+    }
+
+    /// Unfold what hides the line
+    pub fn reveal_line(&mut self, line: usize) {
+        if self.folds.reveal(line) {
+            self.folds_changed();
+        }
+    }
+
+    // the wrapped rows are counted without the hidden lines
+    fn folds_changed(&mut self) {
+        self.wraps.update_rows(&self.rope, &self.folds);
+    }
+
+    /// Number of rows shown on screen: the folded lines are not counted, the wrapped lines take several rows
+    pub fn row_count(&self) -> usize {
+        if self.wraps.is_enabled() {
+            self.wraps.row_count()
+        } else {
+            self.folds.row_count(self.rope.len_lines())
+        }
+    }
+
+    /// The first screen row of the line, folded lines are on the row of their fold header
+    pub fn line_to_row(&self, line: usize) -> usize {
+        if self.wraps.is_enabled() {
+            let line = self.folds.hidden_range(line).map_or(line, |r| r.start - 1);
+            self.wraps.line_to_row(line)
+        } else {
+            self.folds.line_to_row(line)
+        }
     }
 
-    /// return the line indentation length in char
-    pub fn line_indent_len(&self, line: usize) -> usize {
-        get_line_start_boundary(&self.rope.slice(..), line)
+    /// The line shown on the screen row
+    pub fn row_to_line(&self, row: usize) -> usize {
+        if self.wraps.is_enabled() {
+            self.wraps.row_to_line(row).0
+        } else {
+            self.folds.row_to_line(row)
+        }
     }
 
-    /// return the end position of the line
-    pub fn line_end(&mut self, line: usize) -> Position {
-        char_to_position(
-            &self.rope.slice(..),
-            self.rope.line_to_char(line) + line_len_char(&self.rope.slice(..), line),
-        )
+    /// The screen row showing the position, one of the rows of its line when it's wrapped
+    pub fn position_to_row(&self, position: Position) -> usize {
+        let row = self.line_to_row(position.line);
+        if self.is_line_hidden(position.line) {
+            return row;
+        }
+        row + self
+            .wraps
+            .breaks(position.line)
+            .partition_point(|b| *b <= position.column)
+    }
+
+    /// The columns of its line shown on the screen row: the whole line, or a part of it when the line is wrapped
+    pub fn row_columns(&self, row: usize) -> Range<usize> {
+        if self.wraps.is_enabled() {
+            let (line, index) = self.wraps.row_to_line(row);
+            let line_len = line_len_grapheme(&self.rope.slice(..), line);
+            self.wraps.columns(line, index, line_len)
+        } else {
+            0..line_len_grapheme(&self.rope.slice(..), self.row_to_line(row))
+        }
     }
 
-    /// return the end position of the line including end of line characters
-    pub fn line_end_full(&mut self, line: usize) -> Position {
-        self.line_start(line + 1)
+    /// The last column of the screen row where a cursor can be: the end of the line for its last row,
+    /// the column before the next row otherwise, since a cursor at the start of a row is shown on it
+    pub fn row_end(&self, row: usize) -> usize {
+        let columns = self.row_columns(row);
+        let line_len = line_len_grapheme(&self.rope.slice(..), self.row_to_line(row));
+        if columns.end < line_len {
+            columns.end - 1
+        } else {
+            columns.end
+        }
     }
 
-    pub fn select_line(&mut self, line: usize) {
-        let tail = self.line_start(line);
-        let head = self.line_end_full(line);
-        self.selections = vec![Selection {
-            head,
-            tail,
-            is_clone: false,
-            generation: 0,
-        }]
+    /// The long lines are wrapped on several rows
+    pub fn is_soft_wrapped(&self) -> bool {
+        self.wraps.is_enabled()
     }
 
-    pub fn select_all(&mut self) {
-        let tail = char_to_position(&self.rope.slice(..), 0);
-        let head = char_to_position(&self.rope.slice(..), self.rope.len_chars());
-        self.selections = vec![Selection {
-            head,
-            tail,
-            is_clone: false,
-            generation: 0,
-        }]
+    /// Wrap the long lines at the [wrap width](Self::set_wrap_width), or show them on a single row.
+    /// Large files are never wrapped
+    pub fn set_soft_wrap(&mut self, enabled: bool) {
+        let tab_len = self.tab_len();
+        self.wraps
+            .set_enabled(enabled && !self.large_file, &self.rope, &self.folds, tab_len);
     }
 
-    /// Specify the main selection. Also cancel multi-cursor
-    pub fn set_main_selection(&mut self, head: Position, tail: Position) {
-        self.selections = vec![Selection {
-            head,
-            tail,
-            is_clone: false,
-            generation: 0,
-        }]
+    pub fn toggle_soft_wrap(&mut self) {
+        self.set_soft_wrap(!self.is_soft_wrapped());
     }
 
-    pub fn cancel_multi_cursor(&mut self) {
-        self.selections = self
-            .selections
-            .iter()
-            .filter(|s| !s.is_clone)
-            .copied()
-            .collect();
+    /// The number of visible columns of a wrapped row
+    pub fn wrap_width(&self) -> usize {
+        self.wraps.width()
     }
 
-    /// duplicate the selection in the given direction
-    pub fn duplicate_selection(&mut self, direction: MoveDirection) {
-        match direction {
-            MoveDirection::Down => {
-                let s = *self.selections.iter().max().unwrap();
-                let mut news = s;
-                news.head.line = usize::min(s.head.line + 1, self.rope.len_lines() - 1);
-                news.head.column = s
-                    .head
-                    .vcol
-                    .min(line_len_grapheme(&self.rope.slice(..), news.head.line));
-                news.tail = news.head;
-                news.is_clone = true;
-                if news.head.line > s.head.line {
-                    self.selections.push(news);
-                }
-            }
-            MoveDirection::Up => {
-                let s = *self.selections.iter().min().unwrap();
-                let mut news = s;
-                news.head.line = s.head.line.saturating_sub(1);
-                news.head.column = s
-                    .head
-                    .vcol
-                    .min(line_len_grapheme(&self.rope.slice(..), news.head.line));
-                news.tail = news.head;
-                news.is_clone = true;
-                if news.head.line < s.head.line {
-                    self.selections.push(news);
-                }
-            }
-            _ => (),
-        }
+    /// Set the number of visible columns of a wrapped row, tabs counting for several columns
+    pub fn set_wrap_width(&mut self, width: usize) {
+        let tab_len = self.tab_len();
+        self.wraps.set_width(width, &self.rope, &self.folds, tab_len);
+    }
 
-        self.merge_selections();
+    /// The bracket next to the char index, the one after it first, and its match as char indexes
+    fn bracket_pair(&self, char_idx: usize) -> Option<(usize, usize)> {
+        [Some(char_idx), char_idx.checked_sub(1)]
+            .into_iter()
+            .flatten()
+            .find_map(|idx| {
+                matching_bracket(&self.rope, self.file_info.syntax, idx).map(|m| (idx, m))
+            })
     }
 
-    /// ducplicate the selection based on the currently selected text
-    pub fn duplicate_selection_for_selected_text(&mut self) {
-        let start = self.position_to_char(self.selections[0].start());
-        let end = self.position_to_char(self.selections[0].end());
-        let content = self.rope.slice(start..end).to_string();
-        let mut s = self
+    /// The brackets next to the cursors of the lines with the brackets matching them.
+    /// The brackets in strings and comments are ignored. Only the first cursors are looked at,
+    /// their matches are kept until they move or the text changes
+    pub fn matching_brackets(&self, lines: Range<usize>) -> Vec<(Position, Position)> {
+        let cursors = self
             .selections
             .iter()
-            .max_by_key(|s| s.generation)
-            .unwrap()
-            .duplicate();
-        let next = self.find_from(&content, s.end(), true, true);
-        if let Some((start, end)) = next {
-            s.tail = start;
-            s.head = end;
-            self.selections.push(s);
-            self.merge_selections();
-        }
+            .filter(|s| lines.contains(&s.head.line))
+            .take(MAX_MATCHED_CURSORS)
+            .map(|s| self.position_to_char(s.head))
+            .collect();
+        self.bracket_cache
+            .pairs(cursors, |cursors| {
+                cursors.iter().filter_map(|idx| self.bracket_pair(*idx)).collect()
+            })
+            .into_iter()
+            .map(|(b, m)| (self.char_to_position(b), self.char_to_position(m)))
+            .collect()
     }
 
-    pub fn page_up(&mut self, amount: usize, expand: bool) {
-        for s in &mut self.selections {
-            s.head.line = s.head.line.saturating_sub(amount);
-            s.head.column = s
-                .head
-                .vcol
-                .min(line_len_grapheme(&self.rope.slice(..), s.head.line));
-            if !expand {
-                s.tail = s.head;
+    /// Move the cursors to the bracket matching the one next to them,
+    /// or to the closing bracket of the block they are in.
+    /// If expand is true, only the head of the selection is moved (the cursor part)
+    pub fn goto_matching_bracket(&mut self, expand: bool) {
+        for i in 0..self.selections.len() {
+            let s = self.selections[i];
+            let head_idx = self.position_to_char(s.head);
+            let target = self.bracket_pair(head_idx).map(|(_, m)| m).or_else(|| {
+                enclosing_brackets(&self.rope, self.file_info.syntax, head_idx..head_idx)
+                    .map(|(_, close)| close)
+            });
+            if let Some(target) = target {
+                let head = self.char_to_position(target);
+                let tail = if !expand { head } else { s.tail };
+                self.reveal_line(head.line);
+                self.selections[i] = Selection::new(head, tail, s.is_clone, s.generation);
             }
         }
         self.merge_selections();
     }
 
-    pub fn page_down(&mut self, amount: usize, expand: bool) {
-        for s in &mut self.selections {
-            s.head.line = usize::min(s.head.line + amount, self.rope.len_lines() - 1);
-            s.head.column = s
-                .head
-                .vcol
-                .min(line_len_grapheme(&self.rope.slice(..), s.head.line));
-            if !expand {
-                s.tail = s.head;
+    /// Select the text between the brackets around each selection.
+    /// When it's already selected, the selection grows to the content of the next brackets
+    pub fn select_inside_brackets(&mut self) {
+        for i in 0..self.selections.len() {
+            let s = self.selections[i];
+            let start = self.position_to_char(s.start());
+            let end = self.position_to_char(s.end());
+            let mut pair = enclosing_brackets(&self.rope, self.file_info.syntax, start..end);
+            if let Some((open, close)) = pair {
+                if open + 1 == start && close == end {
+                    pair = enclosing_brackets(&self.rope, self.file_info.syntax, open..close + 1);
+                }
             }
-        }
-        self.merge_selections();
-    }
-
-    pub fn home(&mut self, expand: bool) {
-        for s in &mut self.selections {
-            s.head.column = match s.head.column {
-                c if c == get_line_start_boundary(&self.rope.slice(..), s.head.line) => 0,
-                _ => get_line_start_boundary(&self.rope.slice(..), s.head.line),
-            };
-            s.head.vcol = s.head.column;
-            if !expand {
-                s.tail = s.head;
+            if let Some((open, close)) = pair {
+                let tail = self.char_to_position(open + 1);
+                let head = self.char_to_position(close);
+                self.reveal_line(head.line);
+                self.selections[i] = Selection::new(head, tail, s.is_clone, s.generation);
             }
         }
-
         self.merge_selections();
     }
-    pub fn end(&mut self, expand: bool) {
+
+    fn selections_out_of_folds(&mut self) {
         for s in &mut self.selections {
-            s.head.column = line_len_grapheme(&self.rope.slice(..), s.head.line);
-            s.head.vcol = s.head.column;
-            if !expand {
-                s.tail = s.head;
+            for p in [&mut s.head, &mut s.tail] {
+                if let Some(hidden) = self.folds.hidden_range(p.line) {
+                    let line = hidden.start - 1;
+                    *p = Position::new(line, line_len_grapheme(&self.rope.slice(..), line));
+                }
             }
         }
-
         self.merge_selections();
     }
 
@@ -1438,6 +2850,16 @@ fn test_visible_col_to_byte() {
     assert_eq!(s.visible_col_to_byte(0, 9), 5);
 }
 
+/// How a document is written by [Document::save_with_options]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SaveOptions {
+    /// Keep the previous content of the file in a `.bak` file next to it
+    pub backup: bool,
+    /// Write back the original bytes of the invalid sequences found when decoding the file,
+    /// instead of the encoded replacement char
+    pub keep_invalid_bytes: bool,
+}
+
 pub enum MoveDirection {
     Up,
     Down,
@@ -1715,13 +3137,22 @@ fn test_position_from_char_idx() {
     }
 }
 
-fn char_to_position(rope: &RopeSlice, char_idx: usize) -> Position {
+pub(crate) fn char_to_position(rope: &RopeSlice, char_idx: usize) -> Position {
     let line = rope.char_to_line(char_idx.min(rope.len_chars()));
     //let column = print_positions::print_positions(&rope.line(line).chars().take(char_idx).collect::<String>()).count();
     let column = char_to_grapheme(&rope.line(line), char_idx - rope.line_to_char(line));
     Position::new(line, column)
 }
 
+/// Char offset of the start of each line, and of the end of the last one
+fn line_char_offsets(lines: &[&str]) -> Vec<usize> {
+    let mut offsets = vec![0];
+    for l in lines {
+        offsets.push(offsets.last().unwrap() + l.chars().count());
+    }
+    offsets
+}
+
 fn line_len_grapheme(rope: &RopeSlice, line_idx: usize) -> usize {
     //line_len_char(rope, line_idx)
     char_to_grapheme(&rope.line(line_idx), line_len_char(rope, line_idx))
@@ -1734,9 +3165,11 @@ fn line_len_grapheme(rope: &RopeSlice, line_idx: usize) -> usize {
 // }
 #[cfg(test)]
 mod test {
+    use std::sync::Arc;
+
     use ropey::Rope;
 
-    use crate::{rope_utils::char_to_grapheme, Document};
+    use crate::{rope_utils::char_to_grapheme, Document, Position, SearchOptions, Searcher};
 
     #[test]
     fn test_char_to_grapheme() {
@@ -1756,6 +3189,24 @@ mod test {
         assert_eq!(idx, (doc.char_to_position(21), doc.char_to_position(26)));
     }
 
+    #[test]
+    fn undo_keeps_redo_branches() {
+        let mut doc = Document::default();
+        doc.insert("hello");
+        doc.insert(" world");
+        doc.undo();
+        doc.insert(" there");
+        assert_eq!(doc.rope.to_string(), "hello there");
+        doc.undo();
+        assert_eq!(doc.redo_branch_count(), 2);
+        doc.next_redo_branch();
+        doc.redo();
+        assert_eq!(doc.rope.to_string(), "hello world");
+        doc.earlier(10);
+        assert_eq!(doc.rope.to_string(), "");
+        assert!(!doc.is_dirty());
+    }
+
     #[test]
     fn find_from_no_match() {
         let mut doc = Document::default();
@@ -1764,4 +3215,586 @@ mod test {
         let idx = doc.find_from(s, doc.char_to_position(0), false, true);
         assert_eq!(idx, None);
     }
+
+    #[test]
+    fn replace_all_is_one_undo_step() {
+        let mut doc = Document::default();
+        doc.insert("foo(1) Foo(2) bar(3)");
+        let options = SearchOptions {
+            regex: true,
+            ..Default::default()
+        };
+        let searcher = Searcher::new(r"foo\((\d)\)", options).unwrap();
+        assert_eq!(doc.replace_all(&searcher, "baz[$1]", true), 2);
+        assert_eq!(doc.rope.to_string(), "baz[1] Baz[2] bar(3)");
+        doc.undo();
+        assert_eq!(doc.rope.to_string(), "foo(1) Foo(2) bar(3)");
+        doc.redo();
+        assert_eq!(doc.rope.to_string(), "baz[1] Baz[2] bar(3)");
+    }
+
+    #[test]
+    fn replace_next_and_in_selection() {
+        let mut doc = Document::default();
+        doc.insert("a a a\na a");
+        let searcher = Searcher::new("a", SearchOptions::default()).unwrap();
+        let replaced = doc.replace(&searcher, Position::new(0, 1), "bb", false);
+        assert_eq!(replaced, Some((Position::new(0, 2), Position::new(0, 4))));
+        assert_eq!(doc.rope.to_string(), "a bb a\na a");
+
+        doc.set_main_selection(Position::new(1, 1), Position::new(0, 5));
+        assert_eq!(doc.replace_in_selections(&searcher, "c", false), 2);
+        assert_eq!(doc.rope.to_string(), "a bb c\nc a");
+        doc.undo();
+        assert_eq!(doc.rope.to_string(), "a bb a\na a");
+    }
+
+    #[test]
+    fn search_cache() {
+        let mut doc = Document::default();
+        doc.insert("Foo foo");
+        let options = SearchOptions {
+            regex: true,
+            ..Default::default()
+        };
+        let searcher = Searcher::new("fo+", options).unwrap();
+        assert_eq!(doc.find_all(&searcher).count(), 2);
+        // the clones share the copy made by the first search
+        let clone = doc.clone();
+        assert!(Arc::ptr_eq(
+            &doc.search_cache.text(&doc.rope),
+            &clone.search_cache.text(&clone.rope)
+        ));
+        doc.insert(" FOO");
+        assert_eq!(doc.find_all(&searcher).count(), 3);
+        assert_eq!(clone.find_all(&searcher).count(), 2);
+    }
+
+    #[test]
+    fn reload_keeps_cursors() {
+        let path = std::env::temp_dir().join(format!("ndoc_reload_{}.txt", std::process::id()));
+        std::fs::write(&path, "one\ntwo\nthree\n").unwrap();
+        let mut doc = Document::from_file(&path).unwrap();
+        assert!(!doc.has_changed_on_disk());
+        doc.set_main_selection(Position::new(2, 2), Position::new(2, 2));
+
+        std::fs::write(&path, "zero\none\ntwo!\nthree\n").unwrap();
+        assert!(doc.has_changed_on_disk());
+        doc.reload().unwrap();
+        assert!(!doc.has_changed_on_disk());
+        assert!(!doc.is_dirty());
+        assert_eq!(doc.rope.to_string(), "zero\none\ntwo!\nthree\n");
+        assert_eq!(doc.selections[0].head, Position::new(3, 2));
+        assert!(doc.diff_with_disk().unwrap().is_empty());
+
+        doc.undo();
+        assert_eq!(doc.rope.to_string(), "one\ntwo\nthree\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn save_follows_symlinks_and_keeps_permissions() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let root = std::env::temp_dir().join(format!("ndoc_save_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let target = root.join("target.sh");
+        let link = root.join("link.sh");
+        std::fs::write(&target, "old\n").unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o750)).unwrap();
+        symlink(&target, &link).unwrap();
+
+        let mut doc = Document::from_file(&link).unwrap();
+        doc.insert("new ");
+        let options = crate::SaveOptions {
+            backup: true,
+            ..Default::default()
+        };
+        doc.save_with_options(&link, options).unwrap();
+
+        assert!(std::fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "new old\n");
+        assert_eq!(std::fs::read_to_string(root.join("target.sh.bak")).unwrap(), "old\n");
+        let mode = std::fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o750);
+        assert!(!doc.is_dirty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn reopen_and_save_with_encoding() {
+        let path = std::env::temp_dir().join(format!("ndoc_encoding_{}.txt", std::process::id()));
+        // "été €" in windows-1252
+        std::fs::write(&path, [0xE9, 0x74, 0xE9, 0x20, 0x80]).unwrap();
+        let mut doc = Document::from_file(&path).unwrap();
+        doc.reopen_with_encoding(encoding_rs::ISO_8859_15).unwrap();
+        assert_eq!(doc.rope.to_string(), "été \u{80}");
+        doc.reopen_with_encoding(encoding_rs::WINDOWS_1252).unwrap();
+        assert_eq!(doc.rope.to_string(), "été €");
+        assert!(!doc.is_dirty());
+
+        assert_eq!(
+            doc.unencodable_chars(encoding_rs::KOI8_R),
+            [(Position::new(0, 0), 'é'), (Position::new(0, 2), 'é'), (Position::new(0, 4), '€')]
+        );
+        assert!(doc.unencodable_chars(encoding_rs::UTF_16LE).is_empty());
+
+        doc.set_encoding(encoding_rs::UTF_8, true);
+        doc.save_as(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), "\u{feff}été €".as_bytes());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keep_invalid_bytes_on_save() {
+        let path = std::env::temp_dir().join(format!("ndoc_lossy_{}.txt", std::process::id()));
+        std::fs::write(&path, b"\xEF\xBB\xBFa\xFFb\nc\xC3\n").unwrap();
+        let mut doc = Document::from_file(&path).unwrap();
+        assert_eq!(doc.rope.to_string(), "a\u{FFFD}b\nc\u{FFFD}\n");
+        let errors = &doc.file_info.decode_errors;
+        assert_eq!(errors.iter().map(|e| e.offset).collect::<Vec<_>>(), [4, 8]);
+        assert_eq!(errors[1].bytes, [0xC3]);
+
+        // the second error is edited away, the first one is moved to the next line
+        doc.set_main_selection(Position::new(1, 2), Position::new(1, 1));
+        doc.insert("d");
+        doc.set_main_selection(Position::new(0, 0), Position::new(0, 0));
+        doc.insert("z\n");
+        assert_eq!(doc.decode_errors().len(), 1);
+        assert_eq!(doc.decode_errors()[0].0, Position::new(1, 1));
+        // the errors follow undo and redo too
+        doc.undo();
+        assert_eq!(doc.decode_errors()[0].0, Position::new(0, 1));
+        doc.redo();
+        assert_eq!(doc.decode_errors()[0].0, Position::new(1, 1));
+
+        let options = crate::SaveOptions {
+            keep_invalid_bytes: true,
+            ..Default::default()
+        };
+        doc.save_with_options(&path, options).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"\xEF\xBB\xBFz\na\xFFb\ncd\n");
+        assert_eq!(doc.file_info.decode_errors[0].offset, 6);
+
+        doc.save_as(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), "\u{FEFF}z\na\u{FFFD}b\ncd\n".as_bytes());
+        assert!(doc.decode_errors().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn convert_mixed_linefeeds() {
+        let mut doc = Document::default();
+        doc.insert("a\r\nb\nc\rd\r\n");
+        let count = doc.linefeed_count();
+        assert_eq!((count.lf, count.crlf, count.cr), (1, 2, 1));
+        assert!(count.is_mixed());
+
+        assert_eq!(doc.convert_linefeeds(crate::LineFeed::LF), 3);
+        assert_eq!(doc.rope.to_string(), "a\nb\nc\nd\n");
+        assert!(!doc.linefeed_count().is_mixed());
+        assert_eq!((doc.file_info.linefeed, doc.file_info.mixed_linefeeds), (crate::LineFeed::LF, false));
+        doc.undo();
+        assert_eq!(doc.rope.to_string(), "a\r\nb\nc\rd\r\n");
+        assert_eq!((doc.file_info.linefeed, doc.file_info.mixed_linefeeds), (crate::LineFeed::CRLF, true));
+        doc.redo();
+        assert_eq!((doc.file_info.linefeed, doc.file_info.mixed_linefeeds), (crate::LineFeed::LF, false));
+        doc.undo();
+
+        assert_eq!(doc.convert_linefeeds(crate::LineFeed::CRLF), 2);
+        assert_eq!(doc.rope.to_string(), "a\r\nb\r\nc\r\nd\r\n");
+    }
+
+    #[test]
+    fn large_file_mode() {
+        let path = std::env::temp_dir().join(format!("ndoc_large_{}.txt", std::process::id()));
+        // long enough to be decoded in two chunks, with an invalid byte at the end of the first one
+        let mut content = "\u{FEFF}".to_string();
+        content.push_str(&"    héllo wörld\n".repeat(70_000));
+        let mut bytes = content.into_bytes();
+        // a lead byte followed by a space
+        bytes[(1 << 20) - 1] = 0xC3;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut progress = Vec::new();
+        let doc = Document::from_file_with_progress(&path, 1024, |p| progress.push(p)).unwrap();
+        assert!(doc.is_large_file());
+        assert_eq!(progress.len(), 2);
+        assert_eq!(progress.last(), Some(&1.));
+        assert_eq!(doc.file_info.encoding, encoding_rs::UTF_8);
+        assert_eq!(doc.file_info.decode_errors.len(), 1);
+        assert_eq!(doc.file_info.decode_errors[0].offset, (1 << 20) - 1);
+        assert_eq!(doc.file_info.decode_errors[0].bytes, [0xC3]);
+        assert_eq!(doc.file_info.indentation, crate::Indentation::default());
+        assert!(!doc.has_changed_on_disk());
+
+        let small = Document::from_file(&path).unwrap();
+        assert!(!small.is_large_file());
+        assert_eq!(doc.rope, small.rope);
+        assert_eq!(doc.file_info.decode_errors, small.file_info.decode_errors);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn binary_detection() {
+        let path = std::env::temp_dir().join(format!("ndoc_binary_{}", std::process::id()));
+        let content = [0x7F, b'E', b'L', b'F', 0x02, 0x01, 0x01, 0x00, 0xFF, 0xC3];
+        std::fs::write(&path, content).unwrap();
+        let mut doc = Document::from_file(&path).unwrap();
+        assert!(doc.is_binary());
+        assert_eq!(doc.binary_content(), Some(content.as_slice()));
+        assert_eq!(doc.rope.len_chars(), 0);
+
+        doc.save_as(&path).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+
+        // invalid sequences once decoded, unless the file is too short to tell
+        let garbage = [0xC3, 0x28, 0xFF, 0x80, 0x41, 0x42, 0x43];
+        std::fs::write(&path, [[0xEF, 0xBB, 0xBF].as_slice(), &garbage].concat()).unwrap();
+        assert!(!Document::from_file(&path).unwrap().is_binary());
+        std::fs::write(&path, [[0xEF, 0xBB, 0xBF].as_slice(), &garbage.repeat(100)].concat()).unwrap();
+        assert!(Document::from_file(&path).unwrap().is_binary());
+
+        // UTF-16 with a BOM is text
+        let mut bytes = vec![0xFF, 0xFE];
+        "hello".encode_utf16().for_each(|c| bytes.extend_from_slice(&c.to_le_bytes()));
+        std::fs::write(&path, &bytes).unwrap();
+        let doc = Document::from_file(&path).unwrap();
+        assert!(!doc.is_binary());
+        assert_eq!(doc.rope, "hello");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn move_over_folds() {
+        use crate::MoveDirection;
+
+        let mut doc = Document::default();
+        doc.insert("a\n  b\n  c\nd\n");
+        assert!(doc.fold(0));
+        assert!(doc.is_folded(0) && doc.is_line_hidden(2));
+        assert_eq!(doc.row_count(), 3);
+
+        doc.set_main_selection(Position::new(0, 1), Position::new(0, 1));
+        doc.move_selections(MoveDirection::Down, false);
+        assert_eq!(doc.selections[0].head, Position::new(3, 1));
+        doc.move_selections(MoveDirection::Left, false);
+        doc.move_selections(MoveDirection::Left, false);
+        assert_eq!(doc.selections[0].head, Position::new(0, 1));
+        doc.move_selections(MoveDirection::Right, false);
+        assert_eq!(doc.selections[0].head, Position::new(3, 0));
+
+        // the fold follows the edits before it
+        doc.set_main_selection(Position::new(0, 0), Position::new(0, 0));
+        doc.insert("z\n");
+        assert!(doc.is_folded(1) && !doc.is_folded(0));
+        doc.undo();
+        assert!(doc.is_folded(0));
+        doc.unfold_all();
+        assert_eq!(doc.row_count(), 5);
+    }
+
+    #[test]
+    fn soft_wrap() {
+        use crate::MoveDirection;
+
+        let mut doc = Document::default();
+        doc.insert("one two three four\nfive\n");
+        doc.set_wrap_width(9);
+        doc.set_soft_wrap(true);
+        // "one two " "three " "four"
+        assert_eq!(doc.row_count(), 5);
+        assert_eq!(doc.row_to_line(2), 0);
+        assert_eq!(doc.row_columns(1), 8..14);
+        assert_eq!(doc.line_to_row(1), 3);
+
+        doc.set_main_selection(Position::new(0, 2), Position::new(0, 2));
+        doc.move_selections(MoveDirection::Down, false);
+        assert_eq!(doc.selections[0].head, Position::new(0, 10));
+        assert_eq!(doc.position_to_row(doc.selections[0].head), 1);
+        doc.end(false);
+        assert_eq!(doc.selections[0].head, Position::new(0, 13));
+        doc.end(false);
+        assert_eq!(doc.selections[0].head, Position::new(0, 18));
+        doc.home(false);
+        assert_eq!(doc.selections[0].head, Position::new(0, 14));
+        doc.move_selections(MoveDirection::Down, false);
+        assert_eq!(doc.selections[0].head, Position::new(1, 0));
+
+        // the edited lines are wrapped again
+        doc.insert("six seven ");
+        // "six seven " "five"
+        assert_eq!(doc.row_count(), 6);
+        doc.set_soft_wrap(false);
+        assert_eq!(doc.row_count(), 3);
+        assert_eq!(doc.row_columns(0), 0..18);
+    }
+
+    #[test]
+    fn block_selection() {
+        use crate::MoveDirection;
+
+        let mut doc = Document::default();
+        doc.insert("abcd\n\tx\na\nabcdef");
+        doc.select_block(0, 1, 3, 3);
+        assert!(doc.is_block_selection());
+        assert_eq!(doc.get_selection_content(), "bc\n\n\nbc");
+        // the tab covers the visible columns 0 to 3
+        doc.select_block(1, 4, 1, 5);
+        assert_eq!(doc.get_selection_content(), "x");
+
+        // the short lines are padded
+        doc.select_block(0, 5, 3, 5);
+        doc.insert("|");
+        assert_eq!(doc.rope, "abcd |\n\tx|\na    |\nabcde|f");
+        assert!(!doc.is_block_selection());
+        doc.undo();
+        assert_eq!(doc.rope, "abcd\n\tx\na\nabcdef");
+
+        // the cursors in virtual space don't delete
+        doc.select_block(2, 3, 3, 3);
+        doc.backspace();
+        assert_eq!(doc.rope, "abcd\n\tx\na\nabdef");
+
+        doc.set_main_selection(Position::new(0, 1), Position::new(0, 1));
+        doc.expand_block_selection(MoveDirection::Down);
+        doc.expand_block_selection(MoveDirection::Down);
+        doc.expand_block_selection(MoveDirection::Right);
+        assert_eq!(doc.selections.len(), 3);
+        assert_eq!(doc.get_selection_content(), "b\n\n");
+        doc.insert_many("1\n2\n3");
+        assert_eq!(doc.rope, "a1cd\n\t2x\na3\nabdef");
+    }
+
+    #[test]
+    fn block_selection_soft_wrap() {
+        let mut doc = Document::default();
+        doc.insert("\tab cd ef gh\n0123456789abcdef");
+        doc.set_wrap_width(9);
+        doc.set_soft_wrap(true);
+        // the second row of the first line starts at the column 7, after a tab
+        assert_eq!(doc.row_columns(1), 7..12);
+        assert_eq!(doc.col_to_vcol(0, 7), 10);
+        assert_eq!(doc.row_columns(3), 9..16);
+
+        // from the start of the second row of the first line to the second row of the next one
+        doc.select_block(0, 10, 1, 12);
+        assert_eq!(doc.get_selection_content(), "ef\nab");
+        assert_eq!(doc.position_to_row(doc.selections[0].head), 1);
+        assert_eq!(doc.position_to_row(doc.selections[1].head), 3);
+    }
+
+    #[test]
+    fn occurrences() {
+        let mut doc = Document::default();
+        doc.insert("foo bar\nFoo foo food\nbar");
+        // the word under the cursor matches whole words with the same case
+        doc.set_main_selection(Position::new(1, 5), Position::new(1, 5));
+        doc.select_all_occurrences();
+        assert_eq!(doc.selections.len(), 2);
+        assert_eq!(doc.get_selection_content(), "foo\nfoo");
+        // the selected text matches anywhere
+        doc.set_main_selection(Position::new(1, 7), Position::new(1, 4));
+        doc.select_all_occurrences();
+        assert_eq!(doc.get_selection_content(), "foo\nFoo\nfoo\nfoo");
+
+        doc.set_main_selection(Position::new(0, 3), Position::new(0, 0));
+        doc.duplicate_selection_for_selected_text();
+        assert_eq!(doc.selections[1].start(), Position::new(1, 0));
+        doc.skip_occurrence();
+        assert_eq!(doc.selections.len(), 2);
+        assert_eq!(doc.selections[1].start(), Position::new(1, 4));
+
+        doc.select_word(Position::new(0, 5));
+        doc.duplicate_selection_for_selected_text();
+        assert_eq!(doc.selections[1].start(), Position::new(2, 0));
+        // the next one wraps around to the main selection, the cursor stays
+        doc.skip_occurrence();
+        assert_eq!(doc.selections.len(), 2);
+        assert_eq!(doc.selections[1].start(), Position::new(2, 0));
+        doc.undo_last_cursor();
+        assert_eq!(doc.selections.len(), 1);
+        assert_eq!(doc.selections[0].start(), Position::new(0, 4));
+
+        doc.set_main_selection(Position::new(2, 1), Position::new(0, 1));
+        doc.add_cursors_at_line_ends();
+        assert_eq!(
+            doc.selections.iter().map(|s| s.head).collect::<Vec<_>>(),
+            [Position::new(0, 7), Position::new(1, 12), Position::new(2, 1)]
+        );
+        doc.undo_last_cursor();
+        assert_eq!(doc.selections.len(), 2);
+    }
+
+    #[test]
+    fn brackets() {
+        let mut doc = Document::default();
+        doc.insert("f(a, [b, c])");
+        doc.set_main_selection(Position::new(0, 10), Position::new(0, 10));
+        assert_eq!(
+            doc.matching_brackets(0..1),
+            [(Position::new(0, 10), Position::new(0, 5))]
+        );
+        doc.select_inside_brackets();
+        assert_eq!(doc.get_selection_content(), "b, c");
+        doc.select_inside_brackets();
+        assert_eq!(doc.get_selection_content(), "a, [b, c]");
+
+        doc.set_main_selection(Position::new(0, 3), Position::new(0, 3));
+        doc.goto_matching_bracket(false);
+        assert_eq!(doc.selections[0].head, Position::new(0, 11));
+        doc.goto_matching_bracket(false);
+        assert_eq!(doc.selections[0].head, Position::new(0, 1));
+
+        // the matches follow the edits, only the cursors of the lines are looked at
+        doc.set_main_selection(Position::new(0, 10), Position::new(0, 10));
+        doc.insert("d");
+        assert_eq!(
+            doc.matching_brackets(0..1),
+            [(Position::new(0, 11), Position::new(0, 5))]
+        );
+        assert!(doc.matching_brackets(1..2).is_empty());
+    }
+
+    #[test]
+    fn auto_pairs() {
+        let mut doc = Document::default();
+        doc.insert("a\nb");
+        doc.selections = vec![
+            (Position::new(0, 1), Position::new(0, 1)).into(),
+            (Position::new(1, 1), Position::new(1, 1)).into(),
+        ];
+        doc.insert("(");
+        doc.insert("\"");
+        assert_eq!(doc.rope.to_string(), "a(\"\")\nb(\"\")");
+        doc.insert("x");
+        doc.insert("\"");
+        doc.insert(")");
+        assert_eq!(doc.rope.to_string(), "a(\"x\")\nb(\"x\")");
+        assert_eq!(doc.selections[1].head, Position::new(1, 6));
+
+        // a closing char typed by hand is not typed over
+        doc.insert(")");
+        assert_eq!(doc.rope.to_string(), "a(\"x\"))\nb(\"x\"))");
+        // no quote pair after a word
+        doc.insert("it'");
+        assert_eq!(doc.rope.to_string(), "a(\"x\"))it'\nb(\"x\"))it'");
+
+        doc.insert("[");
+        doc.backspace();
+        assert_eq!(doc.rope.to_string(), "a(\"x\"))it'\nb(\"x\"))it'");
+
+        doc.set_main_selection(Position::new(0, 0), Position::new(0, 1));
+        doc.insert("[");
+        assert_eq!(doc.rope.line(0).to_string(), "[a](\"x\"))it'\n");
+        assert_eq!(doc.get_selection_content(), "a");
+    }
+
+    #[test]
+    fn toggle_comments() {
+        let mut doc = Document::default();
+        doc.file_info.syntax = crate::syntax::SYNTAXSET.find_syntax_by_name("Rust").unwrap();
+        doc.insert("fn f() {\n\tif x {\n        y();\n\n\t}\n}\n");
+        doc.set_main_selection(Position::new(1, 0), Position::new(4, 1));
+        doc.toggle_line_comment();
+        assert_eq!(
+            doc.rope.to_string(),
+            "fn f() {\n\t// if x {\n    //     y();\n\n\t// }\n}\n"
+        );
+        doc.toggle_line_comment();
+        assert_eq!(doc.rope.to_string(), "fn f() {\n\tif x {\n        y();\n\n\t}\n}\n");
+
+        doc.set_main_selection(Position::new(2, 8), Position::new(2, 11));
+        doc.toggle_block_comment();
+        assert_eq!(doc.rope.line(2).to_string(), "        /*y()*/;\n");
+        assert_eq!(doc.get_selection_content(), "/*y()*/");
+        doc.toggle_block_comment();
+        assert_eq!(doc.rope.line(2).to_string(), "        y();\n");
+
+        // one undo step
+        doc.undo();
+        assert_eq!(doc.rope.line(2).to_string(), "        /*y()*/;\n");
+
+        // the text between two comments is commented
+        doc.set_main_selection(Position::new(2, 8), Position::new(2, 15));
+        doc.insert("/* a */ b /* c */");
+        doc.set_main_selection(Position::new(2, 8), Position::new(2, 25));
+        doc.toggle_block_comment();
+        assert_eq!(doc.rope.line(2).to_string(), "        /*/* a */ b /* c */*/;\n");
+
+        // trailing whitespace after a block comment used as a line comment
+        doc.file_info.syntax = crate::syntax::SYNTAXSET.find_syntax_by_name("HTML").unwrap();
+        doc.set_main_selection(Position::new(0, 0), Position::new(0, 0));
+        doc.insert("<!-- x -->  \n");
+        doc.set_main_selection(Position::new(0, 0), Position::new(0, 0));
+        doc.toggle_line_comment();
+        assert_eq!(doc.rope.line(0).to_string(), "x  \n");
+    }
+
+    #[test]
+    fn indentation_rules() {
+        let mut doc = Document::new(crate::Indentation::Space(4));
+        doc.file_info.syntax = crate::syntax::SYNTAXSET.find_syntax_by_name("Python").unwrap();
+        for input in ["if x:", "\n", "y()", "\n", "els", "e", ":"] {
+            doc.insert(input);
+        }
+        assert_eq!(doc.rope.to_string(), "if x:\n    y()\nelse:");
+
+        doc.file_info.syntax = crate::syntax::SYNTAXSET.find_syntax_by_name("Ruby").unwrap();
+        doc.set_main_selection(Position::new(0, 0), Position::new(2, 5));
+        doc.insert("[1].each do |i|\nputs i\n  end");
+        doc.set_main_selection(Position::new(0, 0), Position::new(2, 5));
+        doc.reindent();
+        assert_eq!(doc.rope.to_string(), "[1].each do |i|\n    puts i\nend");
+
+        // only the body of an `if` without braces is indented
+        doc.file_info.syntax = crate::syntax::SYNTAXSET.find_syntax_by_name("C").unwrap();
+        doc.set_main_selection(Position::new(0, 0), Position::new(2, 3));
+        doc.insert("if (x)\nfoo();\n    bar();");
+        doc.set_main_selection(Position::new(0, 0), Position::new(2, 7));
+        doc.reindent();
+        assert_eq!(doc.rope.to_string(), "if (x)\n    foo();\nbar();");
+    }
+
+    #[test]
+    fn worker_highlights_visible_lines_first() {
+        use super::{BackgroundWorker, BackgroundWorkerMessage};
+        use crate::syntax::{LinesChange, StyledLinesCache, SYNTAXSET};
+        use std::sync::mpsc;
+
+        let (tx, _rx) = mpsc::channel();
+        let mut worker = BackgroundWorker::new(tx);
+        let syntax = SYNTAXSET.find_syntax_by_name("Rust").unwrap();
+        let rope = Rope::from_str(&"let a = 1;\n".repeat(3000));
+        let caches = [StyledLinesCache::new(), StyledLinesCache::new()];
+        for (id, cache) in caches.iter().enumerate() {
+            let (tx, rx) = mpsc::channel();
+            worker.handle_message(BackgroundWorkerMessage::UpdateBuffer(
+                id,
+                syntax.clone(),
+                rope.clone(),
+                LinesChange::from_line(0, &rope),
+                cache.clone(),
+                tx,
+                4,
+            ));
+            rx.recv().unwrap();
+        }
+        worker.handle_message(BackgroundWorkerMessage::VisibleLines(1, 1500..1550));
+
+        // the second document is highlighted up to its visible lines before the first one continues
+        worker.highlight_next_chunk();
+        worker.highlight_next_chunk();
+        assert!(caches[1].get(1549).is_some());
+        assert!(caches[0].get(200).is_none());
+
+        worker.handle_message(BackgroundWorkerMessage::UnregisterDocument(1));
+        while worker.has_pending_work() {
+            worker.highlight_next_chunk();
+        }
+        assert!(caches[0].get(2999).is_some());
+        assert!(caches[1].get(2999).is_none());
+    }
 }
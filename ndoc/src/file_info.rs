@@ -1,7 +1,16 @@
-use std::fmt::Display;
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    fs::{self, File, Metadata},
+    hash::{DefaultHasher, Hasher},
+    io::{self, Read, Write},
+    path::Path,
+    time::SystemTime,
+};
 
-use encoding_rs::Encoding;
-use ropey::RopeSlice;
+use chardetng::EncodingDetector;
+use encoding_rs::{DecoderResult, EncoderResult, Encoding};
+use ropey::{Rope, RopeBuilder, RopeSlice};
 use serde::{Deserialize, Serialize};
 use syntect::parsing::SyntaxReference;
 
@@ -12,8 +21,12 @@ pub struct FileInfo {
     pub encoding: &'static Encoding,
     pub bom: Option<Vec<u8>>,
     pub linefeed: LineFeed,
+    /// The file used more than one kind of line feed when it was loaded
+    pub mixed_linefeeds: bool,
     pub indentation: Indentation,
     pub syntax: &'static SyntaxReference,
+    /// Byte sequences of the file that are invalid in its encoding
+    pub decode_errors: Vec<DecodeError>,
 }
 
 impl PartialEq for FileInfo {
@@ -21,8 +34,10 @@ impl PartialEq for FileInfo {
         self.encoding == other.encoding
             && self.bom == other.bom
             && self.linefeed == other.linefeed
+            && self.mixed_linefeeds == other.mixed_linefeeds
             && self.indentation == other.indentation
             && self.syntax.name == other.syntax.name
+            && self.decode_errors == other.decode_errors
     }
 }
 
@@ -32,12 +47,24 @@ impl Default for FileInfo {
             encoding: encoding_rs::UTF_8,
             bom: None,
             linefeed: Default::default(),
+            mixed_linefeeds: false,
             indentation: Indentation::Space(4),
             syntax: SYNTAXSET.find_syntax_plain_text(),
+            decode_errors: Vec::new(),
         }
     }
 }
 
+/// A byte sequence of a file that is invalid in its encoding, decoded as U+FFFD
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    /// Offset of the sequence in the file
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+    /// Char index of the replacement char in the decoded text
+    pub char_idx: usize,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LineFeed {
     CR,
@@ -54,6 +81,61 @@ impl Default for LineFeed {
     }
 }
 
+impl LineFeed {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LineFeed::LF => "LF",
+            LineFeed::CRLF => "CRLF",
+            LineFeed::CR => "CR",
+        }
+    }
+}
+
+/// Number of line feeds of each kind in a text
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LineFeedCount {
+    pub lf: usize,
+    pub crlf: usize,
+    pub cr: usize,
+}
+
+impl LineFeedCount {
+    pub fn new(input: &RopeSlice) -> Self {
+        let mut count = Self::default();
+        let mut bytes = input.bytes().peekable();
+        while let Some(b) = bytes.next() {
+            match b {
+                b'\r' if bytes.peek() == Some(&b'\n') => {
+                    bytes.next();
+                    count.crlf += 1;
+                }
+                b'\r' => count.cr += 1,
+                b'\n' => count.lf += 1,
+                _ => (),
+            }
+        }
+        count
+    }
+
+    /// More than one kind of line feed is used
+    pub fn is_mixed(&self) -> bool {
+        [self.lf, self.crlf, self.cr].iter().filter(|c| **c > 0).count() > 1
+    }
+
+    /// The most used kind of line feed, CRLF on a tie. None if there is no line feed at all
+    pub fn most_used(&self) -> Option<LineFeed> {
+        if self.lf + self.crlf + self.cr == 0 {
+            None
+        } else if self.cr > self.crlf && self.cr > self.lf {
+            Some(LineFeed::CR)
+        } else if self.lf > self.crlf && self.lf > self.cr {
+            Some(LineFeed::LF)
+        } else {
+            Some(LineFeed::CRLF)
+        }
+    }
+}
+
 impl Display for LineFeed {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -95,40 +177,398 @@ impl Display for Indentation {
     }
 }
 
-/// Detect the carriage return type of the buffer
-pub fn detect_linefeed(input: &RopeSlice) -> LineFeed {
-    let linefeed = Default::default();
+/// State of a file on disk when it was last loaded or saved, used to detect modifications made outside the editor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FileStamp {
+    modified: Option<SystemTime>,
+    len: u64,
+    hash: u64,
+}
+
+impl FileStamp {
+    pub fn new(metadata: &Metadata, content: &[u8]) -> Self {
+        Self::with_hash(metadata, hash_content(content))
+    }
+
+    /// The hash of the content must be computed like [hash_content] does
+    pub fn with_hash(metadata: &Metadata, hash: u64) -> Self {
+        Self {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+            hash,
+        }
+    }
+
+    /// The file has the same modification time and size, its content is assumed to be the same
+    pub fn same_metadata(&self, metadata: &Metadata) -> bool {
+        self.modified == metadata.modified().ok() && self.len == metadata.len()
+    }
+
+    pub fn same_content(&self, content: &[u8]) -> bool {
+        self.len == content.len() as u64 && self.hash == hash_content(content)
+    }
+}
+
+// the content is fed with `write` so it can also be hashed by chunks
+fn hash_content(content: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(content);
+    hasher.finish()
+}
 
-    if input.len_bytes() == 0 {
-        return linefeed;
+/// Decode the raw content of a file. The encoding is given by the BOM if there is one, otherwise it is guessed from the content.
+/// Return the decoded text, the encoding and the BOM
+pub(crate) fn decode(bytes: &[u8]) -> (Cow<'_, str>, &'static Encoding, Option<Vec<u8>>) {
+    match Encoding::for_bom(bytes) {
+        Some((encoding, bom_size)) => {
+            let bom = bytes[0..bom_size].to_vec();
+            (encoding.decode_with_bom_removal(bytes).0, encoding, Some(bom))
+        }
+        None => {
+            let mut detector = EncodingDetector::new();
+            detector.feed(bytes, true);
+            let encoding = detector.guess(None, true);
+            (encoding.decode_with_bom_removal(bytes).0, encoding, None)
+        }
     }
+}
+
+/// All the encodings a file can be decoded from or encoded to.
+/// The `replacement` encoding is left out, it decodes anything to a single replacement char
+pub const ENCODINGS: &[&Encoding] = &[
+    encoding_rs::UTF_8,
+    encoding_rs::UTF_16LE,
+    encoding_rs::UTF_16BE,
+    encoding_rs::IBM866,
+    encoding_rs::ISO_8859_2,
+    encoding_rs::ISO_8859_3,
+    encoding_rs::ISO_8859_4,
+    encoding_rs::ISO_8859_5,
+    encoding_rs::ISO_8859_6,
+    encoding_rs::ISO_8859_7,
+    encoding_rs::ISO_8859_8,
+    encoding_rs::ISO_8859_8_I,
+    encoding_rs::ISO_8859_10,
+    encoding_rs::ISO_8859_13,
+    encoding_rs::ISO_8859_14,
+    encoding_rs::ISO_8859_15,
+    encoding_rs::ISO_8859_16,
+    encoding_rs::KOI8_R,
+    encoding_rs::KOI8_U,
+    encoding_rs::MACINTOSH,
+    encoding_rs::WINDOWS_874,
+    encoding_rs::WINDOWS_1250,
+    encoding_rs::WINDOWS_1251,
+    encoding_rs::WINDOWS_1252,
+    encoding_rs::WINDOWS_1253,
+    encoding_rs::WINDOWS_1254,
+    encoding_rs::WINDOWS_1255,
+    encoding_rs::WINDOWS_1256,
+    encoding_rs::WINDOWS_1257,
+    encoding_rs::WINDOWS_1258,
+    encoding_rs::X_MAC_CYRILLIC,
+    encoding_rs::GBK,
+    encoding_rs::GB18030,
+    encoding_rs::BIG5,
+    encoding_rs::EUC_JP,
+    encoding_rs::ISO_2022_JP,
+    encoding_rs::SHIFT_JIS,
+    encoding_rs::EUC_KR,
+    encoding_rs::X_USER_DEFINED,
+];
+
+/// A file decoded by [decode_by_chunks]
+pub(crate) struct DecodedFile {
+    pub rope: Rope,
+    pub encoding: &'static Encoding,
+    pub bom: Option<Vec<u8>>,
+    pub decode_errors: Vec<DecodeError>,
+    /// Hash of the raw content, for the [FileStamp]
+    pub hash: u64,
+}
+
+/// Decode a file chunk by chunk straight into a rope, without holding the whole raw content and text in memory.
+/// The encoding is detected from the first chunk. `on_progress` is called after each chunk with the fraction of `len` read
+pub(crate) fn decode_by_chunks(
+    mut reader: impl Read,
+    len: u64,
+    mut on_progress: impl FnMut(f32),
+) -> io::Result<DecodedFile> {
+    const CHUNK_LEN: usize = 1 << 20;
+    let mut chunk = vec![0; CHUNK_LEN];
+    let mut filled = read_chunk(&mut reader, &mut chunk)?;
 
-    let mut cr = 0;
-    let mut lf = 0;
-    let mut crlf = 0;
+    let (encoding, bom_len) = Encoding::for_bom(&chunk[..filled]).unwrap_or_else(|| {
+        let mut detector = EncodingDetector::new();
+        detector.feed(&chunk[..filled], filled < CHUNK_LEN);
+        (detector.guess(None, true), 0)
+    });
+    let bom = (bom_len > 0).then(|| chunk[..bom_len].to_vec());
 
-    let mut chars = input.chars().take(1000);
-    while let Some(c) = chars.next() {
-        if c == '\r' {
-            if let Some(c2) = chars.next() {
-                if c2 == '\n' {
-                    crlf += 1;
-                } else {
-                    cr += 1;
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut builder = RopeBuilder::new();
+    let mut hasher = DefaultHasher::new();
+    let mut buffer = "\0".repeat(CHUNK_LEN / 4);
+    let mut decode_errors = Vec::new();
+    let mut char_idx = 0;
+    // offset in the file of the start of the chunk, and the end of the previous chunk,
+    // as an invalid sequence can start in the previous chunk
+    let mut chunk_offset = 0;
+    let mut previous_tail = Vec::new();
+    let mut start = bom_len;
+    loop {
+        hasher.write(&chunk[..filled]);
+        let last = filled < CHUNK_LEN;
+        loop {
+            let (result, read, written) =
+                decoder.decode_to_str_without_replacement(&chunk[start..filled], &mut buffer, last);
+            start += read;
+            builder.append(&buffer[..written]);
+            char_idx += buffer[..written].chars().count();
+            match result {
+                DecoderResult::InputEmpty => break,
+                DecoderResult::OutputFull => (),
+                DecoderResult::Malformed(len, extra) => {
+                    let end = chunk_offset + start - extra as usize;
+                    let offset = end - len as usize;
+                    let bytes = previous_tail
+                        .iter()
+                        .chain(chunk[..filled].iter())
+                        .skip(offset + previous_tail.len() - chunk_offset)
+                        .take(len as usize)
+                        .copied()
+                        .collect();
+                    decode_errors.push(DecodeError {
+                        offset,
+                        bytes,
+                        char_idx,
+                    });
+                    builder.append("\u{FFFD}");
+                    char_idx += 1;
                 }
             }
-        } else if c == '\n' {
-            lf += 1;
         }
+        chunk_offset += filled;
+        on_progress(if len == 0 { 1. } else { chunk_offset as f32 / len as f32 });
+        if last {
+            break;
+        }
+        previous_tail = chunk[filled.saturating_sub(8)..filled].to_vec();
+        filled = read_chunk(&mut reader, &mut chunk)?;
+        start = 0;
     }
 
-    if cr > crlf && cr > lf {
-        LineFeed::CR
-    } else if lf > crlf && lf > cr {
-        LineFeed::LF
-    } else {
-        LineFeed::CRLF
+    Ok(DecodedFile {
+        rope: builder.finish(),
+        encoding,
+        bom,
+        decode_errors,
+        hash: hasher.finish(),
+    })
+}
+
+/// Fill the chunk, unless the end of the file is reached. Return the number of bytes read
+fn read_chunk(reader: &mut impl Read, chunk: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < chunk.len() {
+        match reader.read(&mut chunk[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Decode the raw content of a file with the given encoding, ignoring detection.
+/// A BOM is only removed if it belongs to this encoding
+pub(crate) fn decode_with<'a>(
+    bytes: &'a [u8],
+    encoding: &'static Encoding,
+) -> (Cow<'a, str>, Option<Vec<u8>>) {
+    match Encoding::for_bom(bytes) {
+        Some((bom_encoding, bom_size)) if bom_encoding == encoding => (
+            encoding.decode_without_bom_handling(&bytes[bom_size..]).0,
+            Some(bytes[..bom_size].to_vec()),
+        ),
+        _ => (encoding.decode_without_bom_handling(bytes).0, None),
+    }
+}
+
+/// Find the invalid byte sequences of a file that was decoded to `text`
+pub(crate) fn decode_errors(
+    bytes: &[u8],
+    text: &str,
+    encoding: &'static Encoding,
+    bom: Option<&[u8]>,
+) -> Vec<DecodeError> {
+    // the decoder replaces each invalid sequence with U+FFFD, no need to decode again without it
+    if !text.contains('\u{FFFD}') {
+        return Vec::new();
+    }
+    let mut decoder = encoding.new_decoder_without_bom_handling();
+    let mut buffer = "\0".repeat(4096);
+    let mut offset = bom.map(|b| b.len()).unwrap_or_default();
+    let mut char_idx = 0;
+    let mut errors = Vec::new();
+    loop {
+        let (result, read, written) =
+            decoder.decode_to_str_without_replacement(&bytes[offset..], &mut buffer, true);
+        offset += read;
+        char_idx += buffer[..written].chars().count();
+        match result {
+            DecoderResult::InputEmpty => return errors,
+            DecoderResult::OutputFull => (),
+            DecoderResult::Malformed(len, extra) => {
+                let start = offset - extra as usize - len as usize;
+                errors.push(DecodeError {
+                    offset: start,
+                    bytes: bytes[start..start + len as usize].to_vec(),
+                    char_idx,
+                });
+                char_idx += 1;
+            }
+        }
+    }
+}
+
+/// The BOM of the encoding, if it has one
+pub(crate) fn bom_for(encoding: &'static Encoding) -> Option<Vec<u8>> {
+    match encoding.name() {
+        "UTF-8" => Some(vec![0xEF, 0xBB, 0xBF]),
+        "UTF-16LE" => Some(vec![0xFF, 0xFE]),
+        "UTF-16BE" => Some(vec![0xFE, 0xFF]),
+        _ => None,
+    }
+}
+
+/// The chars of the text that can't be represented in the encoding, with their byte offset.
+/// [encode] would replace them with a numeric character reference
+pub(crate) fn unencodable_chars(input: &str, encoding: &'static Encoding) -> Vec<(usize, char)> {
+    // UTF-8 and UTF-16 (whose output encoding is UTF-8) can encode anything
+    if encoding.output_encoding() != encoding {
+        return Vec::new();
     }
+    let mut encoder = encoding.new_encoder();
+    let mut buffer = [0u8; 4096];
+    let mut offset = 0;
+    let mut chars = Vec::new();
+    loop {
+        let (result, read, _) =
+            encoder.encode_from_utf8_without_replacement(&input[offset..], &mut buffer, true);
+        offset += read;
+        match result {
+            EncoderResult::InputEmpty => return chars,
+            EncoderResult::OutputFull => (),
+            EncoderResult::Unmappable(c) => chars.push((offset - c.len_utf8(), c)),
+        }
+    }
+}
+
+/// Encode the rope after the BOM, writing back the raw bytes of the invalid sequences in place of their replacement chars.
+/// `errors` are the char indices of the replacement chars with their sequence, in document order.
+/// Return the content with the decode errors it contains
+pub(crate) fn encode_with_invalid_bytes(
+    rope: &Rope,
+    encoding: &'static Encoding,
+    bom: Option<&[u8]>,
+    errors: &[(usize, &DecodeError)],
+) -> (Vec<u8>, Vec<DecodeError>) {
+    let mut output = bom.map(|b| b.to_vec()).unwrap_or_default();
+    let mut decode_errors = Vec::new();
+    let mut written_chars = 0;
+    for (char_idx, error) in errors {
+        let input = rope.slice(written_chars..*char_idx).to_string();
+        output.extend_from_slice(&encode(&input, encoding));
+        decode_errors.push(DecodeError {
+            offset: output.len(),
+            bytes: error.bytes.clone(),
+            char_idx: *char_idx,
+        });
+        output.extend_from_slice(&error.bytes);
+        written_chars = char_idx + 1;
+    }
+    let input = rope.slice(written_chars..).to_string();
+    output.extend_from_slice(&encode(&input, encoding));
+    (output, decode_errors)
+}
+
+/// Replace the file with the content, going through a synced temporary file so a crash never leaves it truncated.
+/// The permissions of the file are kept and it is copied to `<path>.bak` first if `backup` is set
+pub(crate) fn write_atomically(path: &Path, content: &[u8], backup: bool) -> io::Result<File> {
+    let target = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let folder = match target.parent() {
+        Some(folder) if !folder.as_os_str().is_empty() => folder,
+        _ => Path::new("."),
+    };
+    let mut temp = tempfile::NamedTempFile::new_in(folder)?;
+    temp.write_all(content)?;
+    if let Ok(metadata) = fs::metadata(&target) {
+        temp.as_file().set_permissions(metadata.permissions())?;
+        if backup {
+            let mut backup_path = target.clone().into_os_string();
+            backup_path.push(".bak");
+            fs::copy(&target, backup_path)?;
+        }
+    }
+    temp.as_file().sync_all()?;
+    temp.persist(&target).map_err(|e| e.error)
+}
+
+/// Encode the text with the given encoding.
+/// UTF-16 is handled here because encoding_rs only decode it
+pub(crate) fn encode<'a>(input: &'a str, encoding: &'static Encoding) -> Cow<'a, [u8]> {
+    match encoding.name() {
+        "UTF-16LE" => {
+            let mut v = Vec::new();
+            input
+                .encode_utf16()
+                .for_each(|i| v.extend_from_slice(&i.to_le_bytes()));
+            Cow::from(v)
+        }
+        "UTF-16BE" => {
+            let mut v = Vec::new();
+            input
+                .encode_utf16()
+                .for_each(|i| v.extend_from_slice(&i.to_be_bytes()));
+            Cow::from(v)
+        }
+        _ => encoding.encode(input).0,
+    }
+}
+
+/// Guess if the content is binary from its first 8000 bytes: a NUL byte, like git does,
+/// or more than 10% of control chars that are not found in text files.
+/// UTF-16 content is text when it starts with a BOM, despite its NUL bytes
+pub(crate) fn is_binary(bytes: &[u8]) -> bool {
+    if bytes.starts_with(&[0xFF, 0xFE]) || bytes.starts_with(&[0xFE, 0xFF]) {
+        return false;
+    }
+    let head = &bytes[..bytes.len().min(8000)];
+    let controls = head
+        .iter()
+        .filter(|b| matches!(b, 0x01..=0x08 | 0x0E..=0x1A | 0x1C..=0x1F | 0x7F))
+        .count();
+    head.contains(&0) || controls * 10 > head.len()
+}
+
+/// Guess if decoded content is binary: more than 10% of its first 8000 chars are invalid sequences
+/// or control chars, which happens when the bytes are decoded with a single-byte encoding.
+/// Short texts are never binary, a few invalid bytes would weigh too much in them
+pub(crate) fn is_binary_text(text: &str) -> bool {
+    const MIN_SAMPLE: usize = 512;
+    let (count, garbage) = text.chars().take(8000).fold((0, 0), |(count, garbage), c| {
+        let is_garbage =
+            c == char::REPLACEMENT_CHARACTER || (c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0B' | '\x0C' | '\x1B'));
+        (count + 1, garbage + is_garbage as usize)
+    });
+    count >= MIN_SAMPLE && garbage * 10 > count
+}
+
+/// Detect the carriage return type of the buffer, the most used one wins
+pub fn detect_linefeed(input: &RopeSlice) -> LineFeed {
+    LineFeedCount::new(input).most_used().unwrap_or_default()
 }
 
 pub fn detect_indentation(input: &RopeSlice) -> Indentation {
@@ -0,0 +1,164 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use ignore::WalkBuilder;
+use ropey::Rope;
+
+use crate::{
+    document::{char_to_position, Position},
+    file_info::{decode, is_binary},
+    search::Searcher,
+};
+
+// The content of the matched line is truncated to this number of chars
+const MAX_LINE_LEN: usize = 256;
+
+/// A match found in a file by [FindInFiles]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileMatch {
+    pub path: PathBuf,
+    pub start: Position,
+    pub end: Position,
+    /// The content of the line where the match starts, without its line feed
+    pub line: String,
+}
+
+/// Events sent by a [FindInFiles] search
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FindInFilesEvent {
+    /// All the matches of a single file
+    Matches(Vec<FileMatch>),
+    /// The whole tree has been searched. Not sent if the search was cancelled
+    Done,
+}
+
+/// A search running in a background thread over all the files of a directory tree.
+/// `.gitignore` files are honored, hidden and binary files are skipped.
+/// The search is cancelled when the handle is dropped.
+#[derive(Debug)]
+pub struct FindInFiles {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl FindInFiles {
+    /// Start searching the directory tree. `on_event` is called from the background thread each time a file with matches is found
+    pub fn start(
+        root: impl Into<PathBuf>,
+        searcher: Searcher,
+        on_event: impl Fn(FindInFilesEvent) + Send + 'static,
+    ) -> Self {
+        let root = root.into();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let c = cancelled.clone();
+        thread::spawn(move || {
+            let walker = WalkBuilder::new(&root).require_git(false).build();
+            for entry in walker.flatten() {
+                if c.load(Ordering::Relaxed) {
+                    return;
+                }
+                if !entry.file_type().is_some_and(|t| t.is_file()) {
+                    continue;
+                }
+                let matches = search_file(entry.path(), &searcher, &c);
+                if !matches.is_empty() && !c.load(Ordering::Relaxed) {
+                    on_event(FindInFilesEvent::Matches(matches));
+                }
+            }
+            if !c.load(Ordering::Relaxed) {
+                on_event(FindInFilesEvent::Done);
+            }
+        });
+        Self { cancelled }
+    }
+
+    /// Stop the search, no more event will be sent
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for FindInFiles {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+fn search_file(path: &Path, searcher: &Searcher, cancelled: &AtomicBool) -> Vec<FileMatch> {
+    let Ok(bytes) = fs::read(path) else {
+        return Vec::new();
+    };
+    if is_binary(&bytes) {
+        return Vec::new();
+    }
+    let rope = Rope::from_str(&decode(&bytes).0);
+    let slice = rope.slice(..);
+    let mut matches = Vec::new();
+    for range in searcher.find_iter(&rope, 0) {
+        if cancelled.load(Ordering::Relaxed) {
+            break;
+        }
+        let start = char_to_position(&slice, range.start);
+        let line = rope
+            .line(start.line)
+            .chars()
+            .take_while(|c| *c != '\n' && *c != '\r')
+            .take(MAX_LINE_LEN)
+            .collect();
+        matches.push(FileMatch {
+            path: path.to_path_buf(),
+            start,
+            end: char_to_position(&slice, range.end),
+            line,
+        });
+    }
+    matches
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, sync::mpsc, time::Duration};
+
+    use super::{FindInFiles, FindInFilesEvent};
+    use crate::{Position, SearchOptions, Searcher};
+
+    #[test]
+    fn find_in_tree() {
+        let root = std::env::temp_dir().join(format!("ndoc_find_in_files_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join(".gitignore"), "ignored.txt\n").unwrap();
+        fs::write(root.join("a.txt"), "hello\nsay hello hello\n").unwrap();
+        fs::write(root.join("sub/b.txt"), "nothing here\n").unwrap();
+        fs::write(root.join("ignored.txt"), "hello\n").unwrap();
+        fs::write(root.join("binary.bin"), b"hello\0world").unwrap();
+
+        let searcher = Searcher::new("hello", SearchOptions::default()).unwrap();
+        let (tx, rx) = mpsc::channel();
+        let _search = FindInFiles::start(&root, searcher, move |e| tx.send(e).unwrap());
+
+        let mut matches = Vec::new();
+        while let FindInFilesEvent::Matches(m) = rx.recv_timeout(Duration::from_secs(10)).unwrap() {
+            matches.extend(m);
+        }
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(matches.iter().all(|m| m.path == root.join("a.txt")));
+        assert_eq!(
+            matches.iter().map(|m| m.start).collect::<Vec<_>>(),
+            [Position::new(0, 0), Position::new(1, 4), Position::new(1, 10)]
+        );
+        assert_eq!(matches[1].end, Position::new(1, 9));
+        assert_eq!(matches[1].line, "say hello hello");
+    }
+}
@@ -0,0 +1,340 @@
+use std::{collections::BTreeMap, ops::Range};
+
+use ropey::{Rope, RopeSlice};
+use syntect::parsing::{Scope, SyntaxReference};
+
+use crate::{
+    brackets::closing_bracket,
+    syntax::{CodeBrackets, LinesChange},
+};
+
+/// Code syntaxes fold on brackets and indentation, the others (plain text, markup...) only on indentation
+fn code_syntax(syntax: &SyntaxReference) -> Option<&SyntaxReference> {
+    Scope::new("source")
+        .is_ok_and(|s| s.is_prefix_of(syntax.scope))
+        .then_some(syntax)
+}
+
+/// Follow the nesting of the brackets, return true when `c` closes the last open one
+fn push_bracket(stack: &mut Vec<char>, c: char) -> bool {
+    if let Some(closing) = closing_bracket(c) {
+        stack.push(closing);
+        false
+    } else if stack.last() == Some(&c) {
+        stack.pop();
+        stack.is_empty()
+    } else {
+        // unbalanced closing bracket
+        false
+    }
+}
+
+/// The first bracket of the line that is not closed on the same line
+fn unclosed_bracket(brackets: &[(usize, char)]) -> Option<(usize, char)> {
+    let mut stack = Vec::new();
+    for &(idx, c) in brackets {
+        if closing_bracket(c).is_some() {
+            stack.push((idx, c));
+        } else if stack.last().and_then(|(_, o)| closing_bracket(*o)) == Some(c) {
+            stack.pop();
+        }
+    }
+    stack.first().copied()
+}
+
+/// Width of the indentation of the line, None for blank lines
+fn indent_width(line: RopeSlice, tab_len: usize) -> Option<usize> {
+    let mut width = 0;
+    for c in line.chars() {
+        match c {
+            ' ' => width += 1,
+            '\t' => width += tab_len - width % tab_len,
+            '\n' | '\r' => return None,
+            _ => return Some(width),
+        }
+    }
+    None
+}
+
+/// The lines following `line` that are more indented
+fn indentation_range(rope: &Rope, tab_len: usize, line: usize) -> Option<Range<usize>> {
+    let indent = indent_width(rope.line(line), tab_len)?;
+    let mut end = line + 1;
+    for i in line + 1..rope.len_lines() {
+        match indent_width(rope.line(i), tab_len) {
+            None => continue,
+            Some(w) if w > indent => end = i + 1,
+            Some(_) => break,
+        }
+    }
+    (end > line + 1).then_some(line + 1..end)
+}
+
+/// The lines between a bracket left open by `line` and the line closing it
+fn bracket_range(rope: &Rope, syntax: &SyntaxReference, line: usize) -> Option<Range<usize>> {
+    let mut lines = CodeBrackets::new(rope, Some(syntax), line);
+    let (_, brackets) = lines.next()?;
+    let (open_idx, open) = unclosed_bracket(&brackets)?;
+    let mut stack = Vec::new();
+    push_bracket(&mut stack, open);
+    let mut following = brackets.into_iter().filter(|(idx, _)| *idx > open_idx);
+    if following.any(|(_, c)| push_bracket(&mut stack, c)) {
+        return None;
+    }
+    for (i, brackets) in lines {
+        if brackets.into_iter().any(|(_, c)| push_bracket(&mut stack, c)) {
+            return (i > line + 1).then_some(line + 1..i);
+        }
+    }
+    None
+}
+
+/// The lines hidden by folding at `line`: the block opened by a bracket for code, the more indented lines otherwise
+pub(crate) fn fold_range(
+    rope: &Rope,
+    syntax: &SyntaxReference,
+    tab_len: usize,
+    line: usize,
+) -> Option<Range<usize>> {
+    if line >= rope.len_lines() {
+        return None;
+    }
+    code_syntax(syntax)
+        .and_then(|s| bracket_range(rope, s, line))
+        .or_else(|| indentation_range(rope, tab_len, line))
+}
+
+/// A fold can start at the line. Only the line and the next ones are checked, so it's cheap enough for every drawn line
+pub(crate) fn is_foldable(rope: &Rope, syntax: &SyntaxReference, tab_len: usize, line: usize) -> bool {
+    if line >= rope.len_lines() {
+        return false;
+    }
+    if let Some(syntax) = code_syntax(syntax) {
+        let brackets = CodeBrackets::new(rope, Some(syntax), line).next();
+        if brackets.is_some_and(|(_, b)| unclosed_bracket(&b).is_some()) {
+            return true;
+        }
+    }
+    let Some(indent) = indent_width(rope.line(line), tab_len) else {
+        return false;
+    };
+    (line + 1..rope.len_lines())
+        .find_map(|i| indent_width(rope.line(i), tab_len))
+        .is_some_and(|w| w > indent)
+}
+
+/// Every fold of the text, in a single pass
+pub(crate) fn fold_ranges(rope: &Rope, syntax: &SyntaxReference, tab_len: usize) -> Vec<Range<usize>> {
+    let mut ranges = BTreeMap::new();
+
+    if let Some(syntax) = code_syntax(syntax) {
+        let mut stack: Vec<(usize, char)> = Vec::new();
+        for (line, brackets) in CodeBrackets::new(rope, Some(syntax), 0) {
+            for (_, c) in brackets {
+                if let Some(closing) = closing_bracket(c) {
+                    stack.push((line, closing));
+                } else if stack.last().is_some_and(|(_, closing)| *closing == c) {
+                    let (start, _) = stack.pop().unwrap();
+                    if line > start + 1 {
+                        // the biggest block of the line
+                        let end = ranges.entry(start).or_insert(start + 1..line);
+                        end.end = end.end.max(line);
+                    }
+                }
+            }
+        }
+    }
+
+    // lines still open when a line with less or the same indentation is found
+    let mut stack: Vec<(usize, usize)> = Vec::new();
+    let mut last_line = 0;
+    for line in 0..rope.len_lines() {
+        let Some(indent) = indent_width(rope.line(line), tab_len) else {
+            continue;
+        };
+        while let Some(&(start_indent, start)) = stack.last() {
+            if start_indent < indent {
+                break;
+            }
+            stack.pop();
+            if last_line > start {
+                ranges.entry(start).or_insert(start + 1..last_line + 1);
+            }
+        }
+        stack.push((indent, line));
+        last_line = line;
+    }
+    for (_, start) in stack {
+        if last_line > start {
+            ranges.entry(start).or_insert(start + 1..last_line + 1);
+        }
+    }
+
+    ranges.into_values().collect()
+}
+
+/// The folded regions of a document, as ranges of hidden lines. The line before a range is its header, it stays visible.
+/// Folds can be nested
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Folds {
+    // sorted by start
+    ranges: Vec<Range<usize>>,
+}
+
+impl Folds {
+    pub fn add(&mut self, range: Range<usize>) {
+        if range.is_empty() || self.ranges.contains(&range) {
+            return;
+        }
+        let idx = self
+            .ranges
+            .partition_point(|r| (r.start, r.end) < (range.start, range.end));
+        self.ranges.insert(idx, range);
+    }
+
+    /// Unfold the fold whose header is `line`
+    pub fn remove(&mut self, line: usize) -> bool {
+        let len = self.ranges.len();
+        self.ranges.retain(|r| r.start != line + 1);
+        len != self.ranges.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.ranges.clear();
+    }
+
+    /// The line is the header of a fold
+    pub fn is_folded(&self, line: usize) -> bool {
+        self.ranges.iter().any(|r| r.start == line + 1)
+    }
+
+    /// Unfold every fold hiding the line
+    pub fn reveal(&mut self, line: usize) -> bool {
+        let len = self.ranges.len();
+        self.ranges.retain(|r| !r.contains(&line));
+        len != self.ranges.len()
+    }
+
+    /// The hidden lines, nested folds merged
+    pub fn hidden_ranges(&self) -> Vec<Range<usize>> {
+        let mut hidden: Vec<Range<usize>> = Vec::with_capacity(self.ranges.len());
+        for r in &self.ranges {
+            match hidden.last_mut() {
+                Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+                _ => hidden.push(r.clone()),
+            }
+        }
+        hidden
+    }
+
+    /// The hidden lines around `line`, None if it's visible
+    pub fn hidden_range(&self, line: usize) -> Option<Range<usize>> {
+        self.hidden_ranges().into_iter().find(|r| r.contains(&line))
+    }
+
+    /// The screen row of the line. Hidden lines are on the row of their header
+    pub fn line_to_row(&self, line: usize) -> usize {
+        let mut row = line;
+        for r in self.hidden_ranges() {
+            if r.end <= line {
+                row -= r.len();
+            } else if r.start <= line {
+                row -= line - r.start + 1;
+            }
+        }
+        row
+    }
+
+    /// The line shown on the screen row
+    pub fn row_to_line(&self, row: usize) -> usize {
+        let mut line = row;
+        for r in self.hidden_ranges() {
+            if r.start <= line {
+                line += r.len();
+            } else {
+                break;
+            }
+        }
+        line
+    }
+
+    /// The number of rows needed to show `len_lines` lines
+    pub fn row_count(&self, len_lines: usize) -> usize {
+        len_lines - self.hidden_ranges().iter().map(|r| r.len()).sum::<usize>()
+    }
+
+    /// Follow an edit of the text: the folds after it are moved, the ones whose hidden lines are modified are removed
+    pub fn apply_change(&mut self, change: LinesChange) {
+        let old_end = (change.end as isize - change.delta) as usize;
+        self.ranges = std::mem::take(&mut self.ranges)
+            .into_iter()
+            .filter_map(|r| {
+                if r.end <= change.start {
+                    Some(r)
+                } else if old_end <= r.start {
+                    let start = (r.start as isize + change.delta) as usize;
+                    let end = (r.end as isize + change.delta) as usize;
+                    Some(start..end)
+                } else {
+                    None
+                }
+            })
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ropey::Rope;
+
+    use super::{fold_range, fold_ranges, is_foldable, Folds};
+    use crate::syntax::{LinesChange, SYNTAXSET};
+
+    #[test]
+    fn brackets_and_indentation() {
+        let rust = SYNTAXSET.find_syntax_by_name("Rust").unwrap();
+        let rope = Rope::from_str(
+            "fn main() {\n    let s = \"{\";\n    if x {\n        y();\n    }\n}\n// (\nz();\n",
+        );
+        assert_eq!(fold_range(&rope, rust, 4, 0), Some(1..5));
+        assert_eq!(fold_range(&rope, rust, 4, 2), Some(3..4));
+        assert!(!is_foldable(&rope, rust, 4, 1));
+        assert!(!is_foldable(&rope, rust, 4, 6));
+        assert_eq!(fold_ranges(&rope, rust, 4), vec![1..5, 3..4]);
+
+        let text = SYNTAXSET.find_syntax_plain_text();
+        let rope = Rope::from_str("a\n  b\n\n  c\n    d\ne\n");
+        assert!(is_foldable(&rope, text, 4, 0));
+        assert_eq!(fold_range(&rope, text, 4, 0), Some(1..5));
+        assert_eq!(fold_ranges(&rope, text, 4), vec![1..5, 4..5]);
+    }
+
+    #[test]
+    fn rows() {
+        let mut folds = Folds::default();
+        folds.add(2..5);
+        folds.add(3..4);
+        folds.add(8..10);
+        assert_eq!(folds.row_count(12), 7);
+        assert_eq!(folds.row_to_line(1), 1);
+        assert_eq!(folds.row_to_line(2), 5);
+        assert_eq!(folds.row_to_line(5), 10);
+        assert_eq!(folds.line_to_row(5), 2);
+        assert_eq!(folds.line_to_row(3), 1);
+        assert_eq!(folds.line_to_row(10), 5);
+
+        // a line inserted before the folds moves them, an edit of hidden lines unfolds
+        folds.apply_change(LinesChange {
+            start: 0,
+            end: 2,
+            delta: 1,
+        });
+        assert!(folds.is_folded(2) && folds.is_folded(8));
+        folds.apply_change(LinesChange {
+            start: 10,
+            end: 11,
+            delta: 0,
+        });
+        assert!(folds.is_folded(2) && !folds.is_folded(8));
+    }
+}
@@ -0,0 +1,591 @@
+use std::time::{Duration, Instant};
+
+use ropey::Rope;
+
+use crate::document::Selection;
+
+// beyond these, the oldest revisions outside of the path to the current one are dropped
+const MAX_REVISIONS: usize = 10_000;
+const MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// A single change of the text. `char_idx` is expressed in the rope as it was before the change.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Edit {
+    pub char_idx: usize,
+    pub removed: String,
+    pub inserted: String,
+}
+
+/// Where an edit was applied: `removed` chars at `char_idx` were replaced by `inserted` chars
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Change {
+    pub char_idx: usize,
+    pub removed: usize,
+    pub inserted: usize,
+}
+
+impl Edit {
+    pub fn change(&self) -> Change {
+        Change {
+            char_idx: self.char_idx,
+            removed: self.removed.chars().count(),
+            inserted: self.inserted.chars().count(),
+        }
+    }
+
+    fn apply(&self, rope: &mut Rope) -> Change {
+        let change = self.change();
+        rope.remove(self.char_idx..self.char_idx + change.removed);
+        rope.insert(self.char_idx, &self.inserted);
+        change
+    }
+
+    fn revert(&self, rope: &mut Rope) -> Change {
+        let change = self.change();
+        rope.remove(self.char_idx..self.char_idx + change.inserted);
+        rope.insert(self.char_idx, &self.removed);
+        Change {
+            char_idx: self.char_idx,
+            removed: change.inserted,
+            inserted: change.removed,
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.removed.len() + self.inserted.len()
+    }
+}
+
+/// A node of the undo tree. The root (index 0) is the state of the document when it was loaded.
+#[derive(Debug, Clone)]
+struct Revision {
+    parent: usize,
+    /// The child followed by redo, the most recently created or visited one
+    last_child: Option<usize>,
+    edits: Vec<Edit>,
+    selections_before: Vec<Selection>,
+    selections_after: Vec<Selection>,
+    timestamp: Instant,
+}
+
+impl Revision {
+    fn root() -> Self {
+        Self {
+            parent: 0,
+            last_child: None,
+            edits: Vec::new(),
+            selections_before: Vec::new(),
+            selections_after: Vec::new(),
+            timestamp: Instant::now(),
+        }
+    }
+}
+
+/// Undo tree storing the edits made to a document.
+/// Revisions are stored in creation order, so their index is also a chronological order.
+#[derive(Debug, Clone)]
+pub(crate) struct History {
+    revisions: Vec<Revision>,
+    current: usize,
+    saved: usize,
+    last_action: Action,
+    // set after undo/redo so the next edit is never merged into an existing revision
+    sealed: bool,
+    // changes applied to the rope by the last undo/redo
+    changes: Vec<Change>,
+    // total size of the text stored in the edits
+    size: usize,
+    max_revisions: usize,
+    max_bytes: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            revisions: vec![Revision::root()],
+            current: 0,
+            saved: 0,
+            last_action: Action::None,
+            sealed: false,
+            changes: Vec::new(),
+            size: 0,
+            max_revisions: MAX_REVISIONS,
+            max_bytes: MAX_BYTES,
+        }
+    }
+}
+
+impl History {
+    /// The document differs from the last saved (or loaded) state
+    pub fn is_dirty(&self) -> bool {
+        self.current != self.saved
+    }
+
+    /// Remember the current revision as the one written on disk
+    pub fn mark_saved(&mut self) {
+        self.saved = self.current;
+        self.sealed = true;
+    }
+
+    pub fn push(
+        &mut self,
+        edits: Vec<Edit>,
+        selections_before: Vec<Selection>,
+        selections_after: Vec<Selection>,
+        action: &Action,
+    ) {
+        if edits.is_empty() {
+            return;
+        }
+        self.size += edits.iter().map(Edit::size).sum::<usize>();
+        if self.can_merge() && !self.should_push(action, &self.last_action) {
+            let revision = &mut self.revisions[self.current];
+            revision.edits.extend(edits);
+            revision.selections_after = selections_after;
+            revision.timestamp = Instant::now();
+        } else {
+            let idx = self.revisions.len();
+            self.revisions.push(Revision {
+                parent: self.current,
+                last_child: None,
+                edits,
+                selections_before,
+                selections_after,
+                timestamp: Instant::now(),
+            });
+            self.revisions[self.current].last_child = Some(idx);
+            self.current = idx;
+        }
+        self.sealed = false;
+        self.last_action = action.clone();
+        self.prune();
+    }
+
+    /// Drop the oldest revisions until the limits are respected.
+    /// The branches outside of the path to the current revision go first, with their descendants.
+    /// Then the oldest revisions of the path are dropped, the first one kept becomes the root
+    fn prune(&mut self) {
+        if !self.over_limits(self.revisions.len(), self.size) {
+            return;
+        }
+        let len = self.revisions.len();
+        let mut path = self.ancestors(self.current);
+        path.reverse();
+        let mut on_path = vec![false; len];
+        for idx in path.iter() {
+            on_path[*idx] = true;
+        }
+        let sizes = self
+            .revisions
+            .iter()
+            .map(|r| r.edits.iter().map(Edit::size).sum::<usize>())
+            .collect::<Vec<_>>();
+
+        let mut removed = vec![false; len];
+        let (mut count, mut size) = (len, self.size);
+        for i in 1..len {
+            if removed[self.revisions[i].parent]
+                || (!on_path[i] && self.over_limits(count, size))
+            {
+                removed[i] = true;
+                count -= 1;
+                size -= sizes[i];
+            }
+        }
+
+        let mut kept = vec![true; len];
+        let mut root = 0;
+        while self.over_limits(count, size) && root + 1 < path.len() {
+            root += 1;
+            // the edits of the new root are part of its state, they can't be undone anymore
+            (count, size) = (1, 0);
+            for i in 0..len {
+                kept[i] = i == path[root]
+                    || (i > path[root] && !removed[i] && kept[self.revisions[i].parent]);
+                if kept[i] && i != path[root] {
+                    count += 1;
+                    size += sizes[i];
+                }
+            }
+        }
+        if root == 0 {
+            kept = removed.iter().map(|r| !r).collect();
+        }
+
+        let mut new_idx = vec![usize::MAX; len];
+        let mut revisions = Vec::with_capacity(count);
+        for (i, revision) in std::mem::take(&mut self.revisions).into_iter().enumerate() {
+            if kept[i] {
+                new_idx[i] = revisions.len();
+                revisions.push(revision);
+            }
+        }
+        for r in revisions.iter_mut() {
+            r.parent = new_idx[r.parent];
+            r.last_child = r.last_child.map(|c| new_idx[c]).filter(|c| *c != usize::MAX);
+        }
+        let root = &mut revisions[0];
+        root.parent = 0;
+        root.edits.clear();
+        root.selections_before.clear();
+        // redo follows the most recent remaining branch
+        for i in (1..revisions.len()).rev() {
+            let parent = revisions[i].parent;
+            if revisions[parent].last_child.is_none() {
+                revisions[parent].last_child = Some(i);
+            }
+        }
+        self.revisions = revisions;
+        self.current = new_idx[self.current];
+        // the saved state may not be reachable anymore
+        self.saved = new_idx.get(self.saved).copied().unwrap_or(usize::MAX);
+        self.size = size;
+    }
+
+    fn over_limits(&self, count: usize, size: usize) -> bool {
+        count > self.max_revisions || size > self.max_bytes
+    }
+
+    /// Revert the current revision on the rope and return the selections to restore
+    pub fn undo(&mut self, rope: &mut Rope) -> Option<Vec<Selection>> {
+        if self.current == 0 {
+            return None;
+        }
+        let selections = self.undo_one(rope);
+        self.seal();
+        Some(selections)
+    }
+
+    /// Reapply the last undone revision on the rope and return the selections to restore
+    pub fn redo(&mut self, rope: &mut Rope) -> Option<Vec<Selection>> {
+        let child = self.revisions[self.current].last_child?;
+        let selections = self.redo_one(rope, child);
+        self.seal();
+        Some(selections)
+    }
+
+    /// Go `steps` revisions back in chronological order, possibly switching branch
+    pub fn earlier(&mut self, rope: &mut Rope, steps: usize) -> Option<Vec<Selection>> {
+        self.jump_to(rope, self.current.saturating_sub(steps))
+    }
+
+    /// Go `steps` revisions forward in chronological order, possibly switching branch
+    pub fn later(&mut self, rope: &mut Rope, steps: usize) -> Option<Vec<Selection>> {
+        let target = (self.current + steps).min(self.revisions.len() - 1);
+        self.jump_to(rope, target)
+    }
+
+    /// Go back to the state the document was in `duration` before the current revision
+    pub fn earlier_by(&mut self, rope: &mut Rope, duration: Duration) -> Option<Vec<Selection>> {
+        let time = self.revisions[self.current]
+            .timestamp
+            .checked_sub(duration)?;
+        self.jump_to(rope, self.revision_at(time))
+    }
+
+    /// Go forward to the state the document was in `duration` after the current revision
+    pub fn later_by(&mut self, rope: &mut Rope, duration: Duration) -> Option<Vec<Selection>> {
+        let time = self.revisions[self.current].timestamp + duration;
+        self.jump_to(rope, self.revision_at(time).max(self.current))
+    }
+
+    /// Number of branches reachable by redo from the current revision
+    pub fn redo_branch_count(&self) -> usize {
+        self.children(self.current).count()
+    }
+
+    /// Select the next branch followed by redo, cycling through the children of the current revision
+    pub fn next_redo_branch(&mut self) {
+        let children = self.children(self.current).collect::<Vec<_>>();
+        if children.is_empty() {
+            return;
+        }
+        let next = match self.revisions[self.current].last_child {
+            Some(c) => children
+                .iter()
+                .position(|i| *i == c)
+                .map(|p| children[(p + 1) % children.len()])
+                .unwrap_or(children[0]),
+            None => children[0],
+        };
+        self.revisions[self.current].last_child = Some(next);
+    }
+
+    /// Move to the given revision, undoing up to the common ancestor and redoing down to the target
+    fn jump_to(&mut self, rope: &mut Rope, target: usize) -> Option<Vec<Selection>> {
+        if target == self.current {
+            return None;
+        }
+        let ancestors = self.ancestors(target);
+        let mut selections = Vec::new();
+        while !ancestors.contains(&self.current) {
+            selections = self.undo_one(rope);
+        }
+        let common = self.current;
+        for idx in ancestors
+            .iter()
+            .take_while(|i| **i != common)
+            .copied()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+        {
+            selections = self.redo_one(rope, idx);
+        }
+        self.seal();
+        Some(selections)
+    }
+
+    /// The changes applied to the rope since the last call, in order
+    pub fn take_changes(&mut self) -> Vec<Change> {
+        std::mem::take(&mut self.changes)
+    }
+
+    fn undo_one(&mut self, rope: &mut Rope) -> Vec<Selection> {
+        let revision = &self.revisions[self.current];
+        for edit in revision.edits.iter().rev() {
+            self.changes.push(edit.revert(rope));
+        }
+        let parent = revision.parent;
+        let selections = revision.selections_before.clone();
+        self.revisions[parent].last_child = Some(self.current);
+        self.current = parent;
+        selections
+    }
+
+    fn redo_one(&mut self, rope: &mut Rope, child: usize) -> Vec<Selection> {
+        let revision = &self.revisions[child];
+        for edit in revision.edits.iter() {
+            self.changes.push(edit.apply(rope));
+        }
+        self.revisions[self.current].last_child = Some(child);
+        self.current = child;
+        self.revisions[child].selections_after.clone()
+    }
+
+    /// The given revision followed by all its ancestors up to the root
+    fn ancestors(&self, mut idx: usize) -> Vec<usize> {
+        let mut v = vec![idx];
+        while idx != 0 {
+            idx = self.revisions[idx].parent;
+            v.push(idx);
+        }
+        v
+    }
+
+    fn children(&self, idx: usize) -> impl Iterator<Item = usize> + '_ {
+        self.revisions
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(move |(_, r)| r.parent == idx)
+            .map(|(i, _)| i)
+    }
+
+    /// The last revision created before the given time
+    fn revision_at(&self, time: Instant) -> usize {
+        self.revisions
+            .iter()
+            .rposition(|r| r.timestamp <= time)
+            .unwrap_or(0)
+    }
+
+    fn seal(&mut self) {
+        self.sealed = true;
+        self.last_action = Action::None;
+    }
+
+    fn can_merge(&self) -> bool {
+        !self.sealed
+            && self.current != 0
+            && self.current != self.saved
+            && self.revisions[self.current].last_child.is_none()
+    }
+
+    fn should_push(&self, action: &Action, last_action: &Action) -> bool {
+        match (action, last_action) {
+            (Action::Delete, Action::Delete) => false,
+            (Action::Backspace, Action::Backspace) => false,
+            (Action::Delete, _) => true,
+            (Action::Backspace, _) => true,
+            (Action::Tab, _) => true,
+            (_, Action::Tab) => true,
+            (Action::Replace, _) => true,
+            (_, Action::Replace) => true,
+            (Action::Text(t), _) if t.chars().count() > 1 => true,
+            (Action::Text(t), _) if t.chars().nth(0).is_some_and(|c| !c.is_alphanumeric()) => true,
+            (_, _) => false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub(crate) enum Action {
+    #[default]
+    None,
+    Backspace,
+    Delete,
+    Text(String),
+    Tab,
+    Replace,
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use ropey::Rope;
+
+    use super::{Action, Edit, History};
+
+    fn insert(history: &mut History, rope: &mut Rope, char_idx: usize, text: &str) {
+        let edit = Edit {
+            char_idx,
+            removed: String::new(),
+            inserted: text.to_string(),
+        };
+        edit.apply(rope);
+        history.push(
+            vec![edit],
+            Vec::new(),
+            Vec::new(),
+            &Action::Text(text.to_string()),
+        );
+    }
+
+    #[test]
+    fn undo_redo() {
+        let mut rope = Rope::new();
+        let mut history = History::default();
+        insert(&mut history, &mut rope, 0, "hello");
+        insert(&mut history, &mut rope, 5, " world");
+        history.undo(&mut rope);
+        assert_eq!(rope.to_string(), "hello");
+        history.undo(&mut rope);
+        assert_eq!(rope.to_string(), "");
+        assert!(history.undo(&mut rope).is_none());
+        history.redo(&mut rope);
+        history.redo(&mut rope);
+        assert_eq!(rope.to_string(), "hello world");
+        assert!(history.redo(&mut rope).is_none());
+    }
+
+    #[test]
+    fn branches_are_kept() {
+        let mut rope = Rope::new();
+        let mut history = History::default();
+        insert(&mut history, &mut rope, 0, "hello");
+        insert(&mut history, &mut rope, 5, " world");
+        history.undo(&mut rope);
+        insert(&mut history, &mut rope, 5, " there");
+        assert_eq!(rope.to_string(), "hello there");
+        assert_eq!(history.redo_branch_count(), 0);
+
+        history.undo(&mut rope);
+        assert_eq!(history.redo_branch_count(), 2);
+        history.redo(&mut rope);
+        assert_eq!(rope.to_string(), "hello there");
+        history.undo(&mut rope);
+        history.next_redo_branch();
+        history.redo(&mut rope);
+        assert_eq!(rope.to_string(), "hello world");
+
+        // chronological navigation goes through both branches
+        history.later(&mut rope, 1);
+        assert_eq!(rope.to_string(), "hello there");
+        history.earlier(&mut rope, 1);
+        assert_eq!(rope.to_string(), "hello world");
+        history.earlier(&mut rope, 2);
+        assert_eq!(rope.to_string(), "");
+    }
+
+    #[test]
+    fn earlier_by_time() {
+        let mut rope = Rope::new();
+        let mut history = History::default();
+        insert(&mut history, &mut rope, 0, "a");
+        insert(&mut history, &mut rope, 1, " b");
+        insert(&mut history, &mut rope, 3, " c");
+        let now = history.revisions[3].timestamp;
+        history.revisions[0].timestamp = now - Duration::from_secs(600);
+        history.revisions[1].timestamp = now - Duration::from_secs(400);
+        history.revisions[2].timestamp = now - Duration::from_secs(200);
+
+        history.earlier_by(&mut rope, Duration::from_secs(300));
+        assert_eq!(rope.to_string(), "a");
+        history.later_by(&mut rope, Duration::from_secs(400));
+        assert_eq!(rope.to_string(), "a b c");
+    }
+
+    #[test]
+    fn prune_old_branches() {
+        let mut rope = Rope::new();
+        let mut history = History {
+            max_revisions: 4,
+            ..Default::default()
+        };
+        insert(&mut history, &mut rope, 0, "a");
+        insert(&mut history, &mut rope, 1, " b");
+        history.undo(&mut rope);
+        insert(&mut history, &mut rope, 1, " c");
+        history.undo(&mut rope);
+        history.mark_saved();
+        insert(&mut history, &mut rope, 1, " d");
+        // the oldest branch is dropped, the path to the current revision is kept
+        assert_eq!(history.revisions.len(), 4);
+        assert_eq!(history.redo_branch_count(), 0);
+        history.undo(&mut rope);
+        assert!(!history.is_dirty());
+        assert_eq!(history.redo_branch_count(), 2);
+        history.redo(&mut rope);
+        assert_eq!(rope.to_string(), "a d");
+        history.undo(&mut rope);
+        history.next_redo_branch();
+        history.redo(&mut rope);
+        assert_eq!(rope.to_string(), "a c");
+        history.undo(&mut rope);
+        history.undo(&mut rope);
+        assert_eq!(rope.to_string(), "");
+
+        // over the byte limit, every revision outside of the current path goes
+        history.max_bytes = 3;
+        insert(&mut history, &mut rope, 0, "xyz");
+        assert_eq!(history.revisions.len(), 2);
+        assert_eq!(history.size, 3);
+        // the saved revision was dropped
+        history.undo(&mut rope);
+        assert!(history.is_dirty());
+        assert!(history.undo(&mut rope).is_none());
+    }
+
+    #[test]
+    fn prune_linear_history() {
+        let mut rope = Rope::new();
+        let mut history = History {
+            max_revisions: 3,
+            ..Default::default()
+        };
+        for text in ["a", " b", " c", " d"] {
+            let end = rope.len_chars();
+            insert(&mut history, &mut rope, end, text);
+        }
+        assert_eq!(rope.to_string(), "a b c d");
+        // the oldest revisions are merged into the root
+        assert_eq!(history.revisions.len(), 3);
+        assert_eq!(history.size, 4);
+        history.undo(&mut rope);
+        history.undo(&mut rope);
+        assert_eq!(rope.to_string(), "a b");
+        assert!(history.undo(&mut rope).is_none());
+        history.redo(&mut rope);
+        history.redo(&mut rope);
+        assert_eq!(rope.to_string(), "a b c d");
+
+        history.max_bytes = 2;
+        insert(&mut history, &mut rope, 7, " e");
+        assert_eq!(history.revisions.len(), 2);
+        history.undo(&mut rope);
+        assert_eq!(rope.to_string(), "a b c d");
+        assert!(history.undo(&mut rope).is_none());
+    }
+}
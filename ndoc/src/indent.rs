@@ -0,0 +1,53 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use syntect::parsing::{ScopedMetadata, SyntaxReference};
+
+use crate::syntax::syntax_metadata;
+
+// used by the syntaxes without indentation metadata, like plain text
+static BRACE_INCREASE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"\{[^}"']*$"#).unwrap());
+static BRACE_DECREASE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\s*\}").unwrap());
+
+/// When to change the indentation of a line, from the `increaseIndentPattern`, `decreaseIndentPattern`
+/// and `bracketIndentNextLinePattern` metadata of the syntax.
+/// The syntaxes without these patterns indent after a `{` and dedent a line starting with `}`
+pub(crate) struct IndentRules {
+    metadata: ScopedMetadata<'static>,
+    has_patterns: bool,
+}
+
+impl IndentRules {
+    pub fn new(syntax: &SyntaxReference) -> Self {
+        let metadata = syntax_metadata(syntax);
+        let has_patterns = metadata.items.iter().any(|(_, m)| {
+            m.items.increase_indent_pattern.is_some() || m.items.decrease_indent_pattern.is_some()
+        });
+        Self {
+            metadata,
+            has_patterns,
+        }
+    }
+
+    /// The lines after this one are indented
+    pub fn increase(&self, line: &str) -> bool {
+        if self.has_patterns {
+            self.metadata.increase_indent(line)
+        } else {
+            BRACE_INCREASE.is_match(line)
+        }
+    }
+
+    /// The line is less indented than the previous one, it closes a block
+    pub fn decrease(&self, line: &str) -> bool {
+        if self.has_patterns {
+            self.metadata.decrease_indent(line)
+        } else {
+            BRACE_DECREASE.is_match(line)
+        }
+    }
+
+    /// Only the next line is indented, like the body of an `if` without braces
+    pub fn indent_next_line(&self, line: &str) -> bool {
+        self.has_patterns && self.metadata.bracket_increase(line)
+    }
+}
@@ -0,0 +1,197 @@
+use std::{collections::HashMap, path::Path, sync::Mutex};
+
+use globset::{Glob, GlobMatcher};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use ropey::RopeSlice;
+use syntect::parsing::SyntaxReference;
+
+use crate::syntax::SYNTAXSET;
+
+/// Number of lines at the start and at the end of a file searched for a modeline, as Vim does
+const MODELINE_LINES: usize = 5;
+
+struct FileAssociation {
+    glob: String,
+    matcher: GlobMatcher,
+    syntax_name: String,
+}
+
+static FILE_ASSOCIATIONS: Mutex<Vec<FileAssociation>> = Mutex::new(Vec::new());
+
+static VIM_MODELINE: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?:^|\s)(?:vi|vim|ex):.*?\b(?:ft|filetype|syntax)=([\w+#.-]+)").unwrap()
+});
+static EMACS_MODELINE: Lazy<Regex> = Lazy::new(|| Regex::new(r"-\*-(.*?)-\*-").unwrap());
+static EMACS_MODE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)\bmode\s*:\s*([^;\s]+)").unwrap());
+
+/// Map the files matching the globs to syntax names, these associations are checked before any other detection.
+/// Globs without a `/` are matched against the file name, the others against the whole path.
+/// Return the globs that are invalid, with their error
+pub fn set_file_associations(associations: &HashMap<String, String>) -> Vec<(String, String)> {
+    let mut errors = Vec::new();
+    let mut file_associations = Vec::new();
+    for (glob, syntax_name) in associations {
+        match Glob::new(glob) {
+            Ok(g) => file_associations.push(FileAssociation {
+                glob: glob.clone(),
+                matcher: g.compile_matcher(),
+                syntax_name: syntax_name.clone(),
+            }),
+            Err(e) => errors.push((glob.clone(), e.to_string())),
+        }
+    }
+    // the longest globs are the most specific ones
+    file_associations.sort_by(|a, b| b.glob.len().cmp(&a.glob.len()).then(a.glob.cmp(&b.glob)));
+    *FILE_ASSOCIATIONS.lock().unwrap() = file_associations;
+    errors
+}
+
+fn find_syntax(name: &str) -> Option<&'static SyntaxReference> {
+    SYNTAXSET.find_syntax_by_name(name).or_else(|| {
+        // interpreters and editor modes that are not a name or an extension of their syntax
+        let token = match name.to_lowercase().as_str() {
+            "node" | "nodejs" | "deno" | "js" => "JavaScript".to_string(),
+            "sh" | "ksh" | "dash" | "zsh" | "shell" => "bash".to_string(),
+            "c++" => "cpp".to_string(),
+            "objc" => "Objective-C".to_string(),
+            "rscript" => "r".to_string(),
+            "make" | "makefile" => "Makefile".to_string(),
+            "text" | "txt" | "fundamental" => "Plain Text".to_string(),
+            token => token.to_string(),
+        };
+        SYNTAXSET.find_syntax_by_token(&token)
+    })
+}
+
+fn from_associations(path: &Path) -> Option<&'static SyntaxReference> {
+    let file_name = path.file_name()?;
+    FILE_ASSOCIATIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|a| {
+            if a.glob.contains('/') {
+                a.matcher.is_match(path)
+            } else {
+                a.matcher.is_match(file_name)
+            }
+        })
+        .find_map(|a| SYNTAXSET.find_syntax_by_name(&a.syntax_name))
+}
+
+fn modeline_syntax(line: &str) -> Option<&'static SyntaxReference> {
+    if let Some(c) = VIM_MODELINE.captures(line) {
+        return find_syntax(&c[1]);
+    }
+    let variables = EMACS_MODELINE.captures(line)?;
+    let variables = variables[1].trim();
+    let mode = if variables.contains(':') {
+        EMACS_MODE.captures(variables)?.get(1)?.as_str()
+    } else {
+        variables
+    };
+    find_syntax(mode)
+}
+
+fn from_modelines(text: &RopeSlice) -> Option<&'static SyntaxReference> {
+    let len_lines = text.len_lines();
+    let last_lines = len_lines.saturating_sub(MODELINE_LINES).max(MODELINE_LINES);
+    (0..len_lines.min(MODELINE_LINES))
+        .chain(last_lines..len_lines)
+        .find_map(|i| {
+            // a long line is code, not a modeline
+            let line = text.line(i);
+            (line.len_chars() < 256).then(|| modeline_syntax(&line.to_string()))?
+        })
+}
+
+fn from_shebang(line: &str) -> Option<&'static SyntaxReference> {
+    let command = line.strip_prefix("#!")?;
+    let mut args = command.split_whitespace();
+    let mut interpreter = args.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        interpreter = args.find(|a| !a.starts_with('-') && !a.contains('='))?;
+    }
+    // python3, python3.12
+    let interpreter = interpreter.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    find_syntax(interpreter)
+}
+
+fn from_first_line(text: &RopeSlice) -> Option<&'static SyntaxReference> {
+    if text.len_chars() == 0 {
+        return None;
+    }
+    let line = text.line(0);
+    let line = line.slice(..line.len_chars().min(256)).to_string();
+    if line.trim_start_matches('\u{FEFF}').starts_with("<?xml") {
+        if let Some(s) = SYNTAXSET.find_syntax_by_name("XML") {
+            return Some(s);
+        }
+    }
+    from_shebang(&line).or_else(|| SYNTAXSET.find_syntax_by_first_line(&line))
+}
+
+/// Find the syntax of a file. In order, it's given by:
+/// - the [file associations](set_file_associations)
+/// - a Vim or Emacs modeline
+/// - the name or the extension of the file
+/// - the first line of the text: shebang, xml declaration...
+pub fn detect_syntax(path: &Path, text: &RopeSlice) -> &'static SyntaxReference {
+    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let extension = path.extension().and_then(|x| x.to_str()).unwrap_or("");
+    from_associations(path)
+        .or_else(|| from_modelines(text))
+        .or_else(|| SYNTAXSET.find_syntax_by_extension(file_name))
+        .or_else(|| SYNTAXSET.find_syntax_by_extension(extension))
+        .or_else(|| from_first_line(text))
+        .unwrap_or_else(|| SYNTAXSET.find_syntax_plain_text())
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, path::Path};
+
+    use ropey::Rope;
+
+    use super::{detect_syntax, set_file_associations};
+
+    fn syntax_name(path: &str, text: &str) -> String {
+        detect_syntax(Path::new(path), &Rope::from_str(text).slice(..))
+            .name
+            .clone()
+    }
+
+    #[test]
+    fn first_line_and_modelines() {
+        assert_eq!(syntax_name("main.rs", "fn main() {}\n"), "Rust");
+        assert_eq!(syntax_name("deploy", "#!/usr/bin/env python3\nprint()\n"), "Python");
+        assert_eq!(syntax_name("run", "#!/bin/sh\necho\n"), "Bourne Again Shell (bash)");
+        assert_eq!(syntax_name("serve", "#!/usr/bin/env -S node --inspect\n"), "JavaScript");
+        assert_eq!(syntax_name("data", "<?xml version=\"1.0\"?>\n<a/>\n"), "XML");
+        assert_eq!(syntax_name("notes", "some text\n"), "Plain Text");
+
+        // modelines win over the extension
+        let text = format!("{}# vim: set ts=4 ft=python :\n", "x = 1\n".repeat(20));
+        assert_eq!(syntax_name("build.txt", &text), "Python");
+        assert_eq!(syntax_name("config", "# -*- mode: ruby; coding: utf-8 -*-\n"), "Ruby");
+        assert_eq!(syntax_name("script", "#!/bin/foo\n// -*- C++ -*-\n"), "C++");
+    }
+
+    #[test]
+    fn file_associations() {
+        let associations = HashMap::from([
+            ("Jenkinsfile".to_string(), "Groovy".to_string()),
+            ("**/ci/*.txt".to_string(), "YAML".to_string()),
+            ("[".to_string(), "Rust".to_string()),
+        ]);
+        let errors = set_file_associations(&associations);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "[");
+
+        assert_eq!(syntax_name("/src/Jenkinsfile", "pipeline {}\n"), "Groovy");
+        assert_eq!(syntax_name("/src/ci/jobs.txt", "a: 1\n"), "YAML");
+        assert_eq!(syntax_name("/src/jobs.txt", "a: 1\n"), "Plain Text");
+        set_file_associations(&HashMap::new());
+    }
+}
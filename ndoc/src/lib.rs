@@ -1,17 +1,45 @@
+mod auto_pairs;
+mod brackets;
 mod document;
 mod file_info;
+mod find_in_files;
+mod folding;
+mod history;
+mod indent;
+mod language;
+mod replace_in_files;
 mod rope_utils;
+mod search;
 pub mod syntax;
+mod wrap;
 
+pub use auto_pairs::default_auto_pairs;
+pub use auto_pairs::set_auto_pairs;
+pub use auto_pairs::DEFAULT_PAIRS_KEY;
 pub use document::Document;
+pub use document::DEFAULT_LARGE_FILE_THRESHOLD;
+pub use file_info::DecodeError;
 pub use file_info::FileInfo;
+pub use file_info::ENCODINGS;
+pub use find_in_files::FileMatch;
+pub use find_in_files::FindInFiles;
+pub use find_in_files::FindInFilesEvent;
 pub use file_info::LineFeed;
+pub use file_info::LineFeedCount;
 pub use file_info::Indentation;
+pub use language::detect_syntax;
+pub use language::set_file_associations;
 pub use document::MoveDirection;
 pub use document::Selection;
 pub use document::SelectionAera;
 pub use document::Position;
+pub use document::SaveOptions;
+pub use replace_in_files::FileReplace;
+pub use replace_in_files::ReplaceHunk;
+pub use search::SearchOptions;
+pub use search::Searcher;
 pub use ropey::Rope;
+pub use encoding_rs::Encoding;
 pub use syntect::highlighting::Color;
 pub use syntect::highlighting::Theme as SyntectTheme;
 pub use syntect::highlighting::ThemeSet;
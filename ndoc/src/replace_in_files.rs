@@ -0,0 +1,246 @@
+use std::{
+    fs,
+    io::{Error, ErrorKind, Result},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use ropey::Rope;
+
+use crate::{
+    document::{char_to_position, Position},
+    file_info::{
+        decode, decode_errors, detect_linefeed, encode_with_invalid_bytes, unencodable_chars, write_atomically,
+        LineFeed,
+    },
+    search::Searcher,
+};
+
+/// The replacement of a single match, that can be accepted or rejected before being applied
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplaceHunk {
+    /// Char range of the match
+    pub range: Range<usize>,
+    pub start: Position,
+    pub matched: String,
+    pub replacement: String,
+    /// The lines containing the match, before the replacement
+    pub before: String,
+    /// The same lines, after the replacement
+    pub after: String,
+    pub accepted: bool,
+}
+
+/// All the replacements proposed for a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileReplace {
+    pub path: PathBuf,
+    pub hunks: Vec<ReplaceHunk>,
+}
+
+impl FileReplace {
+    /// Compute the replacements of all the matches of the [searcher](Searcher) in the rope.
+    /// See [Searcher::replace_iter] for the replacement syntax
+    pub fn new(
+        path: impl Into<PathBuf>,
+        rope: &Rope,
+        searcher: &Searcher,
+        replacement: &str,
+        preserve_case: bool,
+    ) -> Self {
+        let slice = rope.slice(..);
+        let hunks = searcher
+            .replace_iter(rope, 0, replacement, preserve_case)
+            .map(|(range, replacement)| {
+                let start = char_to_position(&slice, range.start);
+                let lines_start = rope.line_to_char(start.line);
+                let prefix = rope.slice(lines_start..range.start).to_string();
+                let suffix = rope
+                    .slice(range.end..)
+                    .chars()
+                    .take_while(|c| *c != '\n' && *c != '\r')
+                    .collect::<String>();
+                let matched = rope.slice(range.clone()).to_string();
+                ReplaceHunk {
+                    start,
+                    before: format!("{prefix}{matched}{suffix}"),
+                    after: format!("{prefix}{replacement}{suffix}"),
+                    matched,
+                    replacement,
+                    range,
+                    accepted: true,
+                }
+            })
+            .collect();
+        Self {
+            path: path.into(),
+            hunks,
+        }
+    }
+
+    /// Compute the replacements of all the matches of the [searcher](Searcher) in a file on disk
+    pub fn from_file(
+        path: impl AsRef<Path>,
+        searcher: &Searcher,
+        replacement: &str,
+        preserve_case: bool,
+    ) -> Result<Self> {
+        let bytes = fs::read(&path)?;
+        let rope = Rope::from_str(&decode(&bytes).0);
+        Ok(Self::new(
+            path.as_ref(),
+            &rope,
+            searcher,
+            replacement,
+            preserve_case,
+        ))
+    }
+
+    /// The accepted hunks still matching the rope, with their replacement using the given line feed.
+    /// Hunks are returned in document order
+    pub(crate) fn accepted_replacements(
+        &self,
+        rope: &Rope,
+        linefeed: LineFeed,
+    ) -> Vec<(Range<usize>, String)> {
+        self.hunks
+            .iter()
+            .filter(|h| h.accepted)
+            .filter(|h| {
+                h.range.end <= rope.len_chars() && rope.slice(h.range.clone()) == h.matched.as_str()
+            })
+            .map(|h| (h.range.clone(), convert_linefeed(&h.replacement, linefeed)))
+            .collect()
+    }
+
+    /// Apply the accepted hunks to the file on disk, keeping its encoding, BOM, line feeds and invalid byte sequences.
+    /// The file is left untouched if a replacement can't be encoded or would replace an invalid sequence.
+    /// Use [Document::apply_file_replace](crate::Document::apply_file_replace) for files opened in the editor.
+    /// Return the number of replaced matches
+    pub fn apply_to_file(&self) -> Result<usize> {
+        let bytes = fs::read(&self.path)?;
+        let (text, encoding, bom) = decode(&bytes);
+        let errors = decode_errors(&bytes, &text, encoding, bom.as_deref());
+        let mut rope = Rope::from_str(&text);
+        let linefeed = detect_linefeed(&rope.slice(..));
+        let replacements = self.accepted_replacements(&rope, linefeed);
+        if replacements.is_empty() {
+            return Ok(0);
+        }
+        if let Some((_, c)) = replacements
+            .iter()
+            .find_map(|(_, replacement)| unencodable_chars(replacement, encoding).first().copied())
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{c:?} can't be encoded in {}", encoding.name()),
+            ));
+        }
+
+        // follow the invalid sequences through the replacements
+        let mut kept_errors = Vec::with_capacity(errors.len());
+        let mut shift = 0isize;
+        let mut next_replacements = replacements.iter().peekable();
+        for error in &errors {
+            while let Some((range, replacement)) = next_replacements.next_if(|(r, _)| r.end <= error.char_idx) {
+                shift += replacement.chars().count() as isize - range.len() as isize;
+            }
+            if next_replacements.peek().is_some_and(|(r, _)| r.start <= error.char_idx) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("a replacement overwrites the invalid bytes at offset {}", error.offset),
+                ));
+            }
+            kept_errors.push((error.char_idx.saturating_add_signed(shift), error));
+        }
+
+        for (range, replacement) in replacements.iter().rev() {
+            rope.remove(range.clone());
+            rope.insert(range.start, replacement);
+        }
+        let (output, _) = encode_with_invalid_bytes(&rope, encoding, bom.as_deref(), &kept_errors);
+        write_atomically(&self.path, &output, false)?;
+        Ok(replacements.len())
+    }
+}
+
+fn convert_linefeed(input: &str, linefeed: LineFeed) -> String {
+    input
+        .split_inclusive('\n')
+        .map(|l| {
+            match l
+                .strip_suffix('\n')
+                .map(|l| l.strip_suffix('\r').unwrap_or(l))
+            {
+                Some(l) => format!("{l}{linefeed}"),
+                None => l.to_string(),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use ropey::Rope;
+
+    use super::FileReplace;
+    use crate::{SearchOptions, Searcher};
+
+    #[test]
+    fn hunk_preview() {
+        let rope = Rope::from_str("let foo = 1;\r\nfoo += foo;\r\n");
+        let searcher = Searcher::new("foo", SearchOptions::default()).unwrap();
+        let replace = FileReplace::new("test.rs", &rope, &searcher, "bar", false);
+        assert_eq!(replace.hunks.len(), 3);
+        assert_eq!(replace.hunks[0].before, "let foo = 1;");
+        assert_eq!(replace.hunks[0].after, "let bar = 1;");
+        assert_eq!(replace.hunks[2].after, "foo += bar;");
+    }
+
+    #[test]
+    fn apply_keeps_encoding_and_linefeed() {
+        let path = std::env::temp_dir().join(format!("ndoc_replace_{}.txt", std::process::id()));
+        // UTF-16LE with BOM and CRLF
+        let mut bytes = vec![0xFF, 0xFE];
+        "héllo\r\nhéllo\r\n"
+            .encode_utf16()
+            .for_each(|c| bytes.extend_from_slice(&c.to_le_bytes()));
+        fs::write(&path, &bytes).unwrap();
+
+        let options = SearchOptions {
+            regex: true,
+            ..Default::default()
+        };
+        let searcher = Searcher::new("llo$", options).unwrap();
+        let mut replace = FileReplace::from_file(&path, &searcher, "y\nwörld", false).unwrap();
+        replace.hunks[0].accepted = false;
+        assert_eq!(replace.apply_to_file().unwrap(), 1);
+
+        let mut expected = vec![0xFF, 0xFE];
+        "héllo\r\nhéy\r\nwörld\r\n"
+            .encode_utf16()
+            .for_each(|c| expected.extend_from_slice(&c.to_le_bytes()));
+        assert_eq!(fs::read(&path).unwrap(), expected);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn apply_keeps_invalid_bytes() {
+        let path = std::env::temp_dir().join(format!("ndoc_replace_invalid_{}.txt", std::process::id()));
+        fs::write(&path, b"\xFFfoo \xC3 foo\n").unwrap();
+        let searcher = Searcher::new("foo", SearchOptions::default()).unwrap();
+
+        let replace = FileReplace::from_file(&path, &searcher, "quux", false).unwrap();
+        assert_eq!(replace.apply_to_file().unwrap(), 2);
+        assert_eq!(fs::read(&path).unwrap(), b"\xFFquux \xC3 quux\n");
+
+        // the file is windows-1252 now, the replacement can't be encoded
+        fs::write(&path, b"caf\xE9 foo\n").unwrap();
+        let replace = FileReplace::from_file(&path, &searcher, "\u{4E2D}", false).unwrap();
+        assert!(replace.apply_to_file().is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"caf\xE9 foo\n");
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,560 @@
+use std::{
+    ops::Range,
+    sync::{Arc, OnceLock},
+};
+
+use caseless::Caseless;
+use regex::{Regex, RegexBuilder};
+use ropey::Rope;
+
+/// Options used to build a [Searcher]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SearchOptions {
+    /// Match the case of the pattern exactly
+    pub case_sensitive: bool,
+    /// Only match whole words
+    pub whole_word: bool,
+    /// The pattern is a regular expression instead of a plain string
+    pub regex: bool,
+}
+
+/// A compiled search pattern.
+///
+/// Plain text searches without case sensitivity use full Unicode case folding (`ß` matches `SS`).
+/// Regular expressions use the simple Unicode case folding of the regex engine.
+/// Patterns can span several lines, `^` and `$` match at line boundaries.
+///
+/// Plain text is searched in the chunks of the rope, regular expressions in a copy of the whole text
+#[derive(Debug, Clone)]
+pub struct Searcher {
+    regex: Regex,
+    fold_case: bool,
+    // byte length of the (folded) pattern, for plain text searches
+    literal_len: Option<usize>,
+    options: SearchOptions,
+}
+
+impl Searcher {
+    pub fn new(pattern: &str, options: SearchOptions) -> Result<Self, regex::Error> {
+        let fold_case = !options.case_sensitive && !options.regex;
+        let (pattern, literal_len) = match (options.regex, fold_case) {
+            (true, _) => (pattern.to_string(), None),
+            (false, true) => {
+                let folded = pattern.chars().default_case_fold().collect::<String>();
+                (regex::escape(&folded), Some(folded.len()))
+            }
+            (false, false) => (regex::escape(pattern), Some(pattern.len())),
+        };
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(!options.case_sensitive && options.regex)
+            .multi_line(true)
+            .crlf(true)
+            .build()?;
+        Ok(Self {
+            regex,
+            fold_case,
+            literal_len,
+            options,
+        })
+    }
+
+    pub fn options(&self) -> SearchOptions {
+        self.options
+    }
+
+    /// Iterate over all the matches of the rope starting at the given char index.
+    /// Matches are returned as char ranges
+    pub fn find_iter<'a>(&'a self, rope: &'a Rope, from_char_idx: usize) -> Matches<'a> {
+        self.matches(rope, None, from_char_idx)
+    }
+
+    /// Same as [find_iter](Self::find_iter), the copy of the text searched by regular expressions is kept in the cache
+    pub(crate) fn find_iter_in<'a>(
+        &'a self,
+        rope: &'a Rope,
+        cache: &SearchCache,
+        from_char_idx: usize,
+    ) -> Matches<'a> {
+        self.matches(rope, Some(cache), from_char_idx)
+    }
+
+    fn matches<'a>(
+        &'a self,
+        rope: &'a Rope,
+        cache: Option<&SearchCache>,
+        from_char_idx: usize,
+    ) -> Matches<'a> {
+        let from_char_idx = from_char_idx.min(rope.len_chars());
+        let (haystack, byte_idx) = match self.literal_len {
+            Some(_) => {
+                let (window, byte_idx) = Window::new(rope, from_char_idx, self.fold_case);
+                (Haystack::Window(window), byte_idx)
+            }
+            None => {
+                let text = match cache {
+                    Some(cache) => cache.text(rope),
+                    None => rope.to_string().into(),
+                };
+                (Haystack::Text(text), rope.char_to_byte(from_char_idx))
+            }
+        };
+        Matches {
+            searcher: self,
+            rope,
+            haystack,
+            byte_idx,
+        }
+    }
+
+    /// Return the first match starting at the given char index
+    /// If cycling is true, the search continue from the beginning of the rope if the end is reached
+    pub fn find(&self, rope: &Rope, from_char_idx: usize, cycling: bool) -> Option<Range<usize>> {
+        self.find_first(rope, None, from_char_idx, cycling)
+    }
+
+    /// Same as [find](Self::find), the copy of the text searched by regular expressions is kept in the cache
+    pub(crate) fn find_in(
+        &self,
+        rope: &Rope,
+        cache: &SearchCache,
+        from_char_idx: usize,
+        cycling: bool,
+    ) -> Option<Range<usize>> {
+        self.find_first(rope, Some(cache), from_char_idx, cycling)
+    }
+
+    fn find_first(
+        &self,
+        rope: &Rope,
+        cache: Option<&SearchCache>,
+        from_char_idx: usize,
+        cycling: bool,
+    ) -> Option<Range<usize>> {
+        if let Some(m) = self.matches(rope, cache, from_char_idx).next() {
+            return Some(m);
+        }
+        if cycling {
+            return self
+                .matches(rope, cache, 0)
+                .next()
+                .filter(|m| m.start < from_char_idx);
+        }
+        None
+    }
+
+    /// Iterate over all the matches of the rope starting at the given char index, along with the text replacing them.
+    /// In regex mode, `$1` or `${name}` in the replacement are expanded with the captured groups.
+    /// If preserve_case is true, the case of the matched text is applied to the replacement
+    pub fn replace_iter<'a>(
+        &'a self,
+        rope: &'a Rope,
+        from_char_idx: usize,
+        replacement: &'a str,
+        preserve_case: bool,
+    ) -> Replacements<'a> {
+        Replacements {
+            matches: self.matches(rope, None, from_char_idx),
+            replacement,
+            preserve_case,
+        }
+    }
+
+    /// Same as [replace_iter](Self::replace_iter), the copy of the text searched by regular expressions is kept in the cache
+    pub(crate) fn replace_iter_in<'a>(
+        &'a self,
+        rope: &'a Rope,
+        cache: &SearchCache,
+        from_char_idx: usize,
+        replacement: &'a str,
+        preserve_case: bool,
+    ) -> Replacements<'a> {
+        Replacements {
+            matches: self.matches(rope, Some(cache), from_char_idx),
+            replacement,
+            preserve_case,
+        }
+    }
+
+    fn is_whole_word(&self, text: &str, range: &Range<usize>) -> bool {
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        let before = text[..range.start].chars().next_back();
+        let after = text[range.end..].chars().next();
+        !before.is_some_and(is_word) && !after.is_some_and(is_word)
+    }
+}
+
+/// A part of the rope, eventually case folded, moving forward chunk by chunk.
+/// Enough of the previous chunks is kept for a match to span several of them
+struct Window<'a> {
+    chunks: ropey::iter::Chunks<'a>,
+    fold_case: bool,
+    text: String,
+    // byte offset in the text of each char of the window, followed by the length of the text
+    offsets: Vec<usize>,
+    // char index in the rope of the start of the window
+    char_idx: usize,
+}
+
+impl<'a> Window<'a> {
+    /// The window starting before the given char, with the byte offset of the char in the window
+    fn new(rope: &'a Rope, char_idx: usize, fold_case: bool) -> (Self, usize) {
+        // the char before is needed to know if a match is a whole word
+        let (chunks, _, chunk_char_idx, _) = rope.chunks_at_char(char_idx.saturating_sub(1));
+        let mut window = Self {
+            chunks,
+            fold_case,
+            text: String::new(),
+            offsets: vec![0],
+            char_idx: chunk_char_idx,
+        };
+        while window.char_idx + window.offsets.len() <= char_idx && window.push_chunk() {}
+        let byte_idx = window.offsets[(char_idx - window.char_idx).min(window.offsets.len() - 1)];
+        (window, byte_idx)
+    }
+
+    fn push_chunk(&mut self) -> bool {
+        match self.chunks.next() {
+            Some(chunk) => {
+                self.push(chunk);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn push(&mut self, chunk: &str) {
+        for c in chunk.chars() {
+            if self.fold_case {
+                self.text.extend(std::iter::once(c).default_case_fold());
+            } else {
+                self.text.push(c);
+            }
+            self.offsets.push(self.text.len());
+        }
+    }
+
+    /// Move to the next chunk, keeping the last `keep` chars and the char before `byte_idx`, which is updated.
+    /// Return false at the end of the rope
+    fn advance(&mut self, keep: usize, byte_idx: &mut usize) -> bool {
+        let Some(chunk) = self.chunks.next() else {
+            return false;
+        };
+        let len = self.offsets.len() - 1;
+        let current = self.offsets.partition_point(|o| *o <= *byte_idx) - 1;
+        let drop = len.saturating_sub(keep).min(current.saturating_sub(1));
+        if drop > 0 {
+            let dropped_bytes = self.offsets[drop];
+            self.text.drain(..dropped_bytes);
+            self.offsets.drain(..drop);
+            self.offsets.iter_mut().for_each(|o| *o -= dropped_bytes);
+            self.char_idx += drop;
+            *byte_idx -= dropped_bytes;
+        }
+        self.push(chunk);
+        true
+    }
+
+    /// Return the char containing the byte, or the next char if round_up is true and the byte is inside a folded char
+    fn byte_to_char(&self, byte_idx: usize, round_up: bool) -> usize {
+        let idx = self.offsets.partition_point(|o| *o <= byte_idx) - 1;
+        let idx = if round_up && self.offsets[idx] != byte_idx {
+            idx + 1
+        } else {
+            idx
+        };
+        self.char_idx + idx
+    }
+}
+
+/// The text searched by the regex
+enum Haystack<'a> {
+    /// A copy of the whole rope, for regular expressions
+    Text(Arc<str>),
+    /// The part of the rope around the next match, for plain text
+    Window(Window<'a>),
+}
+
+impl Haystack<'_> {
+    fn text(&self) -> &str {
+        match self {
+            Haystack::Text(text) => text,
+            Haystack::Window(window) => &window.text,
+        }
+    }
+}
+
+/// The copy of a rope searched by regular expressions, made on the first search and reused until the text changes.
+/// Clones share the copy, a new cache must be used once the text is modified
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SearchCache {
+    text: Arc<OnceLock<Arc<str>>>,
+}
+
+impl SearchCache {
+    pub fn text(&self, rope: &Rope) -> Arc<str> {
+        self.text.get_or_init(|| rope.to_string().into()).clone()
+    }
+}
+
+/// Iterator over the matches of a [Searcher] in a rope
+pub struct Matches<'a> {
+    searcher: &'a Searcher,
+    rope: &'a Rope,
+    haystack: Haystack<'a>,
+    byte_idx: usize,
+}
+
+impl<'a> Matches<'a> {
+    /// Return the byte range of the next match in the haystack
+    fn next_match(&mut self) -> Option<Range<usize>> {
+        // a match of a plain text can start in the last chars of the window, they are kept when it moves
+        let keep = self.searcher.literal_len.unwrap_or_default() + 1;
+        let whole_word = self.searcher.options.whole_word;
+        while self.byte_idx <= self.haystack.text().len() {
+            let found = self
+                .searcher
+                .regex
+                .find_at(self.haystack.text(), self.byte_idx)
+                .map(|m| m.range());
+            let more = match (&found, &self.haystack) {
+                (_, Haystack::Text(_)) => false,
+                (None, _) => true,
+                // the char after the match is needed to know if it's a whole word
+                (Some(range), Haystack::Window(window)) => {
+                    whole_word && range.end == window.text.len()
+                }
+            };
+            if more {
+                if let Haystack::Window(window) = &mut self.haystack {
+                    if window.advance(keep, &mut self.byte_idx) {
+                        continue;
+                    }
+                }
+            }
+            let range = found?;
+            let text = self.haystack.text();
+            if range.is_empty() || (whole_word && !self.searcher.is_whole_word(text, &range)) {
+                // retry from the next char, a shorter match may be a whole word
+                self.byte_idx = range.start
+                    + text[range.start..]
+                        .chars()
+                        .next()
+                        .map(|c| c.len_utf8())
+                        .unwrap_or(1);
+                continue;
+            }
+            self.byte_idx = range.end;
+            return Some(range);
+        }
+        None
+    }
+
+    fn to_char_range(&self, range: &Range<usize>) -> Range<usize> {
+        match &self.haystack {
+            Haystack::Text(_) => {
+                self.rope.byte_to_char(range.start)..self.rope.byte_to_char(range.end)
+            }
+            Haystack::Window(window) => {
+                window.byte_to_char(range.start, false)..window.byte_to_char(range.end, true)
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Matches<'a> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let range = self.next_match()?;
+        Some(self.to_char_range(&range))
+    }
+}
+
+/// Iterator over the matches of a [Searcher] in a rope, along with the text replacing them
+pub struct Replacements<'a> {
+    matches: Matches<'a>,
+    replacement: &'a str,
+    preserve_case: bool,
+}
+
+impl<'a> Iterator for Replacements<'a> {
+    type Item = (Range<usize>, String);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let range = self.matches.next_match()?;
+        let char_range = self.matches.to_char_range(&range);
+        let mut replacement = String::new();
+        if self.matches.searcher.options.regex {
+            // in regex mode the haystack is a copy of the rope
+            if let Some(captures) = self
+                .matches
+                .searcher
+                .regex
+                .captures_at(self.matches.haystack.text(), range.start)
+            {
+                captures.expand(self.replacement, &mut replacement);
+            }
+        } else {
+            replacement.push_str(self.replacement);
+        }
+        if self.preserve_case {
+            let matched = self.matches.rope.slice(char_range.clone()).to_string();
+            replacement = preserve_case(&matched, &replacement);
+        }
+        Some((char_range, replacement))
+    }
+}
+
+/// Apply the case of `matched` to `replacement`.
+/// Handle the all uppercase, all lowercase and capitalized cases, otherwise `replacement` is returned untouched
+fn preserve_case(matched: &str, replacement: &str) -> String {
+    let mut letters = matched.chars().filter(|c| c.is_alphabetic());
+    let Some(first) = letters.next() else {
+        return replacement.to_string();
+    };
+    let rest = letters.collect::<Vec<_>>();
+    if first.is_uppercase() && rest.iter().all(|c| c.is_uppercase()) && !rest.is_empty() {
+        replacement.to_uppercase()
+    } else if first.is_lowercase() && rest.iter().all(|c| c.is_lowercase()) {
+        replacement.to_lowercase()
+    } else if first.is_uppercase() && rest.iter().all(|c| c.is_lowercase()) {
+        let mut chars = replacement.chars();
+        match chars.next() {
+            Some(c) => c.to_uppercase().chain(chars).collect(),
+            None => String::new(),
+        }
+    } else {
+        replacement.to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ropey::Rope;
+
+    use super::{preserve_case, SearchOptions, Searcher};
+
+    fn find_all(text: &str, pattern: &str, options: SearchOptions) -> Vec<(usize, usize)> {
+        let rope = Rope::from_str(text);
+        let searcher = Searcher::new(pattern, options).unwrap();
+        searcher
+            .find_iter(&rope, 0)
+            .map(|r| (r.start, r.end))
+            .collect()
+    }
+
+    #[test]
+    fn unicode_case_folding() {
+        let options = SearchOptions::default();
+        assert_eq!(find_all("Straße STRASSE", "strasse", options), [(0, 6), (7, 14)]);
+        assert_eq!(find_all("ÉTÉ été", "été", options), [(0, 3), (4, 7)]);
+        let options = SearchOptions {
+            case_sensitive: true,
+            ..Default::default()
+        };
+        assert_eq!(find_all("ÉTÉ été", "été", options), [(4, 7)]);
+    }
+
+    #[test]
+    fn multibyte_match_end() {
+        let options = SearchOptions::default();
+        assert_eq!(find_all("aé€b é€", "é€", options), [(1, 3), (5, 7)]);
+    }
+
+    #[test]
+    fn whole_word() {
+        let options = SearchOptions {
+            whole_word: true,
+            ..Default::default()
+        };
+        assert_eq!(find_all("foobar foo bar_foo foo", "foo", options), [(7, 10), (19, 22)]);
+    }
+
+    #[test]
+    fn regex_multi_line() {
+        let options = SearchOptions {
+            regex: true,
+            ..Default::default()
+        };
+        assert_eq!(find_all("fn a() {\n}\nfn b", r"\{\n\}", options), [(7, 10)]);
+        assert_eq!(find_all("ab\r\nAc\r\n", r"^a.$", options), [(0, 2), (4, 6)]);
+    }
+
+    #[test]
+    fn matches_across_chunks() {
+        // far longer than a chunk of the rope
+        let text = "abc Straße abcd _abc\n".repeat(2000);
+        let rope = Rope::from_str(&text);
+        assert!(rope.chunks().count() > 10);
+        let options = SearchOptions::default();
+        let searcher = Searcher::new("STRASSE", options).unwrap();
+        let matches = searcher.find_iter(&rope, 0).collect::<Vec<_>>();
+        assert_eq!(matches.len(), 2000);
+        assert!(matches.iter().all(|m| rope.slice(m.clone()) == "Straße"));
+
+        let options = SearchOptions {
+            whole_word: true,
+            case_sensitive: true,
+            ..Default::default()
+        };
+        let searcher = Searcher::new("abc", options).unwrap();
+        // 21 chars per line
+        let expected = (0..2000).map(|l| l * 21..l * 21 + 3).collect::<Vec<_>>();
+        assert_eq!(searcher.find_iter(&rope, 0).collect::<Vec<_>>(), expected);
+        // from the middle of the rope, the char before the match is known
+        assert_eq!(
+            searcher.find_iter(&rope, 21 * 1000 + 1).next(),
+            Some(21 * 1001..21 * 1001 + 3)
+        );
+    }
+
+    #[test]
+    fn cycling() {
+        let rope = Rope::from_str("abc abc");
+        let searcher = Searcher::new("abc", SearchOptions::default()).unwrap();
+        assert_eq!(searcher.find(&rope, 5, false), None);
+        assert_eq!(searcher.find(&rope, 5, true), Some(0..3));
+    }
+
+    #[test]
+    fn capture_groups() {
+        let rope = Rope::from_str("let a = foo(1); let b = foo(22);");
+        let options = SearchOptions {
+            regex: true,
+            ..Default::default()
+        };
+        let searcher = Searcher::new(r"foo\((?<arg>\d+)\)", options).unwrap();
+        let replaced = searcher
+            .replace_iter(&rope, 0, "bar(${arg}, $1)", false)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            replaced,
+            [
+                (8..14, "bar(1, 1)".to_string()),
+                (24..31, "bar(22, 22)".to_string())
+            ]
+        );
+
+        // no expansion for plain text searches
+        let searcher = Searcher::new("foo", SearchOptions::default()).unwrap();
+        let (_, r) = searcher.replace_iter(&rope, 0, "$1", false).next().unwrap();
+        assert_eq!(r, "$1");
+    }
+
+    #[test]
+    fn case_preservation() {
+        assert_eq!(preserve_case("FOO", "bar"), "BAR");
+        assert_eq!(preserve_case("foo", "Bar"), "bar");
+        assert_eq!(preserve_case("Foo", "bar"), "Bar");
+        assert_eq!(preserve_case("fOo", "bar"), "bar");
+        assert_eq!(preserve_case("123", "bAr"), "bAr");
+
+        let rope = Rope::from_str("foo Foo FOO");
+        let searcher = Searcher::new("foo", SearchOptions::default()).unwrap();
+        let replaced = searcher
+            .replace_iter(&rope, 0, "bar", true)
+            .map(|(_, r)| r)
+            .collect::<Vec<_>>();
+        assert_eq!(replaced, ["bar", "Bar", "BAR"]);
+    }
+}
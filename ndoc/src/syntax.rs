@@ -1,17 +1,37 @@
 use once_cell::sync::{Lazy, OnceCell};
 use ropey::Rope;
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::VecDeque,
+    fs,
     ops::{Deref, Range},
+    path::{Path, PathBuf},
+    str::FromStr,
     sync::{Arc, Mutex},
 };
+use streaming_iterator::StreamingIterator;
 use syntect::{
-    highlighting::{HighlightState, Highlighter, RangedHighlightIterator, Style, ThemeSet},
-    parsing::{ParseState, ScopeStack, SyntaxReference, SyntaxSet},
+    highlighting::{
+        HighlightState, Highlighter as ThemeHighlighter, RangedHighlightIterator, Style, ThemeSet,
+    },
+    parsing::{
+        ParseState, Scope, ScopeStack, ScopedMetadata, SyntaxDefinition, SyntaxReference,
+        SyntaxSet, SyntaxSetBuilder,
+    },
 };
+use tree_sitter::{InputEdit, Language, Parser, Point, Query, QueryCursor, Tree};
 
 use crate::rope_utils;
 
-pub static SYNTAXSET: Lazy<SyntaxSet> = Lazy::new(SyntaxSet::load_defaults_newlines);
+// set by load_syntaxes, taken when SYNTAXSET is first used
+static CUSTOM_SYNTAXSET: Mutex<Option<SyntaxSet>> = Mutex::new(None);
+pub static SYNTAXSET: Lazy<SyntaxSet> = Lazy::new(|| {
+    CUSTOM_SYNTAXSET
+        .lock()
+        .unwrap()
+        .take()
+        .unwrap_or_else(SyntaxSet::load_defaults_newlines)
+});
 pub static THEMESET: OnceCell<ThemeSet> = OnceCell::new();
 
 pub struct ThemeSetRegistry;
@@ -22,13 +42,151 @@ impl ThemeSetRegistry {
     }
 }
 
+/// A `.sublime-syntax` file that could not be loaded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxLoadError {
+    pub path: PathBuf,
+    pub message: String,
+}
 
-#[derive(Debug)]
-pub struct StateCache {
-    states: Vec<(ParseState, HighlightState)>,
-    highlighter: Highlighter<'static>,
+impl std::fmt::Display for SyntaxLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path.to_string_lossy(), self.message)
+    }
 }
-#[derive(Debug, Clone)]
+
+fn syntax_files(folder: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(folder) else {
+        return;
+    };
+    for path in entries.flatten().map(|e| e.path()) {
+        if path.is_dir() {
+            syntax_files(&path, files);
+        } else if path.extension().is_some_and(|e| e == "sublime-syntax") {
+            files.push(path);
+        }
+    }
+}
+
+/// Add the `.sublime-syntax` files to the builder.
+/// The definitions that fail to compile are left out and returned with their error
+fn add_syntax_files(builder: &mut SyntaxSetBuilder, mut files: Vec<PathBuf>) -> Vec<SyntaxLoadError> {
+    files.sort();
+    let mut errors = Vec::new();
+    for path in files {
+        let definition = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| {
+                let fallback_name = path.file_stem().map(|n| n.to_string_lossy());
+                SyntaxDefinition::load_from_str(&content, true, fallback_name.as_deref())
+                    .map_err(|e| e.to_string())
+            });
+        match definition {
+            Ok(definition) => builder.add(definition),
+            Err(message) => errors.push(SyntaxLoadError { path, message }),
+        }
+    }
+    errors
+}
+
+/// Use the default syntaxes and the ones of `folder` for [SYNTAXSET]. It must be called before the first use of [SYNTAXSET].
+/// Return the definitions that could not be loaded
+pub fn load_syntaxes(folder: impl AsRef<Path>) -> Vec<SyntaxLoadError> {
+    if Lazy::get(&SYNTAXSET).is_some() {
+        return vec![SyntaxLoadError {
+            path: folder.as_ref().to_path_buf(),
+            message: "the syntaxes are already in use".to_string(),
+        }];
+    }
+    let mut files = Vec::new();
+    syntax_files(folder.as_ref(), &mut files);
+    // the default syntaxes are used as they are when there is nothing to add
+    if files.is_empty() {
+        return Vec::new();
+    }
+    let count = files.len();
+    let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+    let errors = add_syntax_files(&mut builder, files);
+    if errors.len() < count {
+        *CUSTOM_SYNTAXSET.lock().unwrap() = Some(builder.build());
+    }
+    errors
+}
+
+/// The metadata of the syntax, from the `.tmPreferences` files: comment tokens, indentation patterns...
+pub(crate) fn syntax_metadata(syntax: &SyntaxReference) -> ScopedMetadata<'static> {
+    SYNTAXSET.metadata().metadata_for_scope(&[syntax.scope])
+}
+
+// brackets inside these scopes are not code
+static NON_CODE_SCOPES: Lazy<Vec<Scope>> = Lazy::new(|| {
+    ["string", "comment", "constant.character"]
+        .iter()
+        .map(|s| Scope::new(s).unwrap())
+        .collect()
+});
+
+/// Parse the lines of a rope one after the other, to find the brackets that are code, not in a string or a comment.
+/// The parsing starts from a fresh state at the first line, it's assumed not to be in the middle of a string or a comment.
+/// Without a syntax, every bracket is code
+pub(crate) struct CodeBrackets<'a> {
+    rope: &'a Rope,
+    parse_state: Option<ParseState>,
+    scope_stack: ScopeStack,
+    line: usize,
+}
+
+impl<'a> CodeBrackets<'a> {
+    pub fn new(rope: &'a Rope, syntax: Option<&SyntaxReference>, line: usize) -> Self {
+        Self {
+            rope,
+            parse_state: syntax.map(ParseState::new),
+            scope_stack: ScopeStack::new(),
+            line,
+        }
+    }
+}
+
+impl Iterator for CodeBrackets<'_> {
+    /// The index of the line, and the char index and value of its brackets
+    type Item = (usize, Vec<(usize, char)>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.line >= self.rope.len_lines() {
+            return None;
+        }
+        let line_idx = self.line;
+        self.line += 1;
+        let text = self.rope.line(line_idx).to_string();
+        let ops = match &mut self.parse_state {
+            Some(state) => state.parse_line(&text, &SYNTAXSET).unwrap_or_default(),
+            None => Vec::new(),
+        };
+        let mut ops = ops.iter().peekable();
+        let line_start = self.rope.line_to_char(line_idx);
+        let mut brackets = Vec::new();
+        for (char_idx, (byte_idx, c)) in text.char_indices().enumerate() {
+            while let Some((_, op)) = ops.next_if(|(pos, _)| *pos <= byte_idx) {
+                let _ = self.scope_stack.apply(op);
+            }
+            if matches!(c, '(' | ')' | '[' | ']' | '{' | '}')
+                && !self
+                    .scope_stack
+                    .as_slice()
+                    .iter()
+                    .any(|s| NON_CODE_SCOPES.iter().any(|n| n.is_prefix_of(*s)))
+            {
+                brackets.push((line_start + char_idx, c));
+            }
+        }
+        for (_, op) in ops {
+            let _ = self.scope_stack.apply(op);
+        }
+        Some((line_idx, brackets))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct SpanStyle {
     pub style: Style,
     pub range: Range<usize>,
@@ -40,7 +198,7 @@ impl SpanStyle {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct StyledLine {
     styles: Vec<SpanStyle>,
 }
@@ -59,9 +217,10 @@ impl Deref for StyledLine {
     }
 }
 
+/// The highlighted lines of a document, None for the lines inserted since the last highlight
 #[derive(Debug, Clone)]
 pub struct StyledLinesCache {
-    pub lines: Arc<Mutex<Vec<StyledLine>>>,
+    pub lines: Arc<Mutex<Vec<Option<StyledLine>>>>,
 }
 
 impl StyledLinesCache {
@@ -69,7 +228,24 @@ impl StyledLinesCache {
         Self::default()
     }
     pub fn get(&self, line_idx: usize) -> Option<StyledLine> {
-        self.lines.lock().unwrap().get(line_idx).cloned()
+        self.lines.lock().unwrap().get(line_idx).cloned().flatten()
+    }
+
+    /// Move the highlighted lines following the change, so they stay in front of their text
+    pub fn apply_change(&self, change: LinesChange) {
+        let mut lines = self.lines.lock().unwrap();
+        if change.start >= lines.len() {
+            return;
+        }
+        if change.delta > 0 {
+            lines.splice(
+                change.start..change.start,
+                std::iter::repeat_n(None, change.delta as usize),
+            );
+        } else {
+            let end = (change.start + change.delta.unsigned_abs()).min(lines.len());
+            lines.drain(change.start..end);
+        }
     }
 }
 
@@ -81,93 +257,686 @@ impl Default for StyledLinesCache {
     }
 }
 
-impl Default for StateCache {
-    fn default() -> Self {
-        StateCache {
+/// The lines modified by an edit: the lines `start..end` of the new text replace
+/// the lines `start..end - delta` of the previous one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LinesChange {
+    pub start: usize,
+    pub end: usize,
+    pub delta: isize,
+}
+
+impl LinesChange {
+    /// Every line from `start` changed, for a new language or theme
+    pub fn from_line(start: usize, rope: &Rope) -> Self {
+        Self {
+            start,
+            end: rope.len_lines(),
+            delta: 0,
+        }
+    }
+
+    /// Where a line of the previous text is in the new text, lines inside the change are moved to its end
+    fn map_line(&self, line: usize) -> usize {
+        if line <= self.start {
+            line
+        } else if line as isize >= self.end as isize - self.delta {
+            (line as isize + self.delta) as usize
+        } else {
+            self.end
+        }
+    }
+}
+
+/// A highlighting backend, styling the lines of a rope for the current theme.
+/// Highlighters live in the highlighter thread of the documents, they don't have to be `Send`
+pub trait Highlighter {
+    /// The text of the document changed, the highlights of the changed lines are outdated.
+    /// Return the first line to highlight again, which can be before the change
+    fn update_text(&mut self, rope: &Rope, change: LinesChange) -> usize;
+
+    /// Highlight the lines `start..end` into the cache. The lines before `start` must have been highlighted.
+    /// Return the next line to highlight, the number of lines when the following lines
+    /// are highlighted the same as before the last change
+    fn update_range(
+        &mut self,
+        highlighted_lines: &StyledLinesCache,
+        rope: &Rope,
+        start: usize,
+        end: usize,
+        tab_len: usize,
+    ) -> usize;
+
+    /// Use the given theme for the next highlights
+    fn change_theme(&mut self, theme: &str);
+}
+
+/// The kind of [Highlighter] used for the syntaxes supported by both backends
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum HighlighterBackend {
+    #[default]
+    #[serde(rename = "syntect")]
+    Syntect,
+    #[serde(rename = "tree-sitter")]
+    TreeSitter,
+}
+
+static HIGHLIGHTER_BACKEND: Mutex<HighlighterBackend> = Mutex::new(HighlighterBackend::Syntect);
+
+/// Set the backend used by the highlighters created from now on
+pub fn set_highlighter_backend(backend: HighlighterBackend) {
+    *HIGHLIGHTER_BACKEND.lock().unwrap() = backend;
+}
+
+pub fn highlighter_backend() -> HighlighterBackend {
+    *HIGHLIGHTER_BACKEND.lock().unwrap()
+}
+
+/// Create a highlighter for the syntax with the current backend.
+/// Syntect is used when there is no tree-sitter grammar for the syntax
+pub fn new_highlighter(syntax: &SyntaxReference, theme: &str) -> Box<dyn Highlighter> {
+    let mut highlighter: Box<dyn Highlighter> = match highlighter_backend() {
+        HighlighterBackend::TreeSitter => match TreeSitterHighlighter::new(&syntax.name) {
+            Some(h) => Box::new(h),
+            None => Box::new(SyntectHighlighter::new(syntax)),
+        },
+        HighlighterBackend::Syntect => Box::new(SyntectHighlighter::new(syntax)),
+    };
+    highlighter.change_theme(theme);
+    highlighter
+}
+
+fn theme_highlighter(theme: &str) -> Option<ThemeHighlighter<'static>> {
+    ThemeSetRegistry::get()
+        .themes
+        .get(theme)
+        .map(ThemeHighlighter::new)
+}
+
+fn store_line(highlighted_lines: &StyledLinesCache, line_idx: usize, line: StyledLine) {
+    let mut hl = highlighted_lines.lines.lock().unwrap();
+    if line_idx >= hl.len() {
+        hl.resize(line_idx, None);
+        hl.push(Some(line));
+    } else {
+        hl[line_idx] = Some(line);
+    }
+}
+
+/// The syntect states at the end of a line
+#[derive(Debug, Clone)]
+struct Checkpoint {
+    line: usize,
+    parse_state: ParseState,
+    highlight_state: HighlightState,
+}
+
+/// Highlighter based on the syntect parser and the TextMate grammars of [SYNTAXSET]
+#[derive(Debug)]
+pub struct SyntectHighlighter {
+    syntax: &'static SyntaxReference,
+    // states at the end of every 16th line, up to the last highlighted line
+    states: Vec<Checkpoint>,
+    // states computed before the last changes, moved to their line in the new text.
+    // Once the parsing reaches one of them with the same states, the following lines don't need a new highlight
+    previous_states: VecDeque<Checkpoint>,
+    // the previous states can't be trusted before this line
+    changed_end: usize,
+    highlighter: ThemeHighlighter<'static>,
+}
+
+impl SyntectHighlighter {
+    pub fn new(syntax: &SyntaxReference) -> Self {
+        Self {
+            syntax: SYNTAXSET
+                .find_syntax_by_name(&syntax.name)
+                .unwrap_or_else(|| SYNTAXSET.find_syntax_plain_text()),
             states: Vec::new(),
-            highlighter: Highlighter::new(&ThemeSetRegistry::get().themes["base16-ocean.dark"]),
+            previous_states: VecDeque::new(),
+            changed_end: 0,
+            highlighter: ThemeHighlighter::new(&ThemeSetRegistry::get().themes["base16-ocean.dark"]),
         }
     }
 }
 
-impl StateCache {
-    pub fn new() -> Self {
-        Self::default()
+impl Highlighter for SyntectHighlighter {
+    fn update_text(&mut self, _rope: &Rope, change: LinesChange) -> usize {
+        let valid = self.states.partition_point(|c| c.line < change.start);
+        let old_end = change.end as isize - change.delta;
+        let mut previous_states = self.states.split_off(valid);
+        previous_states.extend(self.previous_states.drain(..));
+        self.previous_states = previous_states
+            .into_iter()
+            .filter(|c| c.line as isize >= old_end)
+            .map(|mut c| {
+                c.line = change.map_line(c.line);
+                c
+            })
+            .collect();
+        self.changed_end = change.map_line(self.changed_end).max(change.end);
+        // the parsing resumes from the last valid checkpoint
+        self.states.last().map_or(0, |c| c.line + 1)
     }
 
-    pub fn change_theme(&mut self, theme: &str) {
-        if !ThemeSetRegistry::get().themes.contains_key(theme) {
-            // TODO: logerror
-            return;
+    fn change_theme(&mut self, theme: &str) {
+        if let Some(highlighter) = theme_highlighter(theme) {
+            self.highlighter = highlighter;
         }
-        self.highlighter = Highlighter::new(&ThemeSetRegistry::get().themes[theme]);
     }
 
-    pub fn update_range(
+    fn update_range(
         &mut self,
-        highlighted_line: &StyledLinesCache,
-        syntax: &SyntaxReference,
+        highlighted_lines: &StyledLinesCache,
         rope: &Rope,
         start: usize,
         end: usize,
         tab_len: usize,
-    ) {
-        // states are cached every 16 lines
-        let start = (start >> 4).min(self.states.len());
-        let end = (end.min(rope.len_lines()) >> 4) + 1;
-
-        self.states.truncate(start);
-
-        let mut states = self.states.last().cloned().unwrap_or_else(|| {
-            (
-                ParseState::new(syntax),
+    ) -> usize {
+        self.states.truncate(self.states.partition_point(|c| c.line < start));
+        let (first_line, mut parse_state, mut highlight_state) = match self.states.last() {
+            Some(c) => (c.line + 1, c.parse_state.clone(), c.highlight_state.clone()),
+            None => (
+                0,
+                ParseState::new(self.syntax),
                 HighlightState::new(&self.highlighter, ScopeStack::new()),
-            )
-        });
+            ),
+        };
+        let end = end.min(rope.len_lines());
 
-        for i in start << 4..(end << 4).min(rope.len_lines()) {
+        for i in first_line..end {
             let str = rope_utils::get_line_info(&rope.slice(..), i, tab_len).to_string();
-            let ops = states.0.parse_line(&str, &SYNTAXSET);
+            let ops = parse_state.parse_line(&str, &SYNTAXSET);
             let h: Vec<_> = if let Ok(ops) = ops {
-                RangedHighlightIterator::new(&mut states.1, &ops, &str, &self.highlighter)
+                RangedHighlightIterator::new(&mut highlight_state, &ops, &str, &self.highlighter)
                     .map(|h| SpanStyle::new(h.0, h.2))
                     .collect()
             } else {
                 Vec::new()
             };
-            let h = StyledLine::new(h);
-
-            // let h = if let Some(str) = rope.line(i).as_str() {
-            //     let ops = states.0.parse_line(&str, &SYNTAXSET);
-            //     let h: Vec<_> = if let Ok(ops) = ops {
-            //         RangedHighlightIterator::new(&mut states.1, &ops, &str, &self.highlighter)
-            //             .map(|h| SpanStyle::new(h.0, h.2))
-            //             .collect()
-            //     } else {
-            //         Vec::new()
-            //     };
-            //     StyledLine::new(h)
-            // } else {
-            //     let str = rope.line(i).to_string();
-            //     let ops = states.0.parse_line(&str, &SYNTAXSET);
-            //     let h: Vec<_> = if let Ok(ops) = ops {
-            //         RangedHighlightIterator::new(&mut states.1, &ops, &str, &self.highlighter)
-            //             .map(|h| SpanStyle::new(h.0, h.2))
-            //             .collect()
-            //     } else {
-            //         Vec::new()
-            //     };
-            //     StyledLine::new(h)
-            // };
+            store_line(highlighted_lines, i, StyledLine::new(h));
+
+            while self.previous_states.front().is_some_and(|c| c.line < i) {
+                self.previous_states.pop_front();
+            }
+            if i >= self.changed_end
+                && self.previous_states.front().is_some_and(|c| {
+                    c.line == i
+                        && c.parse_state == parse_state
+                        && c.highlight_state == highlight_state
+                })
+            {
+                // converged, the next lines are highlighted as they were
+                self.states.extend(self.previous_states.drain(..));
+                self.changed_end = 0;
+                return rope.len_lines();
+            }
+
             if i & 0xF == 0xF {
-                self.states.push(states.clone());
+                self.states.push(Checkpoint {
+                    line: i,
+                    parse_state: parse_state.clone(),
+                    highlight_state: highlight_state.clone(),
+                });
             }
-            let mut hl = highlighted_line.lines.lock().unwrap();
-            if i >= hl.len() {
-                hl.push(h);
+        }
+        if end == rope.len_lines() {
+            self.previous_states.clear();
+            self.changed_end = 0;
+        } else {
+            // the next lines are not highlighted again yet, a new edit before them must not stop
+            // on the checkpoints of this highlight
+            self.changed_end = self.changed_end.max(end);
+        }
+        end
+    }
+}
+
+/// A tree-sitter grammar, with its highlight query, used for the syntect syntax of the same name
+struct TreeSitterGrammar {
+    syntax_name: &'static str,
+    language: fn() -> Language,
+    highlights_query: &'static str,
+}
+
+const TREE_SITTER_GRAMMARS: &[TreeSitterGrammar] = &[
+    TreeSitterGrammar {
+        syntax_name: "Rust",
+        language: || tree_sitter_rust::LANGUAGE.into(),
+        highlights_query: tree_sitter_rust::HIGHLIGHTS_QUERY,
+    },
+    TreeSitterGrammar {
+        syntax_name: "Python",
+        language: || tree_sitter_python::LANGUAGE.into(),
+        highlights_query: tree_sitter_python::HIGHLIGHTS_QUERY,
+    },
+];
+
+/// The syntaxes that can be highlighted with tree-sitter
+pub fn tree_sitter_syntaxes() -> impl Iterator<Item = &'static str> {
+    TREE_SITTER_GRAMMARS.iter().map(|g| g.syntax_name)
+}
+
+/// The TextMate scope matching a tree-sitter capture name, used to find its style in the theme
+fn capture_scope(capture: &str) -> &str {
+    match capture {
+        "string" => "string.quoted",
+        "escape" => "constant.character.escape",
+        "number" => "constant.numeric",
+        "constant.builtin" => "constant.language",
+        "function" | "function.method" => "entity.name.function",
+        "function.macro" => "support.macro",
+        "type" | "constructor" => "entity.name.type",
+        "type.builtin" => "storage.type",
+        "variable.builtin" => "variable.language",
+        "property" => "variable.other.member",
+        "attribute" => "entity.other.attribute-name",
+        "label" => "entity.name.label",
+        "operator" => "keyword.operator",
+        "tag" => "entity.name.tag",
+        _ => capture,
+    }
+}
+
+/// Highlighter based on a tree-sitter grammar. The text is parsed incrementally,
+/// only the lines whose syntax nodes changed are styled again
+pub struct TreeSitterHighlighter {
+    parser: Parser,
+    query: Query,
+    tree: Option<Tree>,
+    rope: Rope,
+    // sorted ranges of lines to style again
+    dirty: Vec<Range<usize>>,
+    highlighter: ThemeHighlighter<'static>,
+    // style of each capture of the query
+    capture_styles: Vec<Style>,
+}
+
+impl std::fmt::Debug for TreeSitterHighlighter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TreeSitterHighlighter")
+            .field("tree", &self.tree)
+            .finish()
+    }
+}
+
+impl TreeSitterHighlighter {
+    /// Create a highlighter for the syntax, None if there is no tree-sitter grammar for it
+    pub fn new(syntax_name: &str) -> Option<Self> {
+        let grammar = TREE_SITTER_GRAMMARS
+            .iter()
+            .find(|g| g.syntax_name == syntax_name)?;
+        let language = (grammar.language)();
+        let mut parser = Parser::new();
+        parser.set_language(&language).ok()?;
+        let query = Query::new(&language, grammar.highlights_query).ok()?;
+        let mut highlighter = Self {
+            parser,
+            query,
+            tree: None,
+            rope: Rope::new(),
+            dirty: Vec::new(),
+            highlighter: ThemeHighlighter::new(&ThemeSetRegistry::get().themes["base16-ocean.dark"]),
+            capture_styles: Vec::new(),
+        };
+        highlighter.update_capture_styles();
+        Some(highlighter)
+    }
+
+    fn update_capture_styles(&mut self) {
+        self.capture_styles = self
+            .query
+            .capture_names()
+            .iter()
+            .map(|name| {
+                let scope = Scope::from_str(capture_scope(name)).unwrap_or_default();
+                self.highlighter.style_for_stack(&[scope])
+            })
+            .collect();
+    }
+
+    fn parse(&mut self, rope: &Rope, old_tree: Option<&Tree>) -> Option<Tree> {
+        self.parser.parse_with(
+            &mut |byte_idx, _| {
+                if byte_idx >= rope.len_bytes() {
+                    return &[] as &[u8];
+                }
+                let (chunk, chunk_start, _, _) = rope.chunk_at_byte(byte_idx);
+                &chunk.as_bytes()[byte_idx - chunk_start..]
+            },
+            old_tree,
+        )
+    }
+
+    fn mark_dirty(&mut self, lines: Range<usize>) {
+        if lines.is_empty() {
+            return;
+        }
+        self.dirty.push(lines);
+        self.dirty.sort_by_key(|r| r.start);
+        let mut merged: Vec<Range<usize>> = Vec::with_capacity(self.dirty.len());
+        for r in self.dirty.drain(..).filter(|r| !r.is_empty()) {
+            match merged.last_mut() {
+                Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+                _ => merged.push(r),
+            }
+        }
+        self.dirty = merged;
+    }
+
+    fn first_dirty_line(&self) -> usize {
+        self.dirty.first().map_or(self.rope.len_lines(), |r| r.start)
+    }
+
+    fn highlight_line(&self, line_idx: usize, tab_len: usize) -> StyledLine {
+        let default_style = self.highlighter.get_default();
+        let Some(tree) = &self.tree else {
+            let slice = self.rope.slice(..);
+            let line = rope_utils::get_line_info(&slice, line_idx, tab_len);
+            return StyledLine::new(vec![SpanStyle::new(default_style, 0..line.len())]);
+        };
+
+        // style of each byte of the line, in the text of the rope
+        let line_start = self.rope.line_to_byte(line_idx);
+        let raw_line = self.rope.line(line_idx);
+        let mut byte_styles = vec![default_style; raw_line.len_bytes()];
+        let mut cursor = QueryCursor::new();
+        cursor.set_byte_range(line_start..line_start + raw_line.len_bytes());
+        let rope = &self.rope;
+        let mut captures = cursor.captures(&self.query, tree.root_node(), |node: tree_sitter::Node| {
+            rope.byte_slice(node.byte_range()).chunks().map(str::as_bytes)
+        });
+        // captures come in document order, inner nodes after outer ones and override them
+        while let Some((m, idx)) = captures.next() {
+            let capture = m.captures[*idx];
+            let range = capture.node.byte_range();
+            let start = range.start.max(line_start) - line_start;
+            let end = range.end.min(line_start + byte_styles.len()).saturating_sub(line_start);
+            if start < end {
+                byte_styles[start..end].fill(self.capture_styles[capture.index as usize]);
+            }
+        }
+
+        // the line is shown with its tabs expanded to spaces, move the styles accordingly
+        let mut spans: Vec<SpanStyle> = Vec::new();
+        let mut raw_idx = 0;
+        let mut display_idx = 0;
+        let mut column = 0;
+        for c in raw_line.chars() {
+            let style = byte_styles[raw_idx];
+            let display_len = if c == '\t' {
+                tab_len - (column % tab_len)
             } else {
-                hl[i] = h;
+                c.len_utf8()
+            };
+            match spans.last_mut() {
+                Some(span) if span.style == style => span.range.end += display_len,
+                _ => spans.push(SpanStyle::new(style, display_idx..display_idx + display_len)),
+            }
+            raw_idx += c.len_utf8();
+            display_idx += display_len;
+            column += if c == '\t' { display_len } else { 1 };
+        }
+        StyledLine::new(spans)
+    }
+}
+
+fn byte_to_point(rope: &Rope, byte_idx: usize) -> Point {
+    let row = rope.byte_to_line(byte_idx);
+    Point::new(row, byte_idx - rope.line_to_byte(row))
+}
+
+impl Highlighter for TreeSitterHighlighter {
+    fn update_text(&mut self, rope: &Rope, change: LinesChange) -> usize {
+        let old_rope = std::mem::replace(&mut self.rope, rope.clone());
+        let old_end = change.end as isize - change.delta;
+        let consistent = old_end >= 0
+            && old_rope.len_lines() as isize + change.delta == rope.len_lines() as isize;
+        let old_tree = match self.tree.take() {
+            Some(mut tree) if consistent => {
+                let start_byte = old_rope.line_to_byte(change.start.min(old_rope.len_lines()));
+                let old_end_byte = old_rope.line_to_byte((old_end as usize).min(old_rope.len_lines()));
+                let new_end_byte = rope.line_to_byte(change.end.min(rope.len_lines()));
+                tree.edit(&InputEdit {
+                    start_byte,
+                    old_end_byte,
+                    new_end_byte,
+                    start_position: byte_to_point(&old_rope, start_byte),
+                    old_end_position: byte_to_point(&old_rope, old_end_byte),
+                    new_end_position: byte_to_point(rope, new_end_byte),
+                });
+                Some(tree)
+            }
+            _ => None,
+        };
+
+        let tree = self.parse(rope, old_tree.as_ref());
+        match (&old_tree, &tree) {
+            (Some(old_tree), Some(tree)) => {
+                self.dirty = std::mem::take(&mut self.dirty)
+                    .into_iter()
+                    .map(|r| change.map_line(r.start)..change.map_line(r.end))
+                    .collect();
+                self.mark_dirty(change.start..change.end);
+                for range in old_tree.changed_ranges(tree) {
+                    self.mark_dirty(range.start_point.row..range.end_point.row + 1);
+                }
             }
+            _ => {
+                self.dirty.clear();
+                self.mark_dirty(0..rope.len_lines());
+            }
+        }
+        self.tree = tree;
+        // the syntax nodes may have changed before the edit, and lines left from the previous changes
+        self.first_dirty_line()
+    }
+
+    fn change_theme(&mut self, theme: &str) {
+        if let Some(highlighter) = theme_highlighter(theme) {
+            self.highlighter = highlighter;
+            self.update_capture_styles();
+        }
+    }
+
+    fn update_range(
+        &mut self,
+        highlighted_lines: &StyledLinesCache,
+        _rope: &Rope,
+        start: usize,
+        end: usize,
+        tab_len: usize,
+    ) -> usize {
+        let end = end.min(self.rope.len_lines());
+        let lines: Vec<usize> = self
+            .dirty
+            .iter()
+            .flat_map(|r| r.start.max(start)..r.end.min(end))
+            .collect();
+        for i in lines {
+            store_line(highlighted_lines, i, self.highlight_line(i, tab_len));
+        }
+
+        // the lines of the range are up to date
+        self.dirty = std::mem::take(&mut self.dirty)
+            .into_iter()
+            .flat_map(|r| [r.start..r.end.min(start), r.start.max(end)..r.end])
+            .filter(|r| !r.is_empty())
+            .collect();
+        self.first_dirty_line()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ropey::Rope;
+    use syntect::parsing::SyntaxSetBuilder;
+
+    use super::{
+        Highlighter, LinesChange, StyledLinesCache, SyntectHighlighter, TreeSitterHighlighter,
+        SYNTAXSET,
+    };
+
+    fn highlighters() -> [Box<dyn Highlighter>; 2] {
+        let syntax = SYNTAXSET.find_syntax_by_name("Rust").unwrap();
+        [
+            Box::new(SyntectHighlighter::new(syntax)),
+            Box::new(TreeSitterHighlighter::new("Rust").unwrap()),
+        ]
+    }
+
+    /// Insert the text at the start of the line, like the worker does for an edit.
+    /// Return the next line to highlight
+    fn insert_lines(
+        highlighter: &mut dyn Highlighter,
+        cache: &StyledLinesCache,
+        rope: &mut Rope,
+        line: usize,
+        text: &str,
+    ) -> usize {
+        let old_len = rope.len_lines();
+        rope.insert(rope.line_to_char(line), text);
+        let delta = rope.len_lines() as isize - old_len as isize;
+        let change = LinesChange {
+            start: line,
+            end: line + delta as usize + 1,
+            delta,
+        };
+        cache.apply_change(change);
+        let next = highlighter.update_text(rope, change);
+        highlighter.update_range(cache, rope, next, next + 100, 4)
+    }
+
+    /// Highlight the remaining lines, then check they are the same as a full highlight of the rope
+    fn assert_highlight_complete(
+        highlighter: &mut dyn Highlighter,
+        mut full: Box<dyn Highlighter>,
+        cache: &StyledLinesCache,
+        rope: &Rope,
+        mut next: usize,
+    ) {
+        while next < rope.len_lines() {
+            next = highlighter.update_range(cache, rope, next, next + 1000, 4);
         }
+        let full_cache = StyledLinesCache::new();
+        full.update_text(rope, LinesChange::from_line(0, rope));
+        full.update_range(&full_cache, rope, 0, rope.len_lines(), 4);
+        for i in 0..rope.len_lines() {
+            assert_eq!(cache.get(i), full_cache.get(i), "line {i}");
+        }
+    }
+
+    #[test]
+    fn both_backends_cover_the_lines() {
+        let rope = Rope::from_str("fn main() {\n\tlet s = \"é\";\n}\n");
+        for mut highlighter in highlighters() {
+            let cache = StyledLinesCache::new();
+            highlighter.update_text(&rope, LinesChange::from_line(0, &rope));
+            highlighter.update_range(&cache, &rope, 0, rope.len_lines(), 4);
+
+            // the tab is shown as 4 spaces
+            let line = cache.get(1).unwrap();
+            assert_eq!(line.first().unwrap().range.start, 0);
+            assert_eq!(line.last().unwrap().range.end, "    let s = \"é\";\n".len());
+            assert!(line.windows(2).all(|w| w[0].range.end == w[1].range.start));
+
+            // "let" and the string are not styled the same
+            let style_at = |i: usize| line.iter().find(|s| s.range.contains(&i)).unwrap().style;
+            assert_ne!(style_at(4), style_at(13));
+        }
+        assert!(TreeSitterHighlighter::new("Plain Text").is_none());
+    }
+
+    #[test]
+    fn highlight_stops_when_unchanged() {
+        let function = "fn f() {\n    let a = 1;\n}\n".repeat(100);
+        let text = format!("{function}// */\n{function}");
+        let mut rope = Rope::from_str(&text);
+        for (mut highlighter, mut full) in highlighters().into_iter().zip(highlighters()) {
+            let cache = StyledLinesCache::new();
+            highlighter.update_text(&rope, LinesChange::from_line(0, &rope));
+            let next = highlighter.update_range(&cache, &rope, 0, rope.len_lines(), 4);
+            assert_eq!(next, rope.len_lines());
+
+            // insert a line in the middle of the second function
+            let old_len = rope.len_lines();
+            rope.insert(rope.line_to_char(4), "    let b = \"2\";\n");
+            let change = LinesChange {
+                start: 4,
+                end: 6,
+                delta: rope.len_lines() as isize - old_len as isize,
+            };
+            cache.apply_change(change);
+            let next = highlighter.update_text(&rope, change);
+            // the following lines are highlighted the same, the highlight stops early
+            let next = highlighter.update_range(&cache, &rope, next, 100, 4);
+            assert_eq!(next, rope.len_lines());
+
+            let full_cache = StyledLinesCache::new();
+            full.update_text(&rope, LinesChange::from_line(0, &rope));
+            full.update_range(&full_cache, &rope, 0, rope.len_lines(), 4);
+            for i in 0..rope.len_lines() {
+                assert_eq!(cache.get(i), full_cache.get(i), "line {i}");
+            }
+
+            // opening a comment changes the next lines up to its end, they are highlighted again
+            let old_len = rope.len_lines();
+            rope.insert(rope.line_to_char(2), "/*\n");
+            let change = LinesChange {
+                start: 2,
+                end: 3,
+                delta: rope.len_lines() as isize - old_len as isize,
+            };
+            cache.apply_change(change);
+            let next = highlighter.update_text(&rope, change);
+            assert_eq!(highlighter.update_range(&cache, &rope, next, 100, 4), 100);
+
+            // typing before the lines still to highlight again doesn't stop before them
+            let next = insert_lines(highlighter.as_mut(), &cache, &mut rope, 0, "let c = 3; ");
+            assert_highlight_complete(highlighter.as_mut(), full, &cache, &rope, next);
+        }
+    }
+
+    #[test]
+    fn edit_after_an_unfinished_highlight() {
+        let function = "fn f() {\n    let a = 1;\n}\n".repeat(1000);
+        for (mut highlighter, full) in highlighters().into_iter().zip(highlighters()) {
+            let mut rope = Rope::from_str(&function);
+            let cache = StyledLinesCache::new();
+            highlighter.update_text(&rope, LinesChange::from_line(0, &rope));
+            highlighter.update_range(&cache, &rope, 0, rope.len_lines(), 4);
+
+            // the comment changes all the next lines, only the first ones are highlighted before the next edit
+            insert_lines(highlighter.as_mut(), &cache, &mut rope, 500, "/*\n");
+            let next = insert_lines(highlighter.as_mut(), &cache, &mut rope, 2500, "    let b = 2;\n");
+            assert_highlight_complete(highlighter.as_mut(), full, &cache, &rope, next);
+        }
+    }
+
+    #[test]
+    fn custom_syntaxes() {
+        let folder = std::env::temp_dir().join(format!("ndoc_syntaxes_{}", std::process::id()));
+        std::fs::create_dir_all(folder.join("dsl")).unwrap();
+        std::fs::write(
+            folder.join("dsl").join("Pipeline.sublime-syntax"),
+            "%YAML 1.2\n---\nfile_extensions: [pipe]\nscope: source.pipe\ncontexts:\n  main:\n    - match: '\\bstage\\b'\n      scope: keyword.control.pipe\n",
+        )
+        .unwrap();
+        std::fs::write(
+            folder.join("Broken.sublime-syntax"),
+            "%YAML 1.2\n---\nscope: source.broken\ncontexts:\n  main:\n    - match: '(unclosed'\n",
+        )
+        .unwrap();
+
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add_plain_text_syntax();
+        let mut files = Vec::new();
+        super::syntax_files(&folder, &mut files);
+        let errors = super::add_syntax_files(&mut builder, files);
+        let syntax_set = builder.build();
+        let syntax = syntax_set.find_syntax_by_extension("pipe").unwrap();
+        assert_eq!(syntax.name, "Pipeline");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path, folder.join("Broken.sublime-syntax"));
+        std::fs::remove_dir_all(&folder).unwrap();
     }
 }
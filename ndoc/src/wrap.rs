@@ -0,0 +1,210 @@
+use std::{ops::Range, sync::Arc};
+
+use itertools::Itertools;
+use ropey::{Rope, RopeSlice};
+
+use crate::{folding::Folds, rope_utils::NextGraphemeIdxIterator, syntax::LinesChange};
+
+// until the width of the view is known
+const DEFAULT_WRAP_WIDTH: usize = 80;
+
+/// The columns where the rows of a wrapped line start, the first row excluded.
+/// Lines are broken after the last blank fitting in `width` visible columns, in the middle of a word longer than a row.
+/// The blanks ending a row can go over the width
+pub(crate) fn wrap_line(line: RopeSlice, width: usize, tab_len: usize) -> Vec<usize> {
+    let width = width.max(1);
+    let mut breaks = Vec::new();
+    let mut row_start = 0;
+    // column and visible column after the last blank of the row
+    let mut last_blank: Option<(usize, usize)> = None;
+    let mut vcol = 0;
+    for (col, (i, _)) in NextGraphemeIdxIterator::new(&line).tuple_windows().enumerate() {
+        let c = line.char(i);
+        if c == '\n' || c == '\r' {
+            break;
+        }
+        let blank = c == ' ' || c == '\t';
+        let w = if c == '\t' { tab_len - vcol % tab_len } else { 1 };
+        if !blank && vcol + w > row_start + width && vcol > row_start {
+            let (break_col, break_vcol) = last_blank
+                .filter(|(_, v)| *v > row_start)
+                .unwrap_or((col, vcol));
+            breaks.push(break_col);
+            row_start = break_vcol;
+            last_blank = None;
+        }
+        vcol += w;
+        if blank {
+            last_blank = Some((col + 1, vcol));
+        }
+    }
+    breaks
+}
+
+/// The soft wrap of a document: the rows of its long lines, and the first screen row of every line.
+/// The vectors are shared between the clones of the document, they are only copied when modified
+#[derive(Debug, Clone)]
+pub(crate) struct Wraps {
+    enabled: bool,
+    width: usize,
+    // the breaks of each line
+    breaks: Arc<Vec<Vec<usize>>>,
+    // the first row of each line, followed by the row count. Hidden lines start on the row after their fold header
+    rows: Arc<Vec<usize>>,
+}
+
+impl Default for Wraps {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            width: DEFAULT_WRAP_WIDTH,
+            breaks: Arc::default(),
+            rows: Arc::default(),
+        }
+    }
+}
+
+impl Wraps {
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool, rope: &Rope, folds: &Folds, tab_len: usize) {
+        self.enabled = enabled;
+        self.update(rope, folds, tab_len);
+    }
+
+    pub fn set_width(&mut self, width: usize, rope: &Rope, folds: &Folds, tab_len: usize) {
+        if width != self.width {
+            self.width = width;
+            self.update(rope, folds, tab_len);
+        }
+    }
+
+    /// Wrap every line again
+    pub fn update(&mut self, rope: &Rope, folds: &Folds, tab_len: usize) {
+        if self.enabled {
+            self.breaks = Arc::new(
+                rope.lines()
+                    .map(|l| wrap_line(l, self.width, tab_len))
+                    .collect(),
+            );
+        } else {
+            self.breaks = Arc::default();
+        }
+        self.update_rows(rope, folds);
+    }
+
+    /// Wrap the lines modified by an edit
+    pub fn apply_change(&mut self, rope: &Rope, folds: &Folds, tab_len: usize, change: LinesChange) {
+        if !self.enabled {
+            return;
+        }
+        let breaks = Arc::make_mut(&mut self.breaks);
+        let old_end = ((change.end as isize - change.delta) as usize).min(breaks.len());
+        let start = change.start.min(old_end);
+        let end = change.end.min(rope.len_lines());
+        breaks.splice(
+            start..old_end,
+            (start..end).map(|l| wrap_line(rope.line(l), self.width, tab_len)),
+        );
+        self.update_rows(rope, folds);
+    }
+
+    /// Count the rows again, after a change of the folds
+    pub fn update_rows(&mut self, rope: &Rope, folds: &Folds) {
+        if !self.enabled {
+            self.rows = Arc::default();
+            return;
+        }
+        let hidden = folds.hidden_ranges();
+        let mut hidden = hidden.iter().peekable();
+        let mut rows = Vec::with_capacity(rope.len_lines() + 1);
+        let mut row = 0;
+        for line in 0..rope.len_lines() {
+            rows.push(row);
+            while hidden.next_if(|r| r.end <= line).is_some() {}
+            if !hidden.peek().is_some_and(|r| r.contains(&line)) {
+                row += 1 + self.breaks(line).len();
+            }
+        }
+        rows.push(row);
+        self.rows = Arc::new(rows);
+    }
+
+    /// The columns where the rows of the line start, the first one excluded
+    pub fn breaks(&self, line: usize) -> &[usize] {
+        self.breaks.get(line).map_or(&[], |b| b.as_slice())
+    }
+
+    /// The first row of the line
+    pub fn line_to_row(&self, line: usize) -> usize {
+        self.rows
+            .get(line)
+            .or(self.rows.last())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The line shown on the row, and the index of the row in the line
+    pub fn row_to_line(&self, row: usize) -> (usize, usize) {
+        // hidden lines start on the same row as the next visible one, it's the last line starting before the row
+        let line = self.rows[..self.rows.len().saturating_sub(1)]
+            .partition_point(|r| *r <= row)
+            .saturating_sub(1);
+        (line, row - self.line_to_row(line).min(row))
+    }
+
+    pub fn row_count(&self) -> usize {
+        self.rows.last().copied().unwrap_or_default()
+    }
+
+    /// The columns of the line shown on its wrapped row `index`
+    pub fn columns(&self, line: usize, index: usize, line_len: usize) -> Range<usize> {
+        let breaks = self.breaks(line);
+        let start = if index == 0 {
+            0
+        } else {
+            breaks.get(index - 1).copied().unwrap_or(line_len)
+        };
+        start..breaks.get(index).copied().unwrap_or(line_len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ropey::Rope;
+
+    use super::{wrap_line, Wraps};
+    use crate::folding::Folds;
+
+    #[test]
+    fn wrap_lines() {
+        let rope = Rope::from_str("hello world foo\nabcdefghij\n\tab cd\n");
+        assert_eq!(wrap_line(rope.line(0), 8, 4), vec![6, 12]);
+        // the blanks ending a row go over the width
+        assert_eq!(wrap_line(rope.line(0), 5, 4), vec![6, 12]);
+        assert_eq!(wrap_line(rope.line(1), 4, 4), vec![4, 8]);
+        assert_eq!(wrap_line(rope.line(1), 10, 4), Vec::<usize>::new());
+        assert_eq!(wrap_line(rope.line(2), 6, 4), vec![4]);
+
+        let mut folds = Folds::default();
+        folds.add(1..2);
+        let mut wraps = Wraps::default();
+        wraps.set_width(8, &rope, &folds, 4);
+        wraps.set_enabled(true, &rope, &folds, 4);
+        // the hidden line has no row
+        assert_eq!(wraps.row_count(), 3 + 2 + 1);
+        assert_eq!(wraps.line_to_row(2), 3);
+        assert_eq!(wraps.row_to_line(2), (0, 2));
+        assert_eq!(wraps.row_to_line(3), (2, 0));
+        assert_eq!(wraps.row_to_line(4), (2, 1));
+        assert_eq!(wraps.row_to_line(5), (3, 0));
+        assert_eq!(wraps.columns(0, 1, 15), 6..12);
+        assert_eq!(wraps.columns(0, 2, 15), 12..15);
+    }
+}
@@ -111,6 +111,52 @@ const REDO_CMD: ViewCommand = ViewCommand {
     },
 };
 
+const EARLIER_CMD: ViewCommand = ViewCommand {
+    name: "Undo History: Earlier",
+    id: "editor.earlier",
+    action: |_id, v, c| {
+        v.doc.lock().earlier(1);
+        v.refocus_main_selection(c);
+    },
+};
+const LATER_CMD: ViewCommand = ViewCommand {
+    name: "Undo History: Later",
+    id: "editor.later",
+    action: |_id, v, c| {
+        v.doc.lock().later(1);
+        v.refocus_main_selection(c);
+    },
+};
+const NEXT_REDO_BRANCH_CMD: ViewCommand = ViewCommand {
+    name: "Undo History: Switch Redo Branch",
+    id: "editor.next_redo_branch",
+    action: |_id, v, _c| {
+        v.doc.lock().next_redo_branch();
+    },
+};
+const UNDO_TO_TIME_CMD: ViewCommand = ViewCommand {
+    name: "Undo History: Undo to Time",
+    id: "editor.undo_to_time",
+    action: |_id, v, _c| {
+        let doc = v.doc.clone();
+        v.palette()
+            .description("Undo the changes made in the last N minutes")
+            .accept(move |c, _, s| {
+                if let Ok(minutes) = s.trim().parse::<u64>() {
+                    doc.lock()
+                        .earlier_by(std::time::Duration::from_secs(minutes * 60));
+
+                    c.widget()
+                        .lock()
+                        .downcast_ref::<TextEditor>()
+                        .unwrap()
+                        .refocus_main_selection(c);
+                }
+            })
+            .show();
+    },
+};
+
 // const COPY_SELECTION_CMD: ViewCommand = ViewCommand {
 //     name: "Copy Selection",
 //     id: "editor.copyselection",
@@ -152,11 +198,140 @@ const SAVE_DOC_CMD: ViewCommand = ViewCommand {
     name: "Save document",
     id: "editor.save_doc",
     action: |_id, v, c| {
-        if let Some(ref file_name) = v.doc.get().file_name {
-            v.doc.lock().save_as(file_name).unwrap();
-        } else {
-            v.save_as(c);
+        v.save(c);
+    },
+};
+
+const CHANGE_LINEFEED: ViewCommand = ViewCommand {
+    name: "Convert Line Endings",
+    id: "editor.change_linefeed",
+    action: |_id, v, _c| {
+        v.change_linefeed();
+    },
+};
+
+const CONVERT_TO_LF: ViewCommand = ViewCommand {
+    name: "Convert Line Endings to LF",
+    id: "editor.convert_to_lf",
+    action: |_id, v, _c| {
+        v.doc.lock().convert_linefeeds(ndoc::LineFeed::LF);
+    },
+};
+
+const CONVERT_TO_CRLF: ViewCommand = ViewCommand {
+    name: "Convert Line Endings to CRLF",
+    id: "editor.convert_to_crlf",
+    action: |_id, v, _c| {
+        v.doc.lock().convert_linefeeds(ndoc::LineFeed::CRLF);
+    },
+};
+
+const CONVERT_TO_CR: ViewCommand = ViewCommand {
+    name: "Convert Line Endings to CR",
+    id: "editor.convert_to_cr",
+    action: |_id, v, _c| {
+        v.doc.lock().convert_linefeeds(ndoc::LineFeed::CR);
+    },
+};
+
+const WITH_BOM: &str = " with BOM";
+
+/// Encoding names for the palette, encodings having a BOM are listed with and without it
+fn encoding_items() -> Vec<String> {
+    ndoc::ENCODINGS
+        .iter()
+        .flat_map(|e| match e.name() {
+            "UTF-8" | "UTF-16LE" | "UTF-16BE" => {
+                vec![e.name().to_string(), format!("{}{WITH_BOM}", e.name())]
+            }
+            _ => vec![e.name().to_string()],
+        })
+        .collect()
+}
+
+const REOPEN_WITH_ENCODING: ViewCommand = ViewCommand {
+    name: "Reopen with Encoding",
+    id: "editor.reopen_with_encoding",
+    action: |_id, v, _c| {
+        if v.doc.get().file_name.is_none() {
+            return;
         }
+        let doc = v.doc.clone();
+        let items = ndoc::ENCODINGS
+            .iter()
+            .map(|e| e.name().to_string())
+            .collect();
+        v.palette()
+            .description("Reopen with encoding")
+            .items(items)
+            .accept(move |c, idx, _| {
+                if let Err(e) = doc.lock().reopen_with_encoding(ndoc::ENCODINGS[idx]) {
+                    let editor = c.widget().instance().clone();
+                    editor.use_as(|text_editor: &TextEditor| {
+                        text_editor.show_error("Failed to reopen the document", &e)
+                    });
+                }
+            })
+            .show();
+    },
+};
+
+const SAVE_WITH_ENCODING: ViewCommand = ViewCommand {
+    name: "Save with Encoding",
+    id: "editor.save_with_encoding",
+    action: |_id, v, _c| {
+        let doc = v.doc.clone();
+        v.palette()
+            .description("Save with encoding")
+            .items(encoding_items())
+            .accept(move |c, _, val| {
+                let bom = val.ends_with(WITH_BOM);
+                let Some(encoding) =
+                    ndoc::Encoding::for_label(val.trim_end_matches(WITH_BOM).as_bytes())
+                else {
+                    return;
+                };
+                let editor = c.widget().instance().clone();
+                let unencodable = doc.get().unencodable_chars(encoding);
+                if unencodable.is_empty() {
+                    doc.lock().set_encoding(encoding, bom);
+                    editor.use_as(|text_editor: &TextEditor| text_editor.save(c));
+                    return;
+                }
+
+                // let the user look at the chars that would be lost before writing anything
+                let mut items = vec![format!(
+                    "Save anyway, {} characters will be replaced",
+                    unencodable.len()
+                )];
+                items.extend(unencodable.iter().map(|(p, ch)| {
+                    format!("Ln {}, Col {}: {ch} (U+{:04X})", p.line + 1, p.column + 1, *ch as u32)
+                }));
+                let doc = doc.clone();
+                editor.use_as(|text_editor: &TextEditor| {
+                    let doc = doc.clone();
+                    let unencodable = unencodable.clone();
+                    text_editor
+                        .palette()
+                        .description("Some characters can't be encoded")
+                        .items(items.clone())
+                        .accept(move |c, idx, _| {
+                            let editor = c.widget().instance().clone();
+                            if idx == 0 {
+                                doc.lock().set_encoding(encoding, bom);
+                                editor.use_as(|text_editor: &TextEditor| text_editor.save(c));
+                            } else {
+                                let (p, _) = unencodable[idx - 1];
+                                doc.lock().set_main_selection(p, p);
+                                editor.use_as(|text_editor: &TextEditor| {
+                                    text_editor.refocus_main_selection(c)
+                                });
+                            }
+                        })
+                        .show();
+                });
+            })
+            .show();
     },
 };
 
@@ -167,9 +342,7 @@ const OPEN_DOC: WindowCommand = WindowCommand {
         #[cfg(target_os = "windows")]
         context.window_mut().winit().unwrap().set_enable(false);
         if let Some(file) = FileDialog::new().pick_file() {
-            // TODO: check for errors
-            let doc = Document::from_file(file).unwrap();
-            w.add_new_doc(Dynamic::new(doc), context)
+            w.open_file(file);
         }
         #[cfg(target_os = "windows")]
         context.window_mut().winit().unwrap().set_enable(true);
@@ -184,6 +357,7 @@ const CLOSE_DOC: WindowCommand = WindowCommand {
         let current_doc = w.current_doc.get();
         let docs_len = w.documents.get().len();
         if docs_len > 1 {
+            w.documents.get()[current_doc].get().unregister();
             w.documents.lock().remove(current_doc);
             *w.current_doc.lock() = w.current_doc.get().saturating_sub(1);
         }
@@ -290,6 +464,124 @@ const DUPLICATE_SELECTION: ViewCommand = ViewCommand {
     },
 };
 
+const GOTO_MATCHING_BRACKET: ViewCommand = ViewCommand {
+    name: "Go to Matching Bracket",
+    id: "editor.goto_matching_bracket",
+    action: |_id, v, c| {
+        v.doc.lock().goto_matching_bracket(false);
+        v.refocus_main_selection(c);
+    },
+};
+
+const SELECT_INSIDE_BRACKETS: ViewCommand = ViewCommand {
+    name: "Select Inside Brackets",
+    id: "editor.select_inside_brackets",
+    action: |_id, v, c| {
+        v.doc.lock().select_inside_brackets();
+        v.refocus_main_selection(c);
+    },
+};
+
+const TOGGLE_LINE_COMMENT: ViewCommand = ViewCommand {
+    name: "Toggle Line Comment",
+    id: "editor.toggle_line_comment",
+    action: |_id, v, c| {
+        v.doc.lock().toggle_line_comment();
+        v.refocus_main_selection(c);
+    },
+};
+
+const TOGGLE_BLOCK_COMMENT: ViewCommand = ViewCommand {
+    name: "Toggle Block Comment",
+    id: "editor.toggle_block_comment",
+    action: |_id, v, c| {
+        v.doc.lock().toggle_block_comment();
+        v.refocus_main_selection(c);
+    },
+};
+
+const SELECT_ALL_OCCURRENCES: ViewCommand = ViewCommand {
+    name: "Select All Occurrences",
+    id: "editor.select_all_occurrences",
+    action: |_id, v, c| {
+        v.doc.lock().select_all_occurrences();
+        v.refocus_main_selection(c);
+    },
+};
+
+const SKIP_OCCURRENCE: ViewCommand = ViewCommand {
+    name: "Skip Occurrence",
+    id: "editor.skip_occurrence",
+    action: |_id, v, c| {
+        v.doc.lock().skip_occurrence();
+        v.refocus_main_selection(c);
+    },
+};
+
+const UNDO_LAST_CURSOR: ViewCommand = ViewCommand {
+    name: "Undo Last Cursor",
+    id: "editor.undo_last_cursor",
+    action: |_id, v, c| {
+        v.doc.lock().undo_last_cursor();
+        v.refocus_main_selection(c);
+    },
+};
+
+const ADD_CURSORS_AT_LINE_ENDS: ViewCommand = ViewCommand {
+    name: "Add Cursors at Line Ends",
+    id: "editor.add_cursors_at_line_ends",
+    action: |_id, v, c| {
+        v.doc.lock().add_cursors_at_line_ends();
+        v.refocus_main_selection(c);
+    },
+};
+
+const REINDENT: ViewCommand = ViewCommand {
+    name: "Reindent Lines",
+    id: "editor.reindent",
+    action: |_id, v, c| {
+        v.doc.lock().reindent();
+        v.refocus_main_selection(c);
+    },
+};
+
+const TOGGLE_FOLD: ViewCommand = ViewCommand {
+    name: "Fold/Unfold",
+    id: "editor.toggle_fold",
+    action: |_id, v, c| {
+        let line = v.doc.get().selections[0].head.line;
+        v.doc.lock().toggle_fold(line);
+        v.refocus_main_selection(c);
+    },
+};
+
+const FOLD_ALL: ViewCommand = ViewCommand {
+    name: "Fold All",
+    id: "editor.fold_all",
+    action: |_id, v, c| {
+        v.doc.lock().fold_all();
+        v.refocus_main_selection(c);
+    },
+};
+
+const UNFOLD_ALL: ViewCommand = ViewCommand {
+    name: "Unfold All",
+    id: "editor.unfold_all",
+    action: |_id, v, c| {
+        v.doc.lock().unfold_all();
+        v.refocus_main_selection(c);
+    },
+};
+
+const TOGGLE_SOFT_WRAP: ViewCommand = ViewCommand {
+    name: "Toggle Soft Wrap",
+    id: "editor.toggle_soft_wrap",
+    action: |_id, v, c| {
+        v.doc.lock().toggle_soft_wrap();
+        v.refocus_main_selection(c);
+    },
+};
+
 const TOGGLE_SEARCH_PANEL: ViewCommand = ViewCommand {
     name: "Show Search Panel",
     id: "editor.show_search_panel",
@@ -298,6 +590,14 @@ const TOGGLE_SEARCH_PANEL: ViewCommand = ViewCommand {
     },
 };
 
+const FIND_IN_FILES: WindowCommand = WindowCommand {
+    name: "Find in Files",
+    id: "window.find_in_files",
+    action: |_id, w, c| {
+        w.toggle_find_in_files(c);
+    },
+};
+
 const CHANGE_THEME: WindowCommand = WindowCommand {
     name: "Change Theme",
     id: "window.change_theme",
@@ -332,6 +632,17 @@ const CHANGE_LANGUAGE: ViewCommand = ViewCommand {
             .items(languages)
             .accept(move |_, _, val| {
                 doc.lock().update_language(&val);
+                let extension = doc
+                    .get()
+                    .file_name
+                    .and_then(|f| f.extension().map(|e| e.to_string_lossy().to_string()));
+                let mut settings = SETTINGS.lock().unwrap();
+                if let (true, Some(extension)) = (settings.remember_language_choice, extension) {
+                    if let Err(e) = settings.save_file_association(&format!("*.{extension}"), &val)
+                    {
+                        tracing::warn!("Failed to remember the language: {e}");
+                    }
+                }
             })
             .show();
     },
@@ -341,13 +652,21 @@ const SHOW_ALL_COMMAND: WindowCommand = WindowCommand {
     name: "Show All Commands",
     id: "window.show_all_commands",
     action: |_id, w, _c| {
-        let mut items = w
-            .cmd_reg
-            .get()
-            .view
-            .values()
-            .map(|v| (v.id, v.name))
-            .collect::<Vec<_>>();
+        // editor commands only apply to text documents
+        let mut items = if w
+            .editor_switcher
+            .use_as(|f: &EditorSwitcher| f.current_text_editor())
+            .is_some()
+        {
+            w.cmd_reg
+                .get()
+                .view
+                .values()
+                .map(|v| (v.id, v.name))
+                .collect::<Vec<_>>()
+        } else {
+            Vec::new()
+        };
         items.extend(w.cmd_reg.get().window.values().map(|v| (v.id, v.name)));
 
         items.sort_by_key(|i| i.1);
@@ -435,8 +754,24 @@ impl CommandsRegistry {
         cmd_reg.view.insert(GOTO_LINE.id, GOTO_LINE);
         cmd_reg.view.insert(UNDO_CMD.id, UNDO_CMD);
         cmd_reg.view.insert(REDO_CMD.id, REDO_CMD);
+        cmd_reg.view.insert(EARLIER_CMD.id, EARLIER_CMD);
+        cmd_reg.view.insert(LATER_CMD.id, LATER_CMD);
+        cmd_reg
+            .view
+            .insert(NEXT_REDO_BRANCH_CMD.id, NEXT_REDO_BRANCH_CMD);
+        cmd_reg.view.insert(UNDO_TO_TIME_CMD.id, UNDO_TO_TIME_CMD);
 
         cmd_reg.view.insert(SAVE_DOC_CMD.id, SAVE_DOC_CMD);
+        cmd_reg
+            .view
+            .insert(REOPEN_WITH_ENCODING.id, REOPEN_WITH_ENCODING);
+        cmd_reg
+            .view
+            .insert(SAVE_WITH_ENCODING.id, SAVE_WITH_ENCODING);
+        cmd_reg.view.insert(CHANGE_LINEFEED.id, CHANGE_LINEFEED);
+        cmd_reg.view.insert(CONVERT_TO_LF.id, CONVERT_TO_LF);
+        cmd_reg.view.insert(CONVERT_TO_CRLF.id, CONVERT_TO_CRLF);
+        cmd_reg.view.insert(CONVERT_TO_CR.id, CONVERT_TO_CR);
         cmd_reg.window.insert(OPEN_DOC.id, OPEN_DOC);
         cmd_reg.window.insert(CLOSE_DOC.id, CLOSE_DOC);
         cmd_reg.window.insert(SELECT_DOC.id, SELECT_DOC);
@@ -449,9 +784,35 @@ impl CommandsRegistry {
         cmd_reg
             .view
             .insert(DUPLICATE_SELECTION.id, DUPLICATE_SELECTION);
+        cmd_reg
+            .view
+            .insert(GOTO_MATCHING_BRACKET.id, GOTO_MATCHING_BRACKET);
+        cmd_reg
+            .view
+            .insert(SELECT_INSIDE_BRACKETS.id, SELECT_INSIDE_BRACKETS);
+        cmd_reg
+            .view
+            .insert(TOGGLE_LINE_COMMENT.id, TOGGLE_LINE_COMMENT);
+        cmd_reg
+            .view
+            .insert(TOGGLE_BLOCK_COMMENT.id, TOGGLE_BLOCK_COMMENT);
+        cmd_reg
+            .view
+            .insert(SELECT_ALL_OCCURRENCES.id, SELECT_ALL_OCCURRENCES);
+        cmd_reg.view.insert(SKIP_OCCURRENCE.id, SKIP_OCCURRENCE);
+        cmd_reg.view.insert(UNDO_LAST_CURSOR.id, UNDO_LAST_CURSOR);
+        cmd_reg
+            .view
+            .insert(ADD_CURSORS_AT_LINE_ENDS.id, ADD_CURSORS_AT_LINE_ENDS);
+        cmd_reg.view.insert(REINDENT.id, REINDENT);
+        cmd_reg.view.insert(TOGGLE_FOLD.id, TOGGLE_FOLD);
+        cmd_reg.view.insert(FOLD_ALL.id, FOLD_ALL);
+        cmd_reg.view.insert(UNFOLD_ALL.id, UNFOLD_ALL);
+        cmd_reg.view.insert(TOGGLE_SOFT_WRAP.id, TOGGLE_SOFT_WRAP);
         cmd_reg
             .view
             .insert(TOGGLE_SEARCH_PANEL.id, TOGGLE_SEARCH_PANEL);
+        cmd_reg.window.insert(FIND_IN_FILES.id, FIND_IN_FILES);
         cmd_reg.window.insert(CHANGE_THEME.id, CHANGE_THEME);
         cmd_reg.view.insert(CHANGE_LANGUAGE.id, CHANGE_LANGUAGE);
         cmd_reg.window.insert(SHOW_ALL_COMMAND.id, SHOW_ALL_COMMAND);
@@ -497,17 +858,28 @@ fn main() -> anyhow::Result<()> {
     );
 
     let mut cmd_reg = CommandsRegistry::register();
-    cmd_reg.bind_shortcuts(settings);
+    cmd_reg.bind_shortcuts(settings.clone());
 
     let cmd_reg = Dynamic::new(cmd_reg);
     let modal = Modal::new();
 
+    ndoc::syntax::set_highlighter_backend(settings.highlighter);
+    for (glob, error) in ndoc::set_file_associations(&settings.file_associations) {
+        tracing::warn!("Invalid file association {glob}: {error}");
+    }
+    for pair in ndoc::set_auto_pairs(&settings.auto_pairs) {
+        tracing::warn!("Invalid auto pair {pair}, it should be two chars");
+    }
     ndoc::Document::init_highlighter();
     let doc = Dynamic::new(if let Some(path) = std::env::args().nth(1) {
         if !Path::new(&path).exists() {
             File::create_new(&path)?;
         }
-        ndoc::Document::from_file(path)?
+        ndoc::Document::from_file_with_progress(
+            path,
+            settings.large_file_threshold_mb * 1024 * 1024,
+            |_| (),
+        )?
     } else {
         ndoc::Document::default()
     });
@@ -517,12 +889,21 @@ fn main() -> anyhow::Result<()> {
 
     let docs = editor.documents.clone();
     let cur_doc = editor.current_doc.clone();
+    let loading = editor.loading.clone();
+    let switcher = editor.editor_switcher.clone();
+    let change_linefeed = move || {
+        switcher.use_as(|f: &EditorSwitcher| {
+            if let Some(editor) = f.current_text_editor() {
+                editor.use_as(|text_editor: &TextEditor| text_editor.change_linefeed())
+            }
+        })
+    };
 
     let mut win = editor
         .make_with_tag(editor_tag)
         .expand()
         .and(
-            StatusBar::new(docs.clone(), cur_doc)
+            StatusBar::new(docs.clone(), cur_doc, loading, change_linefeed)
                 .centered()
                 .pad_by(Px::new(2)),
         )
@@ -1,9 +1,12 @@
 use crate::shortcut::Shortcut;
 use anyhow::Context;
 use directories::ProjectDirs;
-use ndoc::{syntax::THEMESET, Indentation, ThemeSet};
+use ndoc::{
+    syntax::{HighlighterBackend, THEMESET},
+    Indentation, ThemeSet,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf};
 use toml_edit::{de::from_document, DocumentMut};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -12,6 +15,20 @@ pub struct Settings {
     pub indentation: Indentation,
     pub theme: String,
     pub editor_font: Vec<String>,
+    /// Keep the previous content of a file in a `.bak` file when saving
+    pub backup_on_save: bool,
+    /// Files bigger than this are open in large-file mode, without syntax highlighting
+    pub large_file_threshold_mb: u64,
+    /// Highlight with tree-sitter instead of syntect for the languages it supports
+    pub highlighter: HighlighterBackend,
+    /// Syntax names of the files matching a glob, ex: `"Jenkinsfile" = "Groovy"`
+    pub file_associations: HashMap<String, String>,
+    /// Add the language chosen with "Change Language" to the file associations of the extension
+    pub remember_language_choice: bool,
+    /// The pairs closed automatically by syntax name, `"*"` for the other syntaxes, ex: `"*" = ["()", "\"\""]`
+    pub auto_pairs: HashMap<String, Vec<String>>,
+    /// The column where soft wrapped lines are broken, 0 to wrap at the window edge
+    pub wrap_column: usize,
 }
 
 #[derive(Deserialize, Serialize, Debug, Default)]
@@ -20,6 +37,13 @@ struct OptSettings {
     pub indentation: Option<Indentation>,
     pub theme: Option<String>,
     pub editor_font: Option<Vec<String>>,
+    pub backup_on_save: Option<bool>,
+    pub large_file_threshold_mb: Option<u64>,
+    pub highlighter: Option<HighlighterBackend>,
+    pub file_associations: Option<HashMap<String, String>>,
+    pub remember_language_choice: Option<bool>,
+    pub auto_pairs: Option<HashMap<String, Vec<String>>>,
+    pub wrap_column: Option<usize>,
 }
 
 impl Default for Settings {
@@ -39,7 +63,47 @@ impl Default for Settings {
             crate::DUPLICATE_SELECTION.id.to_string(),
             shortcut!(Ctrl + d),
         );
+        shortcuts.insert(
+            crate::SELECT_ALL_OCCURRENCES.id.to_string(),
+            shortcut!(Ctrl + Shift + l),
+        );
+        shortcuts.insert(
+            crate::SKIP_OCCURRENCE.id.to_string(),
+            shortcut!(Ctrl + Alt + d),
+        );
+        shortcuts.insert(
+            crate::UNDO_LAST_CURSOR.id.to_string(),
+            shortcut!(Ctrl + u),
+        );
+        shortcuts.insert(
+            crate::ADD_CURSORS_AT_LINE_ENDS.id.to_string(),
+            shortcut!(Shift + Alt + i),
+        );
         shortcuts.insert(crate::TOGGLE_SEARCH_PANEL.id.to_string(),shortcut!(Ctrl + f));
+        shortcuts.insert(
+            crate::GOTO_MATCHING_BRACKET.id.to_string(),
+            shortcut!(Ctrl + m),
+        );
+        shortcuts.insert(
+            crate::SELECT_INSIDE_BRACKETS.id.to_string(),
+            shortcut!(Ctrl + Shift + m),
+        );
+        shortcuts.insert(
+            crate::TOGGLE_LINE_COMMENT.id.to_string(),
+            shortcut!(Ctrl + '/'),
+        );
+        shortcuts.insert(
+            crate::TOGGLE_BLOCK_COMMENT.id.to_string(),
+            shortcut!(Shift + Alt + a),
+        );
+        shortcuts.insert(
+            crate::TOGGLE_SOFT_WRAP.id.to_string(),
+            shortcut!(Alt + z),
+        );
+        shortcuts.insert(
+            crate::FIND_IN_FILES.id.to_string(),
+            shortcut!(Ctrl + Shift + f),
+        );
         // shortcuts.insert(crate::COPY_SELECTION_CMD.id.to_string(), shortcut!(Ctrl+c));
         // shortcuts.insert(crate::PASTE_SELECTION_CMD.id.to_string(),shortcut!(Ctrl + v));
         // shortcuts.insert(crate::CUT_SELECTION_CMD.id.to_string(),shortcut!(Ctrl + x));
@@ -79,11 +143,25 @@ impl Default for Settings {
             indentation: Default::default(),
             theme: "base16-eighties.dark".to_string(),
             editor_font,
+            backup_on_save: false,
+            large_file_threshold_mb: ndoc::DEFAULT_LARGE_FILE_THRESHOLD / (1024 * 1024),
+            highlighter: HighlighterBackend::default(),
+            file_associations: HashMap::new(),
+            remember_language_choice: false,
+            auto_pairs: ndoc::default_auto_pairs(),
+            wrap_column: 0,
         }
     }
 }
 
 impl Settings {
+    fn config_file() -> anyhow::Result<PathBuf> {
+        Ok(ProjectDirs::from("rs", "", "somepad")
+            .context("Getting project config path")?
+            .config_dir()
+            .join("settings.toml"))
+    }
+
     fn try_load() -> anyhow::Result<Self> {
         // Load themes
         tracing::trace!("Loading themes");
@@ -96,13 +174,15 @@ impl Settings {
             .context("Loading themes")?;
         THEMESET.set(theme_set).unwrap();
 
+        tracing::trace!("Loading syntaxes");
+        for error in ndoc::syntax::load_syntaxes(config_folder.join("syntaxes")) {
+            tracing::warn!("Failed to load syntax {error}");
+        }
+
         tracing::trace!("Loading settings");
         let default_settings = Settings::default();
 
-        let config_file = ProjectDirs::from("rs", "", "somepad")
-            .context("Getting project config path")?
-            .config_dir()
-            .join("settings.toml");
+        let config_file = Self::config_file()?;
 
         tracing::trace!(
             "reading settings file from {}",
@@ -123,11 +203,52 @@ impl Settings {
             indentation: settings.indentation.unwrap_or(default_settings.indentation),
             theme: settings.theme.unwrap_or(default_settings.theme),
             editor_font: settings.editor_font.unwrap_or(default_settings.editor_font),
+            backup_on_save: settings
+                .backup_on_save
+                .unwrap_or(default_settings.backup_on_save),
+            large_file_threshold_mb: settings
+                .large_file_threshold_mb
+                .unwrap_or(default_settings.large_file_threshold_mb),
+            highlighter: settings.highlighter.unwrap_or(default_settings.highlighter),
+            file_associations: settings
+                .file_associations
+                .unwrap_or(default_settings.file_associations),
+            remember_language_choice: settings
+                .remember_language_choice
+                .unwrap_or(default_settings.remember_language_choice),
+            auto_pairs: settings.auto_pairs.unwrap_or(default_settings.auto_pairs),
+            wrap_column: settings.wrap_column.unwrap_or(default_settings.wrap_column),
         };
 
         Ok(settings)
     }
 
+    /// Associate the files matching `glob` to a syntax, and save it in the settings file
+    pub fn save_file_association(&mut self, glob: &str, syntax_name: &str) -> anyhow::Result<()> {
+        self.file_associations
+            .insert(glob.to_string(), syntax_name.to_string());
+        ndoc::set_file_associations(&self.file_associations);
+
+        // edit the file in place to keep its comments and formatting
+        let config_file = Self::config_file()?;
+        let mut toml = match std::fs::read_to_string(&config_file) {
+            Ok(content) => content.parse::<DocumentMut>().context("Parsing settings")?,
+            Err(_) => DocumentMut::new(),
+        };
+        if !toml.contains_table("file_associations") {
+            toml["file_associations"] = toml_edit::table();
+        }
+        toml["file_associations"][glob] = toml_edit::value(syntax_name);
+        if let Some(folder) = config_file.parent() {
+            std::fs::create_dir_all(folder)?;
+        }
+        std::fs::write(&config_file, toml.to_string()).context(format!(
+            "Writing settings file {}",
+            &config_file.to_string_lossy()
+        ))?;
+        Ok(())
+    }
+
     pub fn load() -> Self {
         if let Ok(settings) = Settings::try_load().context("Loading settings") {
             settings
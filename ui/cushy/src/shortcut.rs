@@ -124,6 +124,12 @@ macro_rules! keykind {
             cushy::kludgine::app::winit::keyboard::NamedKey::Escape,
         )
     };
+    // keys that are not identifiers, like '/'
+    (Char $k:literal) => {
+        cushy::kludgine::app::winit::keyboard::Key::Character(smol_str::SmolStr::new(
+            $k.to_string(),
+        ))
+    };
     (Upper $k:ident) => {
         cushy::kludgine::app::winit::keyboard::Key::Character(smol_str::SmolStr::new(
             stringify!($k).to_uppercase(),
@@ -144,6 +150,12 @@ macro_rules! shortcut {
             modifiers: cushy::kludgine::app::winit::keyboard::ModifiersState::CONTROL,
         }
     };
+    (Ctrl+$c:literal) => {
+        $crate::shortcut::Shortcut {
+            key: keykind!(Char $c),
+            modifiers: cushy::kludgine::app::winit::keyboard::ModifiersState::CONTROL,
+        }
+    };
     (Ctrl+Shift+$c:ident) => {
         $crate::shortcut::Shortcut {
             key: keykind!(Upper $c),
@@ -364,5 +376,6 @@ mod test {
                 modifiers: ModifiersState::CONTROL | ModifiersState::SHIFT
             }
         );
+        assert_eq!(shortcut!(Ctrl + '/'), Shortcut::from_str("Ctrl+/").unwrap());
     }
 }
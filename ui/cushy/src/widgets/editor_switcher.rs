@@ -10,6 +10,7 @@ use ndoc::Document;
 
 use crate::CommandsRegistry;
 
+use super::hex_view::HexEditor;
 use super::text_editor::CodeEditor;
 
 #[derive(Debug)]
@@ -33,13 +34,7 @@ impl EditorSwitcher {
         let editors = documents
             .get()
             .iter()
-            .map(|d| {
-                let code_editor = CodeEditor::new(d.clone(), cmd_reg.clone(), modal.clone());
-                let editor_instance = code_editor.editor.clone();
-                
-                let editor_ref = code_editor.make_widget().into_ref();
-                (d.get().id(), (editor_ref, editor_instance))
-            })
+            .map(|d| (d.get().id(), make_editor(d.clone(), cmd_reg.clone(), modal.clone())))
             .collect();
 
         EditorSwitcher {
@@ -55,6 +50,32 @@ impl EditorSwitcher {
     pub fn current_editor(&self) -> WidgetInstance {
         self.editors.get(&self.current_doc.get()).expect("a valid current document id").1.clone()
     }
+
+    /// The current editor if it is a text editor, binary documents are shown in a hex view
+    pub fn current_text_editor(&self) -> Option<WidgetInstance> {
+        if self.documents.get()[self.current_doc.get()].get().is_binary() {
+            None
+        } else {
+            Some(self.current_editor())
+        }
+    }
+}
+
+/// A code editor for text documents, a hex view for binary ones
+fn make_editor(
+    doc: Dynamic<Document>,
+    cmd_reg: Dynamic<CommandsRegistry>,
+    modal: Modal,
+) -> (WidgetRef, WidgetInstance) {
+    if doc.get().is_binary() {
+        let hex_editor = HexEditor::new(doc, cmd_reg, modal);
+        let editor_instance = hex_editor.editor.clone();
+        (hex_editor.make_widget().into_ref(), editor_instance)
+    } else {
+        let code_editor = CodeEditor::new(doc, cmd_reg, modal);
+        let editor_instance = code_editor.editor.clone();
+        (code_editor.make_widget().into_ref(), editor_instance)
+    }
 }
 
 impl WrapperWidget for EditorSwitcher {
@@ -62,14 +83,11 @@ impl WrapperWidget for EditorSwitcher {
         let id = self.documents.get()[self.current_doc.get()].get().id();
 
         if let std::collections::hash_map::Entry::Vacant(e) = self.editors.entry(id) {
-            let editor_instance = CodeEditor::new(
+            e.insert(make_editor(
                 self.documents.get()[self.current_doc.get()].clone(),
                 self.cmd_reg.clone(),
                 self.modal.clone(),
-            )
-            .make_widget();
-            let editor_ref = editor_instance.clone().into_ref();
-            e.insert((editor_ref, editor_instance));
+            ));
         }
         let e = self.editors.get_mut(&id).unwrap();
         &mut e.0
@@ -1,12 +1,14 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::thread;
 use std::time::SystemTime;
 
-use cushy::context::WidgetContext;
+use cushy::context::{EventContext, WidgetContext};
 use cushy::figures::units::Px;
 use cushy::figures::Zero;
 use cushy::kludgine::app::winit::event::ElementState;
 use cushy::kludgine::app::winit::keyboard::ModifiersState;
-use cushy::value::{Dynamic, Source};
+use cushy::value::{Destination, Dynamic, Source};
 use cushy::widget::{
     EventHandling, MakeWidget, MakeWidgetWithTag, WidgetId, WidgetInstance, WidgetRef, WidgetTag, WrapperWidget, HANDLED, IGNORED
 };
@@ -18,12 +20,14 @@ use cushy::window::KeyEvent;
 use ndoc::Document;
 
 use crate::shortcut::event_match;
-use crate::CommandsRegistry;
+use crate::{get_settings, CommandsRegistry};
 
 use super::editor_switcher::EditorSwitcher;
+use super::find_in_files::{find_in_files_panel, FindInFilesState};
 use super::opened_editor::{OpenedEditor, ResizeHandle};
 use super::palette::PaletteState;
 use super::side_bar::SideBar;
+use crate::utils::DowncastWidget;
 
 #[derive(Debug)]
 pub struct EditorWindow {
@@ -34,6 +38,9 @@ pub struct EditorWindow {
     pub mru_documents: Dynamic<HashMap<usize, SystemTime>>,
     focused: Dynamic<bool>,
     pub editor_switcher: WidgetInstance,
+    pub find_in_files: FindInFilesState,
+    /// Progress of the large files being loaded
+    pub loading: Dynamic<String>,
     modal: Modal,
     pub id: Option<WidgetId>,
 }
@@ -71,10 +78,20 @@ impl EditorWindow {
         )
         .make_with_tag(editor_tag);
 
+        let mut find_in_files = FindInFilesState::new();
+        let find_in_files_panel =
+            find_in_files_panel(&mut find_in_files, documents.clone(), current_doc.clone());
+
         let child = Scroll::vertical(opened_editor)
             .expand_vertically()
             .and(ResizeHandle::new(width))
-            .and(editor_switcher.clone()
+            .and(
+                editor_switcher
+                    .clone()
+                    .expand()
+                    .and(find_in_files_panel)
+                    .into_rows()
+                    .gutter(Px::ZERO),
             )
             .into_columns()
             .gutter(Px::ZERO)
@@ -91,6 +108,8 @@ impl EditorWindow {
             cmd_reg,
             focused: Dynamic::new(false),
             editor_switcher,
+            find_in_files,
+            loading: Dynamic::new(String::new()),
             modal,
             id: None,
         }
@@ -101,10 +120,81 @@ impl EditorWindow {
         *self.current_doc.lock() += 1;
     }
 
+    /// Open a file in a new tab. Files above the large file threshold are loaded in a background thread,
+    /// their progress is shown in the status bar
+    pub fn open_file(&self, path: PathBuf) {
+        self.open_file_then(path, |_| {});
+    }
+
+    /// Same as [open_file](Self::open_file), `then` is called with the document once loaded, before it becomes the current one
+    pub fn open_file_then(
+        &self,
+        path: PathBuf,
+        then: impl FnOnce(&Dynamic<Document>) + Send + 'static,
+    ) {
+        let threshold = get_settings().large_file_threshold_mb * 1024 * 1024;
+        let documents = self.documents.clone();
+        let current_doc = self.current_doc.clone();
+        let loading = self.loading.clone();
+        let name = path
+            .file_name()
+            .map(|f| f.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let load = move || {
+            let result = Document::from_file_with_progress(&path, threshold, |progress| {
+                loading.replace(format!("Loading {name} {:.0}%", progress * 100.));
+            });
+            match result {
+                Ok(doc) => {
+                    loading.replace(String::new());
+                    let doc = Dynamic::new(doc);
+                    documents.lock().push(doc.clone());
+                    then(&doc);
+                    current_doc.replace(documents.get().len() - 1);
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to open {}: {e}", path.to_string_lossy());
+                    loading.replace(format!("Failed to open {name}: {e}"));
+                }
+            }
+        };
+        if std::fs::metadata(&path).is_ok_and(|m| m.len() > threshold) {
+            thread::spawn(load);
+        } else {
+            load();
+        }
+    }
+
     pub fn current_doc(&self) -> Dynamic<Document> {
         self.documents.get()[self.current_doc.get()].clone()
     }
 
+    /// Show or hide the find in files panel. The search starts in the folder of the current document
+    pub fn toggle_find_in_files(&self, context: &mut EventContext) {
+        self.find_in_files.closed.toggle();
+        if self.find_in_files.closed.get() {
+            self.find_in_files.cancel();
+            let editor_id = self
+                .editor_switcher
+                .use_as(|f: &EditorSwitcher| f.current_editor().id());
+            context.for_other(&editor_id).unwrap().focus();
+        } else {
+            if let Some(folder) = self
+                .current_doc()
+                .get()
+                .file_name
+                .as_ref()
+                .and_then(|f| f.parent())
+            {
+                self.find_in_files.root.replace(folder.to_path_buf());
+            }
+            context
+                .for_other(&self.find_in_files.id.unwrap())
+                .unwrap()
+                .focus();
+        }
+    }
+
     pub fn palette(&self) -> PaletteState {
         PaletteState::new(self.modal.clone()).owner(self.id.unwrap())
     }
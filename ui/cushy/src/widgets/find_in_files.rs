@@ -0,0 +1,818 @@
+use std::path::{Path, PathBuf};
+
+use cushy::{
+    context::EventContext,
+    figures::{
+        units::{Lp, Px, UPx},
+        IntoSigned, Point, Rect, Round, ScreenScale, Size, Zero,
+    },
+    kludgine::{
+        app::winit::{
+            event::{ElementState, MouseButton},
+            keyboard::{Key, NamedKey},
+        },
+        shapes::Shape,
+        text::Text,
+        DrawableExt,
+    },
+    styles::{components, Color},
+    value::{Destination, Dynamic, Source},
+    widget::{MakeWidget, MakeWidgetWithTag, Widget, WidgetId, WidgetTag, HANDLED, IGNORED},
+    widgets::{scroll::ScrollBarThickness, Custom, Space},
+    WithClone,
+};
+use ndoc::{
+    Document, FileMatch, FileReplace, FindInFiles, FindInFilesEvent, SearchOptions, Searcher,
+};
+use rfd::FileDialog;
+
+use super::editor_window::EditorWindow;
+use super::text_editor::TextEditor;
+
+/// State of the find in files panel
+#[derive(Debug, Clone)]
+pub struct FindInFilesState {
+    pub closed: Dynamic<bool>,
+    pub root: Dynamic<PathBuf>,
+    pub id: Option<WidgetId>,
+    text: Dynamic<Document>,
+    case_sensitive: Dynamic<bool>,
+    whole_word: Dynamic<bool>,
+    regex: Dynamic<bool>,
+    results: Dynamic<Vec<FileMatch>>,
+    status: Dynamic<String>,
+    searching: Dynamic<bool>,
+    search: Dynamic<Option<FindInFiles>>,
+    replace_text: Dynamic<Document>,
+    preserve_case: Dynamic<bool>,
+    previews: Dynamic<Vec<FileReplace>>,
+    // incremented for each search, so events of a cancelled search are ignored
+    generation: Dynamic<usize>,
+}
+
+impl FindInFilesState {
+    pub fn new() -> Self {
+        Self {
+            closed: Dynamic::new(true),
+            root: Dynamic::new(std::env::current_dir().unwrap_or_default()),
+            id: None,
+            text: Dynamic::new(Document::default()),
+            case_sensitive: Dynamic::new(false),
+            whole_word: Dynamic::new(false),
+            regex: Dynamic::new(false),
+            results: Dynamic::new(Vec::new()),
+            status: Dynamic::new(String::new()),
+            searching: Dynamic::new(false),
+            search: Dynamic::new(None),
+            replace_text: Dynamic::new(Document::default()),
+            preserve_case: Dynamic::new(false),
+            previews: Dynamic::new(Vec::new()),
+            generation: Dynamic::new(0),
+        }
+    }
+
+    fn searcher(&self) -> Option<Searcher> {
+        let search_term = self.text.get().rope.to_string();
+        if search_term.is_empty() {
+            self.status.replace(String::new());
+            return None;
+        }
+        let options = SearchOptions {
+            case_sensitive: self.case_sensitive.get(),
+            whole_word: self.whole_word.get(),
+            regex: self.regex.get(),
+        };
+        match Searcher::new(&search_term, options) {
+            Ok(searcher) => Some(searcher),
+            Err(e) => {
+                self.status.replace(format!("Invalid regex: {e}"));
+                None
+            }
+        }
+    }
+
+    /// Start a new search in the root folder, cancelling the running one
+    pub fn start(&self) {
+        self.cancel();
+        self.results.lock().clear();
+        self.previews.lock().clear();
+
+        let Some(searcher) = self.searcher() else {
+            return;
+        };
+
+        let generation = self.generation.get();
+        let (results, status, searching, current_generation) = (
+            self.results.clone(),
+            self.status.clone(),
+            self.searching.clone(),
+            self.generation.clone(),
+        );
+        self.status.replace("Searching...".to_string());
+        self.searching.replace(true);
+        let search = FindInFiles::start(self.root.get(), searcher, move |event| {
+            if current_generation.get() != generation {
+                return;
+            }
+            match event {
+                FindInFilesEvent::Matches(matches) => {
+                    results.lock().extend(matches);
+                    status.replace(format!("Searching... {} results", results.map_ref(|r| r.len())));
+                }
+                FindInFilesEvent::Done => {
+                    status.replace(format!("{} results", results.map_ref(|r| r.len())));
+                    searching.replace(false);
+                }
+            }
+        });
+        *self.search.lock() = Some(search);
+    }
+
+    /// Cancel the running search, if any
+    pub fn cancel(&self) {
+        *self.generation.lock() += 1;
+        // dropping the search cancels it
+        if self.search.lock().take().is_some() && self.searching.get() {
+            self.status
+                .replace(format!("Cancelled, {} results", self.results.map_ref(|r| r.len())));
+        }
+        self.searching.replace(false);
+    }
+
+    /// Compute the replacements in all the files found by the last search, to be reviewed before being applied.
+    /// Opened documents are previewed with their current content
+    pub fn preview_replace(&self, documents: &Dynamic<Vec<Dynamic<Document>>>) {
+        let Some(searcher) = self.searcher() else {
+            return;
+        };
+        let replacement = self.replace_text.get().rope.to_string();
+        let preserve_case = self.preserve_case.get();
+
+        let mut paths = Vec::new();
+        self.results.map_ref(|results| {
+            for m in results {
+                if !paths.contains(&m.path) {
+                    paths.push(m.path.clone());
+                }
+            }
+        });
+        let mut previews = Vec::new();
+        for path in paths {
+            let preview = match opened_document(documents, &path) {
+                Some(doc) => Ok(FileReplace::new(
+                    &path,
+                    &doc.get().rope,
+                    &searcher,
+                    &replacement,
+                    preserve_case,
+                )),
+                None => FileReplace::from_file(&path, &searcher, &replacement, preserve_case),
+            };
+            match preview {
+                Ok(preview) if !preview.hunks.is_empty() => previews.push(preview),
+                Ok(_) => (),
+                Err(e) => tracing::warn!("Failed to read {}: {e}", path.to_string_lossy()),
+            }
+        }
+        let hunks = previews.iter().map(|p| p.hunks.len()).sum::<usize>();
+        self.status.replace(format!(
+            "{hunks} replacements in {} files, review them then apply",
+            previews.len()
+        ));
+        *self.previews.lock() = previews;
+    }
+
+    /// Apply the accepted replacements. Opened documents are edited in the editor, so the change can be undone,
+    /// other files are written directly to disk
+    pub fn apply_replace(&self, documents: &Dynamic<Vec<Dynamic<Document>>>) {
+        let previews = std::mem::take(&mut *self.previews.lock());
+        let mut replaced = 0;
+        let mut files = 0;
+        let mut errors = Vec::new();
+        for preview in previews {
+            let result = match opened_document(documents, &preview.path) {
+                Some(doc) => Ok(doc.lock().apply_file_replace(&preview)),
+                None => preview.apply_to_file(),
+            };
+            match result {
+                Ok(0) => (),
+                Ok(n) => {
+                    replaced += n;
+                    files += 1;
+                }
+                Err(e) => errors.push(format!("{}: {e}", preview.path.to_string_lossy())),
+            }
+        }
+        self.results.lock().clear();
+        if errors.is_empty() {
+            self.status
+                .replace(format!("Replaced {replaced} occurrences in {files} files"));
+        } else {
+            self.status.replace(format!(
+                "Replaced {replaced} occurrences in {files} files, failed: {}",
+                errors.join(", ")
+            ));
+        }
+    }
+
+    pub fn discard_replace(&self) {
+        self.previews.lock().clear();
+        self.status.replace(String::new());
+    }
+}
+
+fn opened_document(
+    documents: &Dynamic<Vec<Dynamic<Document>>>,
+    path: &Path,
+) -> Option<Dynamic<Document>> {
+    documents
+        .get()
+        .iter()
+        .find(|d| {
+            d.get()
+                .file_name
+                .as_ref()
+                .is_some_and(|f| is_same_file(f, path))
+        })
+        .cloned()
+}
+
+impl Default for FindInFilesState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Build the find in files panel, shown below the editors
+pub fn find_in_files_panel(
+    state: &mut FindInFilesState,
+    documents: Dynamic<Vec<Dynamic<Document>>>,
+    current_doc: Dynamic<usize>,
+) -> cushy::widgets::Collapse {
+    let (input_tag, input_id) = WidgetTag::new();
+    state.id = Some(input_id);
+
+    let action_search = {
+        let state = state.clone();
+        move || state.start()
+    };
+    let action_enter = action_search.clone();
+    let action_cancel = {
+        let state = state.clone();
+        move || state.cancel()
+    };
+    let action_pick_folder = {
+        let root = state.root.clone();
+        move || {
+            if let Some(folder) = FileDialog::new().set_directory(root.get()).pick_folder() {
+                root.replace(folder);
+            }
+        }
+    };
+
+    let root_label = state
+        .root
+        .map_each(|root| root.to_string_lossy().into_owned());
+
+    let search_row = "Find in files: "
+        .and(
+            Custom::new(
+                TextEditor::as_input(state.text.clone())
+                    .make_with_tag(input_tag)
+                    .scrollable_horizontally()
+                    .with(&ScrollBarThickness, Lp::points(0))
+                    .width(Lp::cm(5))
+                    .centered(),
+            )
+            .on_keyboard_input(move |_, k, _, _| {
+                if k.state == ElementState::Pressed && k.logical_key == Key::Named(NamedKey::Enter)
+                {
+                    action_enter();
+                    HANDLED
+                } else {
+                    IGNORED
+                }
+            }),
+        )
+        .and("aA".into_checkbox(state.case_sensitive.clone()).centered())
+        .and("ab".into_checkbox(state.whole_word.clone()).centered())
+        .and(".*".into_checkbox(state.regex.clone()).centered())
+        .and(Space::clear().width(Lp::mm(1)))
+        .and("in".centered())
+        .and(root_label.centered())
+        .and(
+            "..."
+                .into_button()
+                .on_click(move |_| action_pick_folder())
+                .centered(),
+        )
+        .and(
+            "Search"
+                .into_button()
+                .on_click(move |_| action_search())
+                .centered(),
+        )
+        .and(
+            "Cancel"
+                .into_button()
+                .on_click(move |_| action_cancel())
+                .with_enabled(state.searching.clone())
+                .centered(),
+        )
+        .and(Space::clear().width(Lp::mm(1)))
+        .and(state.status.clone().centered())
+        .into_columns();
+
+    let has_results = state.results.map_each(|r| !r.is_empty());
+    let previewing = state.previews.map_each(|p| !p.is_empty());
+    let not_previewing = state.previews.map_each(|p| p.is_empty());
+
+    let action_preview = (&*state, &documents).with_clone(|(state, documents)| {
+        move || state.preview_replace(&documents)
+    });
+    let action_apply = (&*state, &documents).with_clone(|(state, documents)| {
+        move || state.apply_replace(&documents)
+    });
+    let action_discard = {
+        let state = state.clone();
+        move || state.discard_replace()
+    };
+
+    let replace_row = "Replace with: "
+        .and(
+            TextEditor::as_input(state.replace_text.clone())
+                .make_widget()
+                .scrollable_horizontally()
+                .with(&ScrollBarThickness, Lp::points(0))
+                .width(Lp::cm(5))
+                .centered(),
+        )
+        .and("AB".into_checkbox(state.preserve_case.clone()).centered())
+        .and(Space::clear().width(Lp::mm(1)))
+        .and(
+            "Preview"
+                .into_button()
+                .on_click(move |_| action_preview())
+                .with_enabled(has_results)
+                .centered(),
+        )
+        .and(
+            "Apply"
+                .into_button()
+                .on_click(move |_| action_apply())
+                .with_enabled(previewing.clone())
+                .centered(),
+        )
+        .and(
+            "Discard"
+                .into_button()
+                .on_click(move |_| action_discard())
+                .with_enabled(previewing.clone())
+                .centered(),
+        )
+        .into_columns();
+
+    let results = FileMatchList::new(
+        state.results.clone(),
+        state.root.clone(),
+        documents,
+        current_doc,
+    )
+    .scrollable_vertically()
+    .height(Lp::cm(5))
+    .collapse_vertically(previewing);
+
+    let preview = ReplacePreview::new(state.previews.clone(), state.root.clone())
+        .scrollable_vertically()
+        .height(Lp::cm(5))
+        .collapse_vertically(not_previewing);
+
+    search_row
+        .and(replace_row)
+        .and(results)
+        .and(preview)
+        .into_rows()
+        .collapse_vertically(state.closed.clone())
+}
+
+/// List of the matches found by a find in files search. Clicking a match opens its file and selects it.
+#[derive(Debug)]
+pub struct FileMatchList {
+    results: Dynamic<Vec<FileMatch>>,
+    root: Dynamic<PathBuf>,
+    documents: Dynamic<Vec<Dynamic<Document>>>,
+    current_doc: Dynamic<usize>,
+    hovered_idx: Dynamic<Option<usize>>,
+}
+
+impl FileMatchList {
+    pub fn new(
+        results: Dynamic<Vec<FileMatch>>,
+        root: Dynamic<PathBuf>,
+        documents: Dynamic<Vec<Dynamic<Document>>>,
+        current_doc: Dynamic<usize>,
+    ) -> Self {
+        Self {
+            results,
+            root,
+            documents,
+            current_doc,
+            hovered_idx: Dynamic::new(None),
+        }
+    }
+
+    fn item_text(&self, root: &Path, m: &FileMatch) -> String {
+        format!(
+            "{}:{}:{}: {}",
+            m.path.strip_prefix(root).unwrap_or(&m.path).to_string_lossy(),
+            m.start.line + 1,
+            m.start.column + 1,
+            m.line.trim()
+        )
+    }
+
+    fn item_at(&self, location: Point<Px>, context: &EventContext<'_>) -> Option<usize> {
+        let padding = context
+            .get(&components::IntrinsicPadding)
+            .into_px(context.kludgine.scale())
+            .round();
+        let location = location - padding;
+        let idx = (location.y
+            / context
+                .kludgine
+                .line_height()
+                .into_px(context.kludgine.scale()))
+        .get();
+        (idx >= 0 && (idx as usize) < self.results.map_ref(|r| r.len())).then_some(idx as usize)
+    }
+
+    /// Open the file of the match, or switch to it if it is already opened, and select the match.
+    /// Files are opened by the [EditorWindow] containing the list, like any other file
+    fn open(&self, m: &FileMatch, context: &EventContext<'_>) {
+        let opened = self.documents.get().iter().position(|d| {
+            d.get()
+                .file_name
+                .as_ref()
+                .is_some_and(|f| is_same_file(f, &m.path))
+        });
+        if let Some(idx) = opened {
+            self.documents.get()[idx]
+                .lock()
+                .set_main_selection(m.end, m.start);
+            self.current_doc.replace(idx);
+            return;
+        }
+        let (start, end) = (m.start, m.end);
+        let mut parent = context.widget().parent();
+        while let Some(widget) = parent {
+            if let Some(window) = widget.lock().downcast_ref::<EditorWindow>() {
+                window.open_file_then(m.path.clone(), move |doc| {
+                    doc.lock().set_main_selection(end, start);
+                });
+                return;
+            }
+            parent = widget.parent();
+        }
+    }
+}
+
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+impl Widget for FileMatchList {
+    fn redraw(&mut self, context: &mut cushy::context::GraphicsContext<'_, '_, '_, '_>) {
+        let padding = context
+            .get(&components::IntrinsicPadding)
+            .into_px(context.gfx.scale())
+            .round();
+        let padding = Point::new(padding, padding);
+
+        context.redraw_when_changed(&self.results);
+        context.redraw_when_changed(&self.hovered_idx);
+        let bg_hovered_color = context.get(&components::DefaultActiveBackgroundColor);
+        let fg_hovered_color = context.get(&components::DefaultActiveForegroundColor);
+        let fg_color = context.get(&components::TextColor);
+        let bg_color = context.get(&components::WidgetBackground);
+
+        let scale = context.gfx.scale();
+        let size = context.gfx.size();
+        let line_height = context.gfx.line_height().into_upx(scale);
+        let root = self.root.get();
+        let hovered_idx = self.hovered_idx.get();
+        let (first_row, page_len) = visible_rows(context, line_height.into_signed());
+
+        context.apply_current_font_settings();
+
+        context.fill(bg_color);
+        let results = self.results.lock();
+        for (i, m) in results.iter().enumerate().skip(first_row).take(page_len) {
+            let y = Px::new(i as i32) * line_height.into_signed();
+            let hovered = hovered_idx == Some(i);
+            if hovered {
+                context.gfx.draw_shape(
+                    Shape::filled_rect(
+                        Rect::new(
+                            Point::new(Px::ZERO, y),
+                            Size::new(size.width, line_height).into_signed(),
+                        ),
+                        bg_hovered_color,
+                    )
+                    .translate_by(padding),
+                );
+            }
+            let txt_color = if hovered { fg_hovered_color } else { fg_color };
+            let text = self.item_text(&root, m);
+            let text = Text::new(&text, txt_color);
+            context
+                .gfx
+                .draw_text(text.translate_by(padding + Point::new(Px::ZERO, y)));
+        }
+    }
+
+    fn layout(
+        &mut self,
+        available_space: Size<cushy::ConstraintLimit>,
+        context: &mut cushy::context::LayoutContext<'_, '_, '_, '_>,
+    ) -> Size<UPx> {
+        context.invalidate_when_changed(&self.results);
+        let padding = context
+            .get(&components::IntrinsicPadding)
+            .into_upx(context.gfx.scale())
+            .round()
+            * 2;
+
+        let h = UPx::new(self.results.map_ref(|r| r.len()) as _)
+            * context.gfx.line_height().into_upx(context.gfx.scale());
+
+        Size::new(available_space.width.max(), h + padding)
+    }
+
+    fn hit_test(&mut self, _location: Point<Px>, _context: &mut EventContext<'_>) -> bool {
+        true
+    }
+
+    fn hover(
+        &mut self,
+        location: Point<Px>,
+        context: &mut EventContext<'_>,
+    ) -> Option<cushy::kludgine::app::winit::window::CursorIcon> {
+        self.hovered_idx.replace(self.item_at(location, context));
+        None
+    }
+
+    fn unhover(&mut self, _context: &mut EventContext<'_>) {
+        self.hovered_idx.replace(None);
+    }
+
+    fn mouse_down(
+        &mut self,
+        location: Point<Px>,
+        _device_id: cushy::window::DeviceId,
+        _button: MouseButton,
+        context: &mut EventContext<'_>,
+    ) -> cushy::widget::EventHandling {
+        if let Some(idx) = self.item_at(location, context) {
+            let m = self.results.map_ref(|r| r[idx].clone());
+            self.open(&m, context);
+            HANDLED
+        } else {
+            IGNORED
+        }
+    }
+}
+
+/// The first row shown by a scrolled list, and the number of rows it can show
+fn visible_rows(
+    context: &cushy::context::GraphicsContext<'_, '_, '_, '_>,
+    line_height: Px,
+) -> (usize, usize) {
+    if line_height <= Px::ZERO {
+        return (0, 0);
+    }
+    let first_row = (-context.gfx.translation().y / line_height).get().max(0) as usize;
+    let page_len = (context.gfx.clip_rect().size.height.into_signed() / line_height).get() as usize;
+    (first_row, page_len + 2)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PreviewRow {
+    File(usize),
+    Before(usize, usize),
+    After(usize, usize),
+}
+
+/// Diff of the replacements proposed in each file.
+/// Clicking a hunk accepts or rejects it, clicking a file accepts or rejects all its hunks
+#[derive(Debug)]
+pub struct ReplacePreview {
+    previews: Dynamic<Vec<FileReplace>>,
+    root: Dynamic<PathBuf>,
+    hovered_idx: Dynamic<Option<usize>>,
+}
+
+impl ReplacePreview {
+    pub fn new(previews: Dynamic<Vec<FileReplace>>, root: Dynamic<PathBuf>) -> Self {
+        Self {
+            previews,
+            root,
+            hovered_idx: Dynamic::new(None),
+        }
+    }
+
+    /// The row at the given index: a file followed by two rows per hunk
+    fn row(previews: &[FileReplace], mut idx: usize) -> Option<PreviewRow> {
+        for (f, preview) in previews.iter().enumerate() {
+            let len = 1 + 2 * preview.hunks.len();
+            if idx < len {
+                return Some(match idx {
+                    0 => PreviewRow::File(f),
+                    i if i % 2 == 1 => PreviewRow::Before(f, i / 2),
+                    i => PreviewRow::After(f, i / 2 - 1),
+                });
+            }
+            idx -= len;
+        }
+        None
+    }
+
+    fn row_count(&self) -> usize {
+        self.previews
+            .map_ref(|previews| previews.iter().map(|p| 1 + 2 * p.hunks.len()).sum())
+    }
+
+    fn row_text(&self, previews: &[FileReplace], row: PreviewRow) -> String {
+        let root = self.root.get();
+        let check = |accepted: bool| if accepted { "[x]" } else { "[ ]" };
+        match row {
+            PreviewRow::File(f) => {
+                let preview = &previews[f];
+                let accepted = preview.hunks.iter().filter(|h| h.accepted).count();
+                format!(
+                    "{} {} ({accepted}/{})",
+                    check(accepted > 0),
+                    preview
+                        .path
+                        .strip_prefix(&root)
+                        .unwrap_or(&preview.path)
+                        .to_string_lossy(),
+                    preview.hunks.len()
+                )
+            }
+            PreviewRow::Before(f, h) => {
+                let hunk = &previews[f].hunks[h];
+                format!(
+                    "    {} {:>5} - {}",
+                    check(hunk.accepted),
+                    hunk.start.line + 1,
+                    hunk.before.trim_end()
+                )
+            }
+            PreviewRow::After(f, h) => {
+                let hunk = &previews[f].hunks[h];
+                format!("              + {}", hunk.after.trim_end())
+            }
+        }
+    }
+
+    fn row_at(&self, location: Point<Px>, context: &EventContext<'_>) -> Option<usize> {
+        let padding = context
+            .get(&components::IntrinsicPadding)
+            .into_px(context.kludgine.scale())
+            .round();
+        let location = location - padding;
+        let idx = (location.y
+            / context
+                .kludgine
+                .line_height()
+                .into_px(context.kludgine.scale()))
+        .get();
+        (idx >= 0 && (idx as usize) < self.row_count()).then_some(idx as usize)
+    }
+}
+
+impl Widget for ReplacePreview {
+    fn redraw(&mut self, context: &mut cushy::context::GraphicsContext<'_, '_, '_, '_>) {
+        let padding = context
+            .get(&components::IntrinsicPadding)
+            .into_px(context.gfx.scale())
+            .round();
+        let padding = Point::new(padding, padding);
+
+        context.redraw_when_changed(&self.previews);
+        context.redraw_when_changed(&self.hovered_idx);
+        let bg_hovered_color = context.get(&components::DefaultActiveBackgroundColor);
+        let fg_hovered_color = context.get(&components::DefaultActiveForegroundColor);
+        let fg_color = context.get(&components::TextColor);
+        let bg_color = context.get(&components::WidgetBackground);
+        let removed_color = Color::new(224, 108, 117, 255);
+        let added_color = Color::new(152, 195, 121, 255);
+
+        let scale = context.gfx.scale();
+        let size = context.gfx.size();
+        let line_height = context.gfx.line_height().into_upx(scale);
+        let hovered_idx = self.hovered_idx.get();
+        let (first_row, page_len) = visible_rows(context, line_height.into_signed());
+
+        context.apply_current_font_settings();
+
+        context.fill(bg_color);
+        let previews = self.previews.lock();
+        for i in first_row..first_row + page_len {
+            let Some(row) = Self::row(&previews, i) else {
+                break;
+            };
+            let y = Px::new(i as i32) * line_height.into_signed();
+            let hovered = hovered_idx == Some(i);
+            if hovered {
+                context.gfx.draw_shape(
+                    Shape::filled_rect(
+                        Rect::new(
+                            Point::new(Px::ZERO, y),
+                            Size::new(size.width, line_height).into_signed(),
+                        ),
+                        bg_hovered_color,
+                    )
+                    .translate_by(padding),
+                );
+            }
+            let txt_color = match row {
+                _ if hovered => fg_hovered_color,
+                PreviewRow::File(_) => fg_color,
+                PreviewRow::Before(..) => removed_color,
+                PreviewRow::After(..) => added_color,
+            };
+            let text = self.row_text(&previews, row);
+            let text = Text::new(&text, txt_color);
+            context
+                .gfx
+                .draw_text(text.translate_by(padding + Point::new(Px::ZERO, y)));
+        }
+    }
+
+    fn layout(
+        &mut self,
+        available_space: Size<cushy::ConstraintLimit>,
+        context: &mut cushy::context::LayoutContext<'_, '_, '_, '_>,
+    ) -> Size<UPx> {
+        context.invalidate_when_changed(&self.previews);
+        let padding = context
+            .get(&components::IntrinsicPadding)
+            .into_upx(context.gfx.scale())
+            .round()
+            * 2;
+
+        let h = UPx::new(self.row_count() as _)
+            * context.gfx.line_height().into_upx(context.gfx.scale());
+
+        Size::new(available_space.width.max(), h + padding)
+    }
+
+    fn hit_test(&mut self, _location: Point<Px>, _context: &mut EventContext<'_>) -> bool {
+        true
+    }
+
+    fn hover(
+        &mut self,
+        location: Point<Px>,
+        context: &mut EventContext<'_>,
+    ) -> Option<cushy::kludgine::app::winit::window::CursorIcon> {
+        self.hovered_idx.replace(self.row_at(location, context));
+        None
+    }
+
+    fn unhover(&mut self, _context: &mut EventContext<'_>) {
+        self.hovered_idx.replace(None);
+    }
+
+    fn mouse_down(
+        &mut self,
+        location: Point<Px>,
+        _device_id: cushy::window::DeviceId,
+        _button: MouseButton,
+        context: &mut EventContext<'_>,
+    ) -> cushy::widget::EventHandling {
+        let Some(idx) = self.row_at(location, context) else {
+            return IGNORED;
+        };
+        let mut previews = self.previews.lock();
+        let Some(row) = Self::row(&previews, idx) else {
+            return IGNORED;
+        };
+        match row {
+            PreviewRow::File(f) => {
+                let accept = !previews[f].hunks.iter().any(|h| h.accepted);
+                for hunk in previews[f].hunks.iter_mut() {
+                    hunk.accepted = accept;
+                }
+            }
+            PreviewRow::Before(f, h) | PreviewRow::After(f, h) => {
+                previews[f].hunks[h].accepted = !previews[f].hunks[h].accepted;
+            }
+        }
+        HANDLED
+    }
+}
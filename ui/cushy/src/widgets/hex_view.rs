@@ -0,0 +1,419 @@
+use cushy::{
+    context::EventContext,
+    figures::{
+        units::{Lp, Px, UPx},
+        IntoSigned, IntoUnsigned, Point, Rect, Round, ScreenScale, Size, Zero,
+    },
+    kludgine::{
+        app::winit::{
+            event::{ElementState, MouseButton},
+            keyboard::{Key, NamedKey},
+        },
+        shapes::Shape,
+        text::Text,
+        DrawableExt,
+    },
+    styles::{components, FamilyOwned, FontFamilyList},
+    value::{Destination, Dynamic, Source},
+    widget::{
+        EventHandling, MakeWidget, Widget, WidgetId, WidgetInstance, WidgetRef, WrapperWidget,
+        HANDLED, IGNORED,
+    },
+    widgets::layers::Modal,
+    ConstraintLimit, ModifiersExt,
+};
+use ndoc::Document;
+
+use super::{
+    palette::PaletteState,
+    scroll::{ContextScroller, WidgetScrollableExt},
+    text_editor::{CodeEditorColors, TextEditorKind},
+};
+use crate::{shortcut::event_match, CommandsRegistry, GOTO_LINE};
+
+const BYTES_PER_ROW: usize = 16;
+// in chars: the offset column, then the hex column with an extra space in the middle, then the ASCII column
+const HEX_START: usize = 10;
+const ASCII_START: usize = HEX_START + BYTES_PER_ROW * 3 + 2;
+const ROW_LEN: usize = ASCII_START + BYTES_PER_ROW;
+
+/// A read-only hex and ASCII view of a binary document
+#[derive(Debug)]
+pub struct HexView {
+    doc: Dynamic<Document>,
+    cmd_reg: Dynamic<CommandsRegistry>,
+    modal: Modal,
+    pub id: Option<WidgetId>,
+    // anchor and head of the selected bytes, both included
+    selection: Dynamic<(usize, usize)>,
+    line_height: Px,
+    char_width: Px,
+    page_len: usize,
+}
+
+impl HexView {
+    pub fn new(doc: Dynamic<Document>, cmd_reg: Dynamic<CommandsRegistry>, modal: Modal) -> Self {
+        Self {
+            doc,
+            cmd_reg,
+            modal,
+            id: None,
+            selection: Dynamic::new((0, 0)),
+            line_height: Px::new(16),
+            char_width: Px::new(8),
+            page_len: 0,
+        }
+    }
+
+    pub fn palette(&self) -> PaletteState {
+        PaletteState::new(self.modal.clone()).owner(self.id.unwrap())
+    }
+
+    fn content_len(&self) -> usize {
+        self.doc.get().binary_content().map_or(0, |c| c.len())
+    }
+
+    /// Select the bytes from `anchor` to `head`, both included
+    pub fn select(&self, anchor: usize, head: usize) {
+        let last = self.content_len().saturating_sub(1);
+        self.selection.replace((anchor.min(last), head.min(last)));
+    }
+
+    /// Scroll the selection head into view
+    pub fn refocus_selection(&self, context: &EventContext<'_>) {
+        let head = self.selection.get().1;
+        context.make_region_visible(Rect::new(
+            Point::new(
+                Px::ZERO,
+                Px::new((head / BYTES_PER_ROW) as i32) * self.line_height - 10,
+            ),
+            Size::new(Px::new(35), self.line_height + 20),
+        ));
+    }
+
+    /// Ask for an offset, in decimal or in hexadecimal with a `0x` prefix, and select the byte there
+    pub fn go_to_offset(&self) {
+        let len = self.content_len();
+        self.palette()
+            .description("Go to offset")
+            .accept(move |c, _, s| {
+                let s = s.trim();
+                let offset = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+                    Some(hex) => usize::from_str_radix(hex, 16),
+                    None => s.parse::<usize>(),
+                };
+                if let Ok(offset) = offset {
+                    if offset >= len {
+                        return;
+                    }
+                    let guard = c.widget().lock();
+                    let hex_view = guard.downcast_ref::<HexView>().unwrap();
+                    hex_view.select(offset, offset);
+                    hex_view.refocus_selection(c);
+                }
+            })
+            .show();
+    }
+
+    /// The selected bytes as space separated hex values
+    fn selection_as_hex(&self) -> String {
+        let doc = self.doc.get();
+        let Some(content) = doc.binary_content() else {
+            return String::new();
+        };
+        let (anchor, head) = self.selection.get();
+        let end = (anchor.max(head) + 1).min(content.len());
+        content[anchor.min(head).min(end)..end]
+            .iter()
+            .map(|b| format!("{b:02X}"))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn location_to_offset(&self, location: Point<Px>) -> Option<usize> {
+        let len = self.content_len();
+        if len == 0 {
+            return None;
+        }
+        let row = (location.y / self.line_height).get().max(0) as usize;
+        let col = (location.x / self.char_width).get().max(0) as usize;
+        let byte = if col >= ASCII_START {
+            col - ASCII_START
+        } else if col >= HEX_START + BYTES_PER_ROW / 2 * 3 {
+            (col - HEX_START - 1) / 3
+        } else if col >= HEX_START {
+            (col - HEX_START) / 3
+        } else {
+            0
+        };
+        Some((row * BYTES_PER_ROW + byte.min(BYTES_PER_ROW - 1)).min(len - 1))
+    }
+
+    fn byte_rects(&self, offset: usize) -> [Rect<Px>; 2] {
+        let row = offset / BYTES_PER_ROW;
+        let col = offset % BYTES_PER_ROW;
+        let y = Px::new(row as i32) * self.line_height;
+        let hex_col = HEX_START + col * 3 + usize::from(col >= BYTES_PER_ROW / 2);
+        [
+            Rect::new(
+                Point::new(Px::new(hex_col as i32) * self.char_width, y),
+                Size::new(self.char_width * 2, self.line_height),
+            ),
+            Rect::new(
+                Point::new(Px::new((ASCII_START + col) as i32) * self.char_width, y),
+                Size::new(self.char_width, self.line_height),
+            ),
+        ]
+    }
+
+    fn apply_font(&mut self, context: &mut cushy::context::GraphicsContext<'_, '_, '_, '_>) {
+        context.apply_current_font_settings();
+        context.gfx.set_font_size(Lp::points(12));
+        let scale = context.gfx.scale();
+        self.line_height = context.gfx.line_height().into_px(scale).ceil();
+        self.char_width = context.gfx.measure_text("0").size.width;
+    }
+}
+
+fn format_row(offset: usize, bytes: &[u8]) -> String {
+    let mut row = format!("{offset:08X}  ");
+    for i in 0..BYTES_PER_ROW {
+        if i == BYTES_PER_ROW / 2 {
+            row.push(' ');
+        }
+        match bytes.get(i) {
+            Some(b) => row.push_str(&format!("{b:02X} ")),
+            None => row.push_str("   "),
+        }
+    }
+    row.push(' ');
+    row.extend(bytes.iter().map(|b| {
+        if b.is_ascii_graphic() || *b == b' ' {
+            *b as char
+        } else {
+            '.'
+        }
+    }));
+    row
+}
+
+impl Widget for HexView {
+    fn mounted(&mut self, context: &mut EventContext<'_>) {
+        self.id = Some(context.widget().id());
+    }
+
+    fn redraw(&mut self, context: &mut cushy::context::GraphicsContext<'_, '_, '_, '_>) {
+        if context.gfx.clip_rect().size == Size::ZERO {
+            return;
+        }
+        context.redraw_when_changed(&self.doc);
+        context.redraw_when_changed(&self.selection);
+
+        let colors = CodeEditorColors::get(TextEditorKind::Code, context);
+        let padding = context
+            .get(&components::IntrinsicPadding)
+            .into_px(context.gfx.scale())
+            .round();
+        let padding = Point::new(padding, padding);
+        self.apply_font(context);
+
+        context.fill(colors.bg);
+        let doc = self.doc.get();
+        let content = doc.binary_content().unwrap_or_default();
+
+        let first_row = ((-context.gfx.translation().y / self.line_height)
+            .get()
+            .max(0)) as usize;
+        self.page_len =
+            (context.gfx.clip_rect().size.height.into_signed() / self.line_height).get() as _;
+        let last_row = (first_row + self.page_len + 2).min(content.len().div_ceil(BYTES_PER_ROW));
+
+        let (anchor, head) = self.selection.get();
+        let selected = anchor.min(head)..=anchor.max(head);
+        for row in first_row..last_row {
+            let start = row * BYTES_PER_ROW;
+            let bytes = &content[start..(start + BYTES_PER_ROW).min(content.len())];
+            for offset in (start..start + bytes.len()).filter(|o| selected.contains(o)) {
+                for rect in self.byte_rects(offset) {
+                    let color = if offset == head {
+                        colors.cursor
+                    } else {
+                        colors.bg_selection
+                    };
+                    context
+                        .gfx
+                        .draw_shape(Shape::filled_rect(rect, color).translate_by(padding));
+                }
+            }
+
+            let y = Px::new(row as i32) * self.line_height;
+            let text = format_row(start, bytes);
+            let (offset_text, bytes_text) = text.split_at(HEX_START);
+            context.gfx.draw_text(
+                Text::new(offset_text, colors.fg_gutter)
+                    .translate_by(padding + Point::new(Px::ZERO, y)),
+            );
+            context
+                .gfx
+                .draw_text(Text::new(bytes_text, colors.fg).translate_by(
+                    padding + Point::new(Px::new(HEX_START as i32) * self.char_width, y),
+                ));
+        }
+    }
+
+    fn layout(
+        &mut self,
+        _available_space: Size<ConstraintLimit>,
+        context: &mut cushy::context::LayoutContext<'_, '_, '_, '_>,
+    ) -> Size<UPx> {
+        context.invalidate_when_changed(&self.doc);
+        self.apply_font(context);
+        let padding = context
+            .get(&components::IntrinsicPadding)
+            .into_upx(context.gfx.scale())
+            .round()
+            * 2;
+        let rows = self.content_len().div_ceil(BYTES_PER_ROW).max(1);
+        Size::new(
+            (Px::new(ROW_LEN as i32) * self.char_width).into_unsigned() + padding,
+            (Px::new(rows as i32) * self.line_height)
+                .into_unsigned()
+                .max(context.gfx.clip_rect().size.height)
+                + padding,
+        )
+    }
+
+    fn accept_focus(&mut self, _context: &mut EventContext<'_>) -> bool {
+        true
+    }
+
+    fn hit_test(&mut self, _location: Point<Px>, _context: &mut EventContext<'_>) -> bool {
+        true
+    }
+
+    fn mouse_down(
+        &mut self,
+        location: Point<Px>,
+        _device_id: cushy::window::DeviceId,
+        button: MouseButton,
+        context: &mut EventContext<'_>,
+    ) -> EventHandling {
+        context.focus();
+        if button != MouseButton::Left {
+            return IGNORED;
+        }
+        let padding = context
+            .get(&components::IntrinsicPadding)
+            .into_px(context.kludgine.scale())
+            .round();
+        if let Some(offset) = self.location_to_offset(location - padding) {
+            if context.modifiers().shift() {
+                self.select(self.selection.get().0, offset);
+            } else {
+                self.select(offset, offset);
+            }
+        }
+        HANDLED
+    }
+
+    fn mouse_drag(
+        &mut self,
+        location: Point<Px>,
+        _device_id: cushy::window::DeviceId,
+        button: MouseButton,
+        context: &mut EventContext<'_>,
+    ) {
+        if button != MouseButton::Left {
+            return;
+        }
+        let padding = context
+            .get(&components::IntrinsicPadding)
+            .into_px(context.kludgine.scale())
+            .round();
+        if let Some(offset) = self.location_to_offset(location - padding) {
+            self.select(self.selection.get().0, offset);
+            self.refocus_selection(context);
+        }
+    }
+
+    fn keyboard_input(
+        &mut self,
+        _device_id: cushy::window::DeviceId,
+        input: cushy::window::KeyEvent,
+        _is_synthetic: bool,
+        context: &mut EventContext<'_>,
+    ) -> EventHandling {
+        if input.state != ElementState::Pressed {
+            return IGNORED;
+        }
+        if let Some(shortcut) = self
+            .cmd_reg
+            .get()
+            .view_shortcut
+            .iter()
+            .find_map(|(s, c)| (c.id == GOTO_LINE.id).then_some(s.clone()))
+        {
+            if event_match(&input, context.modifiers(), shortcut) {
+                self.go_to_offset();
+                return HANDLED;
+            }
+        }
+        if event_match(&input, context.modifiers(), shortcut!(Ctrl + c)) {
+            if let Some(mut clipboard) = context.cushy().clipboard_guard() {
+                let _ = clipboard.set_text(self.selection_as_hex());
+            }
+            return HANDLED;
+        }
+
+        let (anchor, head) = self.selection.get();
+        let page = self.page_len.max(1) * BYTES_PER_ROW;
+        let head = match input.logical_key {
+            Key::Named(NamedKey::ArrowLeft) => head.saturating_sub(1),
+            Key::Named(NamedKey::ArrowRight) => head + 1,
+            Key::Named(NamedKey::ArrowUp) => head.saturating_sub(BYTES_PER_ROW),
+            Key::Named(NamedKey::ArrowDown) => head + BYTES_PER_ROW,
+            Key::Named(NamedKey::PageUp) => head.saturating_sub(page),
+            Key::Named(NamedKey::PageDown) => head + page,
+            Key::Named(NamedKey::Home) if context.modifiers().primary() => 0,
+            Key::Named(NamedKey::End) if context.modifiers().primary() => usize::MAX,
+            Key::Named(NamedKey::Home) => head - head % BYTES_PER_ROW,
+            Key::Named(NamedKey::End) => head - head % BYTES_PER_ROW + BYTES_PER_ROW - 1,
+            _ => return IGNORED,
+        };
+        if context.modifiers().shift() {
+            self.select(anchor, head);
+        } else {
+            self.select(head, head);
+        }
+        self.refocus_selection(context);
+        HANDLED
+    }
+}
+
+/// A [HexView] in a scroll view, shown by the editor switcher instead of a code editor for binary documents
+#[derive(Debug)]
+pub struct HexEditor {
+    child: WidgetRef,
+    pub(super) editor: WidgetInstance,
+}
+
+impl HexEditor {
+    pub fn new(doc: Dynamic<Document>, cmd_reg: Dynamic<CommandsRegistry>, modal: Modal) -> Self {
+        let editor = HexView::new(doc, cmd_reg, modal).make_widget();
+        let child = editor
+            .clone()
+            .scrollable()
+            .with(
+                &components::FontFamily,
+                FontFamilyList::from(FamilyOwned::Monospace),
+            )
+            .into_ref();
+        Self { child, editor }
+    }
+}
+
+impl WrapperWidget for HexEditor {
+    fn child_mut(&mut self) -> &mut WidgetRef {
+        &mut self.child
+    }
+}
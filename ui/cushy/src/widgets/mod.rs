@@ -1,6 +1,8 @@
 pub mod editor_switcher;
 pub mod editor_window;
 pub mod filtered_list;
+pub mod find_in_files;
+pub mod hex_view;
 pub mod opened_editor;
 pub mod palette;
 pub mod scroll;
@@ -5,18 +5,26 @@ use cushy::{
 };
 use ndoc::{Document, Indentation};
 
-#[derive(Debug)]
 pub struct StatusBar {
     filename: Dynamic<String>,
+    loading: Dynamic<String>,
     selection: Dynamic<String>,
     indent: Dynamic<String>,
     eol: Dynamic<String>,
     encoding: Dynamic<String>,
     syntax: Dynamic<String>,
+    on_eol_click: Box<dyn FnMut() + Send>,
 }
 
 impl StatusBar {
-    pub fn new(documents: Dynamic<Vec<Dynamic<Document>>>, current_doc: Dynamic<usize>) -> Self {
+    /// `loading` is the progress of the files being loaded.
+    /// `on_eol_click` is called when the line feed label is clicked
+    pub fn new(
+        documents: Dynamic<Vec<Dynamic<Document>>>,
+        current_doc: Dynamic<usize>,
+        loading: Dynamic<String>,
+        on_eol_click: impl FnMut() + Send + 'static,
+    ) -> Self {
         let filename = Dynamic::new(String::new());
         let selection = Dynamic::new(String::new());
         let indent = Dynamic::new(String::new());
@@ -62,10 +70,10 @@ impl StatusBar {
                                 Indentation::Space(s) => format!("Spaces: {}", s),
                                 Indentation::Tab(t) => format!("Tabs: {}", t),
                             });
-                            eol.replace(match doc.file_info.linefeed {
-                                ndoc::LineFeed::CR => "CR".to_string(),
-                                ndoc::LineFeed::LF => "LF".to_string(),
-                                ndoc::LineFeed::CRLF => "CRLF".to_string(),
+                            eol.replace(if doc.file_info.mixed_linefeeds {
+                                format!("Mixed ({})", doc.file_info.linefeed.name())
+                            } else {
+                                doc.file_info.linefeed.name().to_string()
                             });
                             encoding.replace(doc.file_info.encoding.name().to_string());
                             syntax.replace(doc.file_info.syntax.name.clone());
@@ -78,22 +86,26 @@ impl StatusBar {
 
         StatusBar {
             filename,
+            loading,
             selection,
             indent,
             eol,
             encoding,
             syntax,
+            on_eol_click: Box::new(on_eol_click),
         }
     }
 }
 
 impl MakeWidget for StatusBar {
     fn make_widget(self) -> cushy::widget::WidgetInstance {
+        let mut on_eol_click = self.on_eol_click;
         self.filename
             .and(Space::clear().expand())
+            .and(self.loading)
             .and(self.selection)
             .and(self.indent)
-            .and(self.eol)
+            .and(self.eol.into_button().on_click(move |_| on_eol_click()))
             .and(self.encoding)
             .and(self.syntax)
             .into_columns()
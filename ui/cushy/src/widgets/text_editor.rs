@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::ops::Range;
 use std::time::{Duration, Instant};
 
 use cushy::context::{AsEventContext, EventContext, GraphicsContext, WidgetContext};
@@ -29,21 +30,24 @@ use super::scroll::{ScrollController, WidgetScrollableExt};
 use cushy::widgets::{Custom, Space};
 use cushy::{context, define_components, ModifiersExt, WithClone};
 use ndoc::syntax::ThemeSetRegistry;
-use ndoc::{Document, Position, Selection};
+use ndoc::{
+    Document, LineFeed, Position, SaveOptions, SearchOptions, Searcher, Selection, SelectionAera,
+};
 use rfd::FileDialog;
 
 use crate::shortcut::{event_match, ModifiersCustomExt};
+use crate::utils::DowncastWidget;
 use crate::{get_settings, CommandsRegistry, FONT_SYSTEM};
 
 use super::scroll::ContextScroller;
 
 pub struct CodeEditorColors {
-    bg: Color,
-    fg: Color,
-    bg_selection: Color,
+    pub(super) bg: Color,
+    pub(super) fg: Color,
+    pub(super) bg_selection: Color,
     border_selection: Color,
-    cursor: Color,
-    fg_gutter: Color,
+    pub(super) cursor: Color,
+    pub(super) fg_gutter: Color,
     bg_gutter: Color,
     bg_find_hightlight: Color,
     fg_find_hightlight: Option<Color>,
@@ -193,6 +197,10 @@ pub struct SearchPanelOption {
     matches: Dynamic<Vec<(Position, Position)>>,
     selected_match: Dynamic<usize>,
     case_sensitive: Dynamic<bool>,
+    whole_word: Dynamic<bool>,
+    regex: Dynamic<bool>,
+    replace_text: Dynamic<Document>,
+    preserve_case: Dynamic<bool>,
 }
 
 impl SearchPanelOption {
@@ -204,10 +212,37 @@ impl SearchPanelOption {
             matches: Dynamic::new(Vec::new()),
             selected_match: Dynamic::new(0),
             case_sensitive: Dynamic::new(false),
+            whole_word: Dynamic::new(false),
+            regex: Dynamic::new(false),
+            replace_text: Dynamic::new(Document::default()),
+            preserve_case: Dynamic::new(false),
+        }
+    }
+
+    fn searcher(&self) -> Option<Searcher> {
+        let search_term = self.text.get().rope.to_string();
+        if search_term.is_empty() {
+            return None;
         }
+        let options = SearchOptions {
+            case_sensitive: self.case_sensitive.get(),
+            whole_word: self.whole_word.get(),
+            regex: self.regex.get(),
+        };
+        Searcher::new(&search_term, options).ok()
     }
 }
 
+/// The text of a screen row: a whole line, or a part of it when the line is soft wrapped
+struct RowLayout {
+    line: usize,
+    columns: Range<usize>,
+    // the byte of the visible line where the row starts
+    byte_start: usize,
+    last: bool,
+    buffer: Buffer,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TextEditorKind {
     Code,
@@ -227,7 +262,10 @@ pub struct TextEditor {
     scale: Fraction,
     cmd_reg: Dynamic<CommandsRegistry>,
     eol_width: Px,
+    char_width: Px,
     click_info: Dynamic<ClickInfo>,
+    // the line and visible column where the rectangular selection dragged with Alt starts
+    block_anchor: Option<(usize, usize)>,
     focused: Dynamic<bool>,
     kind: TextEditorKind,
     search_panel: SearchPanelOption,
@@ -236,9 +274,14 @@ pub struct TextEditor {
     foreach_handles: Vec<CallbackHandle>,
 
     current_words_found: Dynamic<Vec<(Position, Position)>>,
+    decode_errors: Dynamic<Vec<(Position, Position)>>,
+    decode_errors_reported: bool,
 
     should_refocus: Dynamic<bool>,
+    disk_changed: Dynamic<bool>,
     page_len: usize,
+    // lines shown by the last redraw, given to the highlighter
+    visible_lines: Range<usize>,
 
     modal: Modal,
     pub id: Option<WidgetId>,
@@ -254,6 +297,11 @@ impl TextEditor {
         let mut editor = TextEditor::create(doc.clone(), modal);
 
         doc.lock().update_theme(&get_settings().theme);
+        let disk_changed = editor.disk_changed.clone();
+        // called from the background worker, the check is done on the next redraw
+        doc.lock().on_file_changed(move || {
+            disk_changed.replace(true);
+        });
 
         editor.cmd_reg = cmd_reg;
         editor.click_info = click_info;
@@ -266,23 +314,34 @@ impl TextEditor {
             let word_end = d.position_to_char(d.word_end(pos));
             d.rope.slice(word_start..word_end).to_string()
         });
+        editor.decode_errors = debounced_doc.map_each(|d| {
+            d.decode_errors()
+                .into_iter()
+                .map(|(p, _)| (p, Position::new(p.line, p.column + 1)))
+                .collect()
+        });
         editor.search_panel.matches = editor.doc.with_clone(|doc| {
             (
                 &editor.search_panel.case_sensitive.clone(),
+                &editor.search_panel.whole_word.clone(),
+                &editor.search_panel.regex.clone(),
                 &editor.search_panel.text,
             )
-                .map_each(move |(case_insensitive, search_term)| {
-                    let mut items = Vec::new();
-                    let mut idx = Position::new(0, 0);
+                .map_each(move |(case_sensitive, whole_word, regex, search_term)| {
                     let search_term = search_term.rope.to_string();
-                    while let Some(i) =
-                        doc.get()
-                            .find_from(&search_term, idx, false, *case_insensitive)
-                    {
-                        items.push(i);
-                        idx = i.1;
+                    if search_term.is_empty() {
+                        return Vec::new();
                     }
-                    items
+                    let options = SearchOptions {
+                        case_sensitive: *case_sensitive,
+                        whole_word: *whole_word,
+                        regex: *regex,
+                    };
+                    // an invalid regex (often while it is being typed) simply has no match
+                    let Ok(searcher) = Searcher::new(&search_term, options) else {
+                        return Vec::new();
+                    };
+                    doc.get().find_all(&searcher).collect()
                 })
         });
 
@@ -342,7 +401,9 @@ impl TextEditor {
             family_name: None,
             cmd_reg: Dynamic::new(CommandsRegistry::new()),
             eol_width: Px::ZERO,
+            char_width: Px::ZERO,
             click_info: Dynamic::new(ClickInfo::default()),
+            block_anchor: None,
             focused: Dynamic::new(false),
             kind: TextEditorKind::Code,
 
@@ -350,9 +411,13 @@ impl TextEditor {
             foreach_handles: Vec::new(),
 
             current_words_found: Dynamic::new(Vec::new()),
+            decode_errors: Dynamic::new(Vec::new()),
+            decode_errors_reported: false,
 
             should_refocus: Dynamic::new(false),
+            disk_changed: Dynamic::new(false),
             page_len: 0,
+            visible_lines: 0..0,
 
             modal,
             id: None,
@@ -420,19 +485,40 @@ impl TextEditor {
             .into()
     }
 
+    /// Where the cursor at the position is drawn, its row can be one of the wrapped rows of the line
+    fn position_to_point(&self, doc: &Document, position: Position) -> Point<Px> {
+        let row = doc.position_to_row(position);
+        let row_start = doc.row_columns(row).start;
+        let mut x = self.col_to_px(position.line, position.column);
+        if row_start > 0 {
+            x -= self.col_to_px(position.line, row_start);
+        }
+        Point::new(x, Px::new(row as i32) * self.line_height)
+    }
+
+    /// Width of a char of the editor font, to know how many columns fit in the window
+    fn measure_char_width(&self) -> Px {
+        let mut buffer = Buffer::new(&mut FONT_SYSTEM.lock().unwrap(), self.font_metrics);
+        buffer.set_size(&mut FONT_SYSTEM.lock().unwrap(), None, None);
+        buffer.set_text(
+            &mut FONT_SYSTEM.lock().unwrap(),
+            "0",
+            self.get_editor_default_attr(),
+            cushy::kludgine::cosmic_text::Shaping::Advanced,
+        );
+        buffer.line_layout(&mut FONT_SYSTEM.lock().unwrap(), 0);
+        buffer
+            .layout_runs()
+            .next()
+            .map_or(Px::ZERO, |run| Px::from_float(run.line_w))
+    }
+
     pub fn refocus_main_selection(&self, context: &EventContext<'_>) {
-        if self.doc.get().selections.len() == 1 {
-            let main_selection_head_x = self.col_to_px(
-                self.doc.get().selections[0].head.line,
-                self.doc.get().selections[0].head.column,
-            );
+        let doc = self.doc.get();
+        if doc.selections.len() == 1 {
+            let head = self.position_to_point(&doc, doc.selections[0].head);
             context.make_region_visible(Rect::new(
-                Point::new(
-                    Px::ZERO + main_selection_head_x - 10,
-                    Px::ZERO
-                        + Px::new(self.doc.get().selections[0].head.line as i32) * self.line_height
-                        - 10,
-                ),
+                Point::new(head.x - 10, head.y - 10),
                 Size::new(Px::new(35), self.line_height + 20),
             ));
         }
@@ -455,8 +541,8 @@ impl TextEditor {
         context.for_other(&self.id.unwrap()).unwrap().focus();
     }
 
-    fn layout_line_simple(&self, line_idx: usize) -> Buffer {
-        let raw_text = self.doc.get().get_visible_line(line_idx).to_string();
+    fn layout_line_simple(&self, line_idx: usize, bytes: Range<usize>) -> Buffer {
+        let raw_text = self.doc.get().get_visible_line(line_idx)[bytes].to_string();
 
         let attrs = self.get_editor_default_attr();
         let mut buffer = Buffer::new(&mut FONT_SYSTEM.lock().unwrap(), self.font_metrics);
@@ -474,13 +560,18 @@ impl TextEditor {
         buffer
     }
 
-    fn layout_line(&self, line_idx: usize, colors: &CodeEditorColors) -> Buffer {
+    /// The buffer of the bytes of the visible line shown on a row
+    fn layout_line(&self, line_idx: usize, bytes: Range<usize>, colors: &CodeEditorColors) -> Buffer {
         let style_line_info = self.doc.get().get_style_line_info(line_idx as _);
         if self.kind == TextEditorKind::Input || style_line_info.is_none() {
-            return self.layout_line_simple(line_idx);
+            return self.layout_line_simple(line_idx, bytes);
         }
 
         let raw_text = self.doc.get().get_visible_line(line_idx).to_string();
+        // the part of a span shown on the row
+        let row_text = |start: usize, end: usize| {
+            &raw_text[start.clamp(bytes.start, bytes.end)..end.clamp(bytes.start, bytes.end)]
+        };
 
         let attrs = self.get_editor_default_attr();
 
@@ -551,9 +642,7 @@ impl TextEditor {
                         unreachable!("FoundItemEnd outside found item");
                     }
                     (false, i, StyleSpan::FoundItemStart) => {
-                        let start = cur.min(raw_text.len());
-                        let end = i.min(raw_text.len());
-                        let t = &raw_text[start..end];
+                        let t = row_text(cur, i);
                         spans.push((
                             t,
                             attrs.color(cushy::kludgine::cosmic_text::Color::rgba(
@@ -565,9 +654,7 @@ impl TextEditor {
                         cur = i;
                     }
                     (true, i, StyleSpan::FoundItemEnd) => {
-                        let start = cur.min(raw_text.len());
-                        let end = i.min(raw_text.len());
-                        let t = &raw_text[start..end];
+                        let t = row_text(cur, i);
                         spans.push((
                             t,
                             attrs.color(cushy::kludgine::cosmic_text::Color::rgba(
@@ -586,9 +673,7 @@ impl TextEditor {
                         cur = i;
                     }
                     (false, i, StyleSpan::StyleEnd) => {
-                        let start = cur.min(raw_text.len());
-                        let end = i.min(raw_text.len());
-                        let t = &raw_text[start..end];
+                        let t = row_text(cur, i);
 
                         spans.push((
                             t,
@@ -599,9 +684,7 @@ impl TextEditor {
                         cur = i;
                     }
                     (true, i, StyleSpan::StyleEnd) => {
-                        let start = cur.min(raw_text.len());
-                        let end = i.min(raw_text.len());
-                        let t = &raw_text[start..end];
+                        let t = row_text(cur, i);
                         spans.push((
                             t,
                             attrs.color(cushy::kludgine::cosmic_text::Color::rgba(
@@ -617,9 +700,7 @@ impl TextEditor {
             }
         } else {
             for s in sl.iter() {
-                let start = s.range.start.min(raw_text.len());
-                let end = s.range.end.min(raw_text.len());
-                let t = &raw_text[start..end];
+                let t = row_text(s.range.start, s.range.end);
 
                 let col = cushy::kludgine::cosmic_text::Color::rgba(
                     s.style.foreground.r,
@@ -648,17 +729,44 @@ impl TextEditor {
     fn get_selection_shape(
         &self,
         range: Selection,
-        layouts: &HashMap<usize, Buffer>,
+        layouts: &HashMap<usize, RowLayout>,
     ) -> Option<Path<Px, false>> {
-        let rope = &self.doc.get().rope;
+        let doc = self.doc.get();
+        let mut rows = layouts.iter().collect::<Vec<_>>();
+        rows.sort_by_key(|(row, _)| **row);
 
         let rects = range
-            .areas(rope)
+            .areas(&doc.rope)
             .iter()
-            .filter_map(|a| (layouts.contains_key(&a.line).then_some(*a)))
-            .map(|a| {
-                let col_start = self.doc.get().col_to_byte(a.line, a.col_start);
-                let col_end = self.doc.get().col_to_byte(a.line, a.col_end);
+            .flat_map(|a| {
+                rows.iter()
+                    .filter(|(_, l)| l.line == a.line)
+                    .filter_map(|(row, l)| {
+                        // the part of the area on the row, a wrapped row doesn't hold the cursor at its end
+                        let col_start = a.col_start.max(l.columns.start);
+                        let col_end = a.col_end.min(l.columns.end);
+                        let shown = col_start < col_end
+                            || (a.col_start >= l.columns.start
+                                && if l.last {
+                                    a.col_start <= l.columns.end
+                                } else {
+                                    a.col_start == a.col_end && a.col_start < l.columns.end
+                                });
+                        shown.then(|| {
+                            let area = SelectionAera {
+                                col_start,
+                                col_end: col_end.max(col_start),
+                                include_eol: a.include_eol && l.last,
+                                ..*a
+                            };
+                            (**row, *l, area)
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .map(|(row, l, a)| {
+                let col_start = doc.col_to_byte(a.line, a.col_start) - l.byte_start;
+                let col_end = doc.col_to_byte(a.line, a.col_end) - l.byte_start;
 
                 let c_start = Cursor::new(0, col_start);
                 let c_end = if col_end == col_start {
@@ -667,7 +775,8 @@ impl TextEditor {
                     Cursor::new(0, col_end)
                 };
 
-                let (start, end) = layouts[&a.line]
+                let (start, end) = l
+                    .buffer
                     .layout_runs()
                     .nth(0)
                     .unwrap()
@@ -680,7 +789,7 @@ impl TextEditor {
                     start + Px::from_float(end)
                 };
 
-                let y = self.line_height * Px::new(a.line as i32);
+                let y = self.line_height * Px::new(row as i32);
 
                 Rect::new(
                     Point::new(start, y),
@@ -699,7 +808,7 @@ impl TextEditor {
         make_selection_path(&rects)
     }
 
-    fn get_selections_shapes(&self, layouts: &HashMap<usize, Buffer>) -> Vec<Path<Px, false>> {
+    fn get_selections_shapes(&self, layouts: &HashMap<usize, RowLayout>) -> Vec<Path<Px, false>> {
         self.doc
             .get()
             .selections
@@ -711,7 +820,7 @@ impl TextEditor {
     fn get_items_shapes(
         &self,
         items: Dynamic<Vec<(Position, Position)>>,
-        layouts: &HashMap<usize, Buffer>,
+        layouts: &HashMap<usize, RowLayout>,
     ) -> Vec<Path<Px, false>> {
         items
             .get()
@@ -723,21 +832,49 @@ impl TextEditor {
             .collect()
     }
 
-    fn location_to_position(&self, location: Point<Px>) -> ndoc::Position {
-        let line = ((self.viewport.get().origin.y + location.y) / self.line_height)
+    /// The screen row under the location
+    fn location_to_row(&self, doc: &Document, location: Point<Px>) -> usize {
+        let row = ((self.viewport.get().origin.y + location.y) / self.line_height)
             .floor()
             .get();
-        let line = (line.max(0) as usize).min(self.doc.get().rope.len_lines() - 1);
-        let col_idx = self.px_to_col(line, location.x);
+        (row.max(0) as usize).min(doc.row_count() - 1)
+    }
+
+    fn location_to_position(&self, location: Point<Px>) -> ndoc::Position {
+        let doc = self.doc.get();
+        let row = self.location_to_row(&doc, location);
+        let line = doc.row_to_line(row);
+        // a wrapped row is shown from the start of the window
+        let row_start = doc.row_columns(row).start;
+        let x = if row_start > 0 {
+            location.x + self.col_to_px(line, row_start)
+        } else {
+            location.x
+        };
+        let col_idx = self.px_to_col(line, x).clamp(row_start, doc.row_end(row));
         Position::new(line, col_idx)
     }
 
+    /// The line and the visible column under the location, the columns after the end of the line included
+    fn location_to_vcol(&self, location: Point<Px>) -> (usize, usize) {
+        let doc = self.doc.get();
+        let row = self.location_to_row(&doc, location);
+        let line = doc.row_to_line(row);
+        // a wrapped row is shown from the start of the window
+        let row_start = doc.col_to_vcol(line, doc.row_columns(row).start);
+        let vcol = if self.char_width > Px::ZERO {
+            ((location.x + self.char_width / 2) / self.char_width).get().max(0) as usize
+        } else {
+            0
+        };
+        (line, row_start + vcol)
+    }
+
     pub fn save_as(&self, context: &mut WidgetContext) {
         #[cfg(target_os = "windows")]
         context.window_mut().winit().unwrap().set_enable(false);
         if let Some(file) = FileDialog::new().save_file() {
-            // TODO: check for errors
-            let _ = self.doc.lock().save_as(&file);
+            self.save_to(&file);
         }
         #[cfg(target_os = "windows")]
         context.window_mut().winit().unwrap().set_enable(true);
@@ -747,6 +884,164 @@ impl TextEditor {
     pub fn palette(&self) -> PaletteState {
         PaletteState::new(self.modal.clone()).owner(self.id.unwrap())
     }
+
+    /// Ask for the line feed every line ending of the document is converted to
+    pub fn change_linefeed(&self) {
+        let linefeeds = [LineFeed::LF, LineFeed::CRLF, LineFeed::CR];
+        let current = self.doc.get().file_info.linefeed;
+        let doc = self.doc.clone();
+        self.palette()
+            .description("Convert line endings")
+            .items(linefeeds.iter().map(|l| l.name().to_string()).collect())
+            .selected_idx(linefeeds.iter().position(|l| *l == current).unwrap_or_default())
+            .accept(move |_, idx, _| {
+                doc.lock().convert_linefeeds(linefeeds[idx]);
+            })
+            .show();
+    }
+
+    /// Save the document to its file, or ask for one if it was never saved
+    pub fn save(&self, context: &mut WidgetContext) {
+        if let Some(ref file_name) = self.doc.get().file_name {
+            self.save_to(file_name);
+        } else {
+            self.save_as(context);
+        }
+    }
+
+    /// Save the document, reporting failures to the user.
+    /// If the file had invalid bytes, ask whether to keep them or to write the replacement chars
+    pub fn save_to(&self, path: &std::path::Path) {
+        let options = SaveOptions {
+            backup: get_settings().backup_on_save,
+            ..Default::default()
+        };
+        if self.doc.get().file_info.decode_errors.is_empty() {
+            self.write_doc(path, options);
+            return;
+        }
+        let path = path.to_path_buf();
+        self.palette()
+            .description("The file contains invalid bytes")
+            .items(vec![
+                "Keep the original invalid bytes".to_string(),
+                "Save them as replacement characters".to_string(),
+            ])
+            .accept(move |c, idx, _| {
+                let options = SaveOptions {
+                    keep_invalid_bytes: idx == 0,
+                    ..options
+                };
+                let editor = c.widget().instance().clone();
+                editor.use_as(|text_editor: &TextEditor| text_editor.write_doc(&path, options));
+            })
+            .show();
+    }
+
+    fn write_doc(&self, path: &std::path::Path, options: SaveOptions) {
+        if let Err(e) = self.doc.lock().save_with_options(path, options) {
+            self.show_error("Failed to save the document", e);
+        }
+    }
+
+    /// List the invalid bytes found when the file was decoded, accepting an item goes to it
+    fn report_decode_errors(&self) {
+        let errors = self
+            .doc
+            .get()
+            .decode_errors()
+            .into_iter()
+            .map(|(p, e)| (p, e.bytes.clone()))
+            .collect::<Vec<_>>();
+        if errors.is_empty() {
+            return;
+        }
+        let items = errors
+            .iter()
+            .map(|(p, bytes)| {
+                format!(
+                    "Ln {}, Col {}: {}",
+                    p.line + 1,
+                    p.column + 1,
+                    bytes
+                        .iter()
+                        .map(|b| format!("{b:02X}"))
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                )
+            })
+            .collect();
+        let doc = self.doc.clone();
+        self.palette()
+            .description("Invalid bytes were replaced with U+FFFD")
+            .items(items)
+            .accept(move |c, idx, _| {
+                let p = errors[idx].0;
+                doc.lock().set_main_selection(p, p);
+                let editor = c.widget().instance().clone();
+                editor.use_as(|text_editor: &TextEditor| text_editor.refocus_main_selection(c));
+            })
+            .show();
+    }
+
+    pub fn show_error(&self, description: &'static str, error: impl std::fmt::Display) {
+        tracing::warn!("{description}: {error}");
+        self.palette()
+            .description(description)
+            .items(vec![error.to_string()])
+            .show();
+    }
+
+    /// Reload the document if it was modified by another program.
+    /// If it has unsaved changes, ask the user what to do
+    fn check_disk_changes(&self) {
+        if !self.doc.get().has_changed_on_disk() {
+            return;
+        }
+        if !self.doc.get().is_dirty() {
+            if let Err(e) = self.doc.lock().reload() {
+                tracing::warn!("Failed to reload {}: {e}", self.doc.get().title());
+            }
+            return;
+        }
+        let doc = self.doc.clone();
+        let disk_changed = self.disk_changed.clone();
+        self.palette()
+            .description("The file was modified by another program")
+            .items(vec![
+                "Reload from disk".to_string(),
+                "Keep my changes".to_string(),
+                "Show diff".to_string(),
+            ])
+            .accept(move |c, idx, _| match idx {
+                0 => {
+                    if let Err(e) = doc.lock().reload() {
+                        tracing::warn!("Failed to reload {}: {e}", doc.get().title());
+                    }
+                }
+                1 => doc.lock().ignore_disk_changes(),
+                _ => {
+                    let diff = match doc.get().diff_with_disk() {
+                        Ok(diff) => diff.lines().map(String::from).collect(),
+                        Err(e) => vec![e.to_string()],
+                    };
+                    let disk_changed = disk_changed.clone();
+                    c.widget()
+                        .lock()
+                        .downcast_ref::<TextEditor>()
+                        .unwrap()
+                        .palette()
+                        .description("Changes on disk")
+                        .items(diff)
+                        .accept(move |_, _, _| {
+                            // ask again once the diff is reviewed
+                            disk_changed.replace(true);
+                        })
+                        .show();
+                }
+            })
+            .show();
+    }
 }
 
 impl Widget for TextEditor {
@@ -772,6 +1067,16 @@ impl Widget for TextEditor {
             self.should_refocus.replace(false);
         }
 
+        context.redraw_when_changed(&self.disk_changed);
+        if self.disk_changed.replace(false) == Some(true) {
+            self.check_disk_changes();
+        }
+
+        if self.kind == TextEditorKind::Code && !self.decode_errors_reported {
+            self.decode_errors_reported = true;
+            self.report_decode_errors();
+        }
+
         let padding = context
             .get(&components::IntrinsicPadding)
             .into_px(context.gfx.scale())
@@ -779,6 +1084,7 @@ impl Widget for TextEditor {
 
         context.redraw_when_changed(&self.doc);
         context.redraw_when_changed(&self.current_words_found);
+        context.redraw_when_changed(&self.decode_errors);
 
         if self.kind == TextEditorKind::Input && self.focused.get() {
             let translation = context.gfx.translation();
@@ -790,30 +1096,38 @@ impl Widget for TextEditor {
             );
         }
 
-        let first_line = (-context.gfx.translation().y / self.line_height) - 1;
-        let last_line =
-            first_line + (context.gfx.clip_rect().size.height.into_signed() / self.line_height) + 2;
+        let first_row = (-context.gfx.translation().y / self.line_height) - 1;
+        let last_row =
+            first_row + (context.gfx.clip_rect().size.height.into_signed() / self.line_height) + 2;
 
-        let first_line = first_line.get().max(0) as usize;
-        let last_line = last_line.get() as usize;
-        let total_line = last_line - first_line;
+        let first_row = first_row.get().max(0) as usize;
+        let last_row = last_row.get() as usize;
+        let doc = self.doc.get();
+        // folded lines have no row, the lines between the first and the last one are not all drawn.
+        // A wrapped line has a row for each part of it
+        let rows = (first_row..last_row.min(doc.row_count())).collect::<Vec<usize>>();
+        let first_line = doc.row_to_line(first_row);
+        let last_line = doc.row_to_line(last_row);
         self.page_len =
             (context.gfx.clip_rect().size.height.into_signed() / self.line_height).get() as _;
+        if self.kind == TextEditorKind::Code && self.visible_lines != (first_line..last_line) {
+            self.visible_lines = first_line..last_line;
+            self.doc.get().set_visible_lines(first_line..last_line);
+        }
 
         if self.kind == TextEditorKind::Code {
             context.gfx.set_font_size(Lp::points(12));
         }
         context.fill(colors.bg);
-        let doc = self.doc.get();
 
         // highlight current line
         if self.kind == TextEditorKind::Code
             && doc.selections.len() == 1
             && doc.selections[0].is_empty()
         {
-            let line = doc.selections[0].head.line;
+            let row = doc.position_to_row(doc.selections[0].head);
             let translation = context.gfx.translation();
-            let y = units::Px::new(line as _) * self.line_height;
+            let y = units::Px::new(row as _) * self.line_height;
             let rect = Rect::new(
                 Point::new(Px::ZERO, y),
                 Size::new(
@@ -828,16 +1142,29 @@ impl Widget for TextEditor {
         }
 
         // TODO: cache layouts
-        let buffers = self
-            .doc
-            .get()
-            .rope
-            .lines()
-            .enumerate()
-            .skip(first_line)
-            .take(total_line)
-            .map(|(i, _)| (i, self.layout_line(i, &colors)))
-            .collect::<HashMap<usize, Buffer>>();
+        let buffers = rows
+            .iter()
+            .map(|row| {
+                let line = doc.row_to_line(*row);
+                let columns = doc.row_columns(*row);
+                let last = doc.row_end(*row) == columns.end;
+                let byte_start = doc.col_to_byte(line, columns.start);
+                let byte_end = if last {
+                    doc.get_visible_line(line).len()
+                } else {
+                    doc.col_to_byte(line, columns.end)
+                };
+                let buffer = self.layout_line(line, byte_start..byte_end, &colors);
+                let layout = RowLayout {
+                    line,
+                    columns,
+                    byte_start,
+                    last,
+                    buffer,
+                };
+                (*row, layout)
+            })
+            .collect::<HashMap<usize, RowLayout>>();
 
         // draw selections
         for path in self.get_selections_shapes(&buffers) {
@@ -880,12 +1207,37 @@ impl Widget for TextEditor {
             // }
         }
 
-        for i in first_line..last_line {
-            let y = units::Px::new(i as _) * self.line_height;
-            if let Some(b) = buffers.get(&i) {
+        // outline the brackets next to the cursors and their match
+        if self.kind == TextEditorKind::Code {
+            let bracket_color = context.get(&MatchingBracketColor);
+            for (bracket, matching) in doc.matching_brackets(first_line..last_line + 1) {
+                for p in [bracket, matching] {
+                    let range = (p, Position::new(p.line, p.column + 1)).into();
+                    if let Some(path) = self.get_selection_shape(range, &buffers) {
+                        context.gfx.draw_shape(
+                            path.stroke(StrokeOptions::px_wide(Px::new(1)).colored(bracket_color))
+                                .translate_by(Point::new(padding, padding)),
+                        );
+                    }
+                }
+            }
+        }
+
+        // mark the chars decoded from invalid bytes
+        let invalid_bytes_color = context.get(&InvalidBytesColor);
+        for path in self.get_items_shapes(self.decode_errors.clone(), &buffers) {
+            context.gfx.draw_shape(
+                path.stroke(StrokeOptions::px_wide(Px::new(1)).colored(invalid_bytes_color))
+                    .translate_by(Point::new(padding, padding)),
+            );
+        }
+
+        for row in &rows {
+            let y = units::Px::new(*row as _) * self.line_height;
+            if let Some(l) = buffers.get(row) {
                 context.gfx.draw_text_buffer(
                     Drawable {
-                        source: b,
+                        source: &l.buffer,
                         translation: Point::<Px>::default(),
                         opacity: None,
                         rotation: None,
@@ -903,8 +1255,9 @@ impl Widget for TextEditor {
             .selections
             .iter()
             .filter(|s| s.head.line >= first_line && s.head.line <= last_line)
+            .filter(|s| !doc.is_line_hidden(s.head.line))
         {
-            let head = self.col_to_px(s.head.line, s.head.column).floor();
+            let head = self.position_to_point(&doc, s.head);
 
             context.gfx.draw_shape(
                 Shape::filled_rect(
@@ -914,10 +1267,7 @@ impl Widget for TextEditor {
                     ),
                     colors.cursor,
                 )
-                .translate_by(Point::new(
-                    head + padding,
-                    Px::new(s.head.line as i32) * self.line_height + padding,
-                )),
+                .translate_by(Point::new(head.x.floor() + padding, head.y + padding)),
             );
 
             // let main_selection_head_x = self.grapheme_to_point(
@@ -958,8 +1308,29 @@ impl Widget for TextEditor {
             self.font_metrics =
                 Metrics::new(self.font_size.into_float(), self.line_height.into_float());
             self.eol_width = context.gfx.measure_text("⏎").size.width;
+            self.char_width = self.measure_char_width();
+        }
+
+        // soft wrap at the window edge, or at the column of the settings
+        let wrap_column = get_settings().wrap_column;
+        let wrap_at_edge = self.doc.get().is_soft_wrapped() && wrap_column == 0;
+        if self.kind == TextEditorKind::Code && self.doc.get().is_soft_wrapped() {
+            let wrap_width = if wrap_at_edge && self.char_width > Px::ZERO {
+                let text_width = context.gfx.clip_rect().size.width.into_signed() - padding.into_signed();
+                (text_width / self.char_width).get().max(1) as usize
+            } else {
+                wrap_column.max(1)
+            };
+            if self.doc.get().wrap_width() != wrap_width {
+                self.doc.lock().set_wrap_width(wrap_width);
+            }
         }
-        let height = self.doc.get().rope.len_lines() as f32 * self.font_metrics.line_height;
+        let width = if wrap_at_edge {
+            context.gfx.clip_rect().size.width
+        } else {
+            UPx::new(10000) + padding
+        };
+        let height = self.doc.get().row_count() as f32 * self.font_metrics.line_height;
 
         self.viewport.set(Rect::new(
             context.gfx.translation().abs(),
@@ -969,7 +1340,7 @@ impl Widget for TextEditor {
         reset_text_attr(context);
         if self.kind == TextEditorKind::Code {
             Size::new(
-                UPx::new(10000) + padding,
+                width,
                 UPx::new(height.ceil() as _).max(context.gfx.clip_rect().size.height) + padding,
             )
         } else {
@@ -1012,6 +1383,14 @@ impl Widget for TextEditor {
         if button == MouseButton::Left {
             self.click_info.lock().update(button);
 
+            if self.kind == TextEditorKind::Code && context.modifiers().alt() {
+                let (line, vcol) = self.location_to_vcol(location);
+                self.block_anchor = Some((line, vcol));
+                self.doc.lock().select_block(line, vcol, line, vcol);
+                return HANDLED;
+            }
+            self.block_anchor = None;
+
             let pos = self.location_to_position(location);
             match self.click_info.get().count {
                 0 => {
@@ -1043,6 +1422,14 @@ impl Widget for TextEditor {
             .round();
         let location = location - padding;
         if button == MouseButton::Left {
+            if let Some((anchor_line, anchor_vcol)) = self.block_anchor {
+                let (line, vcol) = self.location_to_vcol(location);
+                self.doc
+                    .lock()
+                    .select_block(anchor_line, anchor_vcol, line, vcol);
+                self.refocus_main_selection(context);
+                return;
+            }
             let head = self.location_to_position(location);
             match self.click_info.get().count {
                 0 => {
@@ -1149,6 +1536,26 @@ impl Widget for TextEditor {
                     self.refocus_main_selection(context);
                     return HANDLED;
                 }
+                Key::Named(
+                    key @ (NamedKey::ArrowLeft
+                    | NamedKey::ArrowRight
+                    | NamedKey::ArrowUp
+                    | NamedKey::ArrowDown),
+                ) if self.kind == TextEditorKind::Code
+                    && context.modifiers().shift()
+                    && context.modifiers().alt()
+                    && !context.modifiers().ctrl() =>
+                {
+                    let dir = match key {
+                        NamedKey::ArrowLeft => ndoc::MoveDirection::Left,
+                        NamedKey::ArrowRight => ndoc::MoveDirection::Right,
+                        NamedKey::ArrowUp => ndoc::MoveDirection::Up,
+                        _ => ndoc::MoveDirection::Down,
+                    };
+                    self.doc.lock().expand_block_selection(dir);
+                    self.refocus_main_selection(context);
+                    return HANDLED;
+                }
                 Key::Named(NamedKey::ArrowLeft) if context.modifiers().word_select() => {
                     self.doc.lock().move_selections_word(
                         ndoc::MoveDirection::Left,
@@ -1283,6 +1690,8 @@ pub struct Gutter {
     scale: Fraction,
     editor_id: WidgetId,
     scroller: ScrollController,
+    // the fold markers are drawn after the line numbers
+    numbers_width: Px,
 }
 
 impl Gutter {
@@ -1298,8 +1707,46 @@ impl Gutter {
             scale: Fraction::ZERO,
             editor_id,
             scroller,
+            numbers_width: Px::ZERO,
         }
     }
+
+    fn text_buffer(&self, text: &str, color: Color) -> Buffer {
+        let col = cushy::kludgine::cosmic_text::Color::rgba(
+            color.red(),
+            color.green(),
+            color.blue(),
+            color.alpha(),
+        );
+
+        let attrs = get_editor_default_attr(self.family_name.as_deref())
+            .color(col)
+            .weight(self.font_weight)
+            .stretch(self.font_stretch);
+
+        let mut buffer = Buffer::new(&mut FONT_SYSTEM.lock().unwrap(), self.font_metrics);
+        buffer.set_size(
+            &mut FONT_SYSTEM.lock().unwrap(),
+            None, //10000.,
+            None, //self.font_metrics.line_height,
+        );
+        buffer.set_text(
+            &mut FONT_SYSTEM.lock().unwrap(),
+            text,
+            attrs,
+            cushy::kludgine::cosmic_text::Shaping::Advanced,
+        );
+        buffer
+    }
+
+    fn location_to_line(&self, location: Point<Px>, editor: &TextEditor) -> usize {
+        let translation = self.scroller.scroll().get().y.into_signed();
+        let row = ((location.y + translation) / editor.line_height)
+            .floor()
+            .get();
+        let doc = editor.doc.get();
+        doc.row_to_line(row.max(0) as usize).min(doc.rope.len_lines() - 1)
+    }
 }
 
 impl Widget for Gutter {
@@ -1320,12 +1767,13 @@ impl Widget for Gutter {
             .into_px(context.gfx.scale())
             .round();
 
-        let first_line = (translation / self.line_height) - 1;
-        let last_line =
-            first_line + (context.gfx.clip_rect().size.height.into_signed() / self.line_height) + 2;
+        let doc = self.doc.get();
+        let first_row = (translation / self.line_height) - 1;
+        let last_row =
+            first_row + (context.gfx.clip_rect().size.height.into_signed() / self.line_height) + 2;
 
-        let first_line = first_line.get().max(0) as usize;
-        let last_line = (last_line.get() as usize).min(self.doc.get().rope.len_lines());
+        let first_row = first_row.get().max(0) as usize;
+        let last_row = (last_row.get() as usize).min(doc.row_count());
 
         context
             .gfx
@@ -1333,44 +1781,36 @@ impl Widget for Gutter {
 
         context.fill(colors.bg_gutter);
 
-        for i in first_line..last_line {
-            let y = units::Px::new(i as _) * self.font_metrics.line_height - translation;
-
-            let col = cushy::kludgine::cosmic_text::Color::rgba(
-                colors.fg_gutter.red(),
-                colors.fg_gutter.green(),
-                colors.fg_gutter.blue(),
-                colors.fg_gutter.alpha(),
-            );
-
-            let attrs = get_editor_default_attr(self.family_name.as_deref())
-                .color(col)
-                .weight(self.font_weight)
-                .stretch(self.font_stretch);
-
-            let mut buffer = Buffer::new(&mut FONT_SYSTEM.lock().unwrap(), self.font_metrics);
-            buffer.set_size(
-                &mut FONT_SYSTEM.lock().unwrap(),
-                None, //10000.,
-                None, //self.font_metrics.line_height,
-            );
-            buffer.set_text(
-                &mut FONT_SYSTEM.lock().unwrap(),
-                &format!("{}", i + 1),
-                attrs,
-                cushy::kludgine::cosmic_text::Shaping::Advanced,
-            );
-            context.gfx.draw_text_buffer(
-                Drawable {
-                    source: &buffer,
-                    translation: Point::new(Px::ZERO + padding, y + padding),
-                    opacity: None,
-                    rotation: None,
-                    scale: None,
-                },
-                colors.fg_gutter,
-                cushy::kludgine::text::TextOrigin::TopLeft,
-            );
+        for row in first_row..last_row {
+            let y = units::Px::new(row as _) * self.font_metrics.line_height - translation;
+            // the rows following the first one of a wrapped line have no number
+            if doc.row_columns(row).start > 0 {
+                continue;
+            }
+            let i = doc.row_to_line(row);
+
+            let mut buffers = vec![(
+                Px::ZERO,
+                self.text_buffer(&format!("{}", i + 1), colors.fg_gutter),
+            )];
+            if doc.is_folded(i) {
+                buffers.push((self.numbers_width, self.text_buffer("▸", colors.fg_gutter)));
+            } else if doc.is_foldable(i) {
+                buffers.push((self.numbers_width, self.text_buffer("▾", colors.fg_gutter)));
+            }
+            for (x, buffer) in &buffers {
+                context.gfx.draw_text_buffer(
+                    Drawable {
+                        source: buffer,
+                        translation: Point::new(*x + padding, y + padding),
+                        opacity: None,
+                        rotation: None,
+                        scale: None,
+                    },
+                    colors.fg_gutter,
+                    cushy::kludgine::text::TextOrigin::TopLeft,
+                );
+            }
         }
 
         reset_text_attr(context);
@@ -1393,7 +1833,7 @@ impl Widget for Gutter {
             self.font_metrics =
                 Metrics::new(self.font_size.into_float(), self.line_height.into_float());
         }
-        let height = self.doc.get().rope.len_lines() as f32 * self.font_metrics.line_height;
+        let height = self.doc.get().row_count() as f32 * self.font_metrics.line_height;
 
         context
             .gfx
@@ -1406,8 +1846,9 @@ impl Widget for Gutter {
 
         let mesured_text = context
             .gfx
-            .measure_text::<UPx>(&format!("{}", self.doc.get().rope.len_lines() + 1));
-        let width = mesured_text.size.width;
+            .measure_text::<UPx>(&format!("{} ", self.doc.get().rope.len_lines() + 1));
+        self.numbers_width = mesured_text.size.width.into_signed();
+        let width = mesured_text.size.width + context.gfx.measure_text::<UPx>("▸").size.width;
 
         reset_text_attr(context);
 
@@ -1433,7 +1874,6 @@ impl Widget for Gutter {
         button: MouseButton,
         context: &mut cushy::context::EventContext<'_>,
     ) -> EventHandling {
-        let translation = self.scroller.scroll().get().y.into_signed();
         let padding = context
             .get(&components::IntrinsicPadding)
             .into_px(context.kludgine.scale())
@@ -1444,12 +1884,13 @@ impl Widget for Gutter {
             let guard = c.widget().lock();
             let editor = guard.downcast_ref::<TextEditor>().unwrap();
 
-            let line = ((location.y + translation) / editor.line_height)
-                .floor()
-                .get();
-            let line = (line.max(0) as usize).min(editor.doc.get().rope.len_lines() - 1);
+            let line = self.location_to_line(location, editor);
 
-            editor.doc.lock().select_line(line);
+            if location.x >= self.numbers_width {
+                editor.doc.lock().toggle_fold(line);
+            } else {
+                editor.doc.lock().select_line(line);
+            }
             HANDLED
         } else {
             IGNORED
@@ -1462,7 +1903,6 @@ impl Widget for Gutter {
         button: MouseButton,
         context: &mut cushy::context::EventContext<'_>,
     ) {
-        let translation = self.scroller.scroll().get().y.into_signed();
         let padding = context
             .get(&components::IntrinsicPadding)
             .into_px(context.kludgine.scale())
@@ -1472,10 +1912,7 @@ impl Widget for Gutter {
             let c = context.for_other(&self.editor_id).unwrap();
             let guard = c.widget().lock();
             let editor = guard.downcast_ref::<TextEditor>().unwrap();
-            let line = ((location.y + translation) / editor.line_height)
-                .floor()
-                .get();
-            let line = (line.max(0) as usize).min(editor.doc.get().rope.len_lines() - 1);
+            let line = self.location_to_line(location, editor);
 
             editor
                 .doc
@@ -1515,7 +1952,7 @@ impl CodeEditor {
 
         let click_info = Dynamic::new(ClickInfo::default());
         let mut text_editor = TextEditor::new(doc.clone(), cmd_reg.clone(), click_info, modal);
-        let search_bar = search_bar(&mut text_editor.search_panel);
+        let search_bar = search_bar(&mut text_editor.search_panel, doc.clone());
 
         let text_editor = text_editor.make_with_tag(editor_tag);
         let editor = text_editor.clone();
@@ -1537,7 +1974,7 @@ impl CodeEditor {
     }
 }
 
-fn search_bar(option: &mut SearchPanelOption) -> cushy::widgets::Collapse {
+fn search_bar(option: &mut SearchPanelOption, doc: Dynamic<Document>) -> cushy::widgets::Collapse {
     let (search_tag, search_bar_id) = WidgetTag::new();
     option.id = Some(search_bar_id);
     let match_count = (&option.text, &option.selected_match).map_each({
@@ -1573,7 +2010,7 @@ fn search_bar(option: &mut SearchPanelOption) -> cushy::widgets::Collapse {
     });
     let action_enter = action_down.clone();
 
-    "Search: "
+    let search_row = "Search: "
         .and(
             Custom::new(
                 TextEditor::as_input(option.text.clone())
@@ -1594,6 +2031,8 @@ fn search_bar(option: &mut SearchPanelOption) -> cushy::widgets::Collapse {
             }),
         )
         .and("aA".into_checkbox(option.case_sensitive.clone()).centered())
+        .and("ab".into_checkbox(option.whole_word.clone()).centered())
+        .and(".*".into_checkbox(option.regex.clone()).centered())
         .and(Space::clear().width(Lp::mm(1)))
         .and(match_count.centered())
         .and(
@@ -1613,7 +2052,98 @@ fn search_bar(option: &mut SearchPanelOption) -> cushy::widgets::Collapse {
                 .with_enabled(search_match.clone())
                 .centered(),
         )
-        .into_columns()
+        .into_columns();
+
+    let action_replace = (&*option, &doc).with_clone(|(option, doc)| {
+        move || {
+            let Some(searcher) = option.searcher() else {
+                return;
+            };
+            let idx = option.selected_match.get();
+            let Some((start, _)) = option.matches.get().get(idx).copied() else {
+                return;
+            };
+            let replacement = option.replace_text.get().rope.to_string();
+            doc.lock()
+                .replace(&searcher, start, &replacement, option.preserve_case.get());
+            // the next match now has the same index, select it
+            let len = option.matches.get().len();
+            *option.selected_match.lock() = if len == 0 { 0 } else { idx % len };
+        }
+    });
+    let action_replace_all = (&*option, &doc).with_clone(|(option, doc)| {
+        move || {
+            if let Some(searcher) = option.searcher() {
+                let replacement = option.replace_text.get().rope.to_string();
+                doc.lock()
+                    .replace_all(&searcher, &replacement, option.preserve_case.get());
+                *option.selected_match.lock() = 0;
+            }
+        }
+    });
+    let action_replace_in_selection = (&*option, &doc).with_clone(|(option, doc)| {
+        move || {
+            if let Some(searcher) = option.searcher() {
+                let replacement = option.replace_text.get().rope.to_string();
+                doc.lock().replace_in_selections(
+                    &searcher,
+                    &replacement,
+                    option.preserve_case.get(),
+                );
+                *option.selected_match.lock() = 0;
+            }
+        }
+    });
+    let action_replace_enter = action_replace.clone();
+
+    let replace_row = "Replace: "
+        .and(
+            Custom::new(
+                TextEditor::as_input(option.replace_text.clone())
+                    .make_widget()
+                    .scrollable_horizontally()
+                    .with(&ScrollBarThickness, Lp::points(0))
+                    .width(Lp::cm(5))
+                    .centered(),
+            )
+            .on_keyboard_input(move |_, k, _, _| {
+                if k.state == ElementState::Pressed && k.logical_key == Key::Named(NamedKey::Enter)
+                {
+                    action_replace_enter();
+                    HANDLED
+                } else {
+                    IGNORED
+                }
+            }),
+        )
+        .and("AB".into_checkbox(option.preserve_case.clone()).centered())
+        .and(Space::clear().width(Lp::mm(1)))
+        .and(
+            "Replace"
+                .into_button()
+                .on_click(move |_| action_replace())
+                .with_enabled(search_match.clone())
+                .centered(),
+        )
+        .and(
+            "Replace All"
+                .into_button()
+                .on_click(move |_| action_replace_all())
+                .with_enabled(search_match.clone())
+                .centered(),
+        )
+        .and(
+            "In Selection"
+                .into_button()
+                .on_click(move |_| action_replace_in_selection())
+                .with_enabled(search_match)
+                .centered(),
+        )
+        .into_columns();
+
+    search_row
+        .and(replace_row)
+        .into_rows()
         .collapse_vertically(option.closed.clone())
 }
 
@@ -1656,6 +2186,8 @@ define_components! {
         CursorColor(Color, "cursor_color", Color::new(0xFF, 0xFF, 0xFF, 0xFF))
         SelectionBackgroundColor(Color, "selection_background_color", Color::new(0x4F, 0x5B, 0x66, 0xFF))
         SelectionBorderColor(Color, "selection_border_color", Color::new(0x20, 0x30, 0x40, 0xFF))
+        InvalidBytesColor(Color, "invalid_bytes_color", Color::new(0xE0, 0x50, 0x50, 0xFF))
+        MatchingBracketColor(Color, "matching_bracket_color", Color::new(0xA0, 0xA0, 0xA0, 0xFF))
     }
 }
 